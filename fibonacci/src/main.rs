@@ -1,6 +1,17 @@
 
+// num-bigint is an external crate (see Cargo.toml) with integers which grow
+// as large as they need to. In the 2015 edition of Rust external crates need
+// to be declared with "extern crate" before they can be used
+extern crate num_bigint;
+
 // Use this syntax to import more than one type from the same root type
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
 use std::io::{self, Write};
+use std::process;
+
+use num_bigint::BigUint;
 
 
 fn fib_recursive(n: usize) -> usize {
@@ -23,6 +34,33 @@ fn fib_recursive(n: usize) -> usize {
     }
 }
 
+// fib_recursive() computes the same values over and over: F(n - 1) and F(n - 2)
+// both need F(n - 3), and so on down, which is why it takes exponential time.
+// Memoization remembers every value the first time it is computed, so that
+// each one is only computed once, and n calls deep is as far as it goes.
+//
+// n calls deep is still one stack frame per call, and a large enough n would
+// run out of stack; F(n) overflows the usize long before that, though, so
+// nth refuses any n above MAX_N (see below) for this one as for the others,
+// and the recursion never goes more than MAX_N calls deep.
+//
+// The cache is borrowed mutably (&mut) so that every recursive call adds to
+// the same one. Keeping it between calls makes later calls cheaper still
+fn fib_memo(n: usize, cache: &mut HashMap<usize, usize>) -> usize {
+    if n == 0 || n == 1 {
+        return n;
+    }
+    // .get() returns an Option<&usize>; the & in the pattern copies the
+    // value out of the reference
+    if let Some(&value) = cache.get(&n) {
+        return value;
+    }
+
+    let value = fib_memo(n - 1, cache) + fib_memo(n - 2, cache);
+    cache.insert(n, value);
+    value
+}
+
 // A dynamic programming version of fib
 fn fib_dp(n: usize) -> usize {
     if n == 0 || n == 1 {
@@ -47,52 +85,798 @@ fn fib_dp(n: usize) -> usize {
     }
 }
 
-fn main() {
-    // "print!" is like "println!" which is used in most examples in the rust
-    // book but it doesn't output a newline
-    print!("Enter what Fibonacci number you want to compute: ");
-    // We need to flush the buffer because print!() does not output a newline
-    // character and does not flush; it uses STDOUT which is buffered
-    io::stdout().flush().unwrap();
-
-    // ::new() is an associated function implemented on a type, in this case on
-    // type String, rather than an instance of String. They are basically
+// Every version above returns a usize, and F(94) is already too large for a
+// 64 bit one. A BigUint has as many digits as it takes, so this one goes on
+// for as long as there is memory: F(500) has 105 of them.
+//
+// It works like fib_dp(). Adding two BigUints takes references (&a + &b), as
+// adding the values themselves would move them, and we still need b
+fn fib_big(n: usize) -> BigUint {
+    let mut a = BigUint::from(0u32);
+    let mut b = BigUint::from(1u32);
+    for _ in 0..n {
+        let next = &a + &b;
+        a = b;
+        b = next;
+    }
+    a
+}
+
+// The sequence itself, one number after another, as an iterator: anything
+// which implements the Iterator trait gets all of its adaptors for free, e.g.
+//   FibIter::new().take(20).collect::<Vec<u128>>()
+// for the first 20 numbers.
+//
+// F(186) is the largest Fibonacci number which fits in a u128. Iterators say
+// they are done by returning None, and this one does so after F(186) rather
+// than overflowing, so it can also be walked to the end with a for loop
+struct FibIter {
+    // The number next() returns next, and the one after it; None once they
+    // are too large for a u128
+    current: Option<u128>,
+    next: Option<u128>,
+}
+
+impl FibIter {
+    fn new() -> FibIter {
+        FibIter { current: Some(0), next: Some(1) }
+    }
+}
+
+impl Iterator for FibIter {
+    // The type of the values the iterator yields
+    type Item = u128;
+
+    fn next(&mut self) -> Option<u128> {
+        // "?" on an Option returns None early if it is None
+        let value = self.current?;
+        self.current = self.next;
+        self.next = self.current.and_then(|current| current.checked_add(value));
+        Some(value)
+    }
+}
+
+// Fast doubling: from F(k) and F(k+1) we can jump straight to F(2k) and
+// F(2k+1) using
+//   F(2k)     = F(k) * (2 * F(k+1) - F(k))
+//   F(2k + 1) = F(k)^2 + F(k+1)^2
+// so we only need about log2(n) steps instead of n.
+//
+// To get F(n) we also compute F(n+1), which overflows a 64 bit usize one index
+// earlier than F(n) itself does. The wrapping_* operations compute everything
+// modulo 2^64 instead of panicking, which still gives the right F(n) whenever
+// F(n) fits; past that (n > 93) the result is garbage
+fn fib_fast(n: usize) -> usize {
+    // Returns the tuple (F(n), F(n+1))
+    fn fib_pair(n: usize) -> (usize, usize) {
+        if n == 0 {
+            return (0, 1);
+        }
+
+        // n / 2 rounds down, so when n is odd we are one step short and need
+        // to take one more step forward
+        let (a, b) = fib_pair(n / 2);
+        let c = a.wrapping_mul(b.wrapping_mul(2).wrapping_sub(a));
+        let d = a.wrapping_mul(a).wrapping_add(b.wrapping_mul(b));
+        if n % 2 == 1 {
+            (d, c.wrapping_add(d))
+        } else {
+            (c, d)
+        }
+    }
+
+    fib_pair(n).0
+}
+
+// Binet's formula gives F(n) in closed form, without walking the sequence:
+//   F(n) = (phi^n - psi^n) / sqrt(5)
+// where phi = (1 + sqrt(5)) / 2, the golden ratio, and psi = (1 - sqrt(5)) / 2.
+// |psi^n / sqrt(5)| is below 1/2 for every n, so it is enough to round
+// phi^n / sqrt(5) to the nearest integer.
+//
+// f64 only has 53 bits of precision, though, and the rounding errors of phi
+// and its powers grow with n. The result is right up to n = 75; F(76) comes
+// out as 3416454622906706 instead of 3416454622906707, and it only gets worse
+// from there. Past n = 93 F(n) does not fit in a u64 anyway, and "as" turns
+// the too large f64 into u64::MAX. A quick estimate, then, not a replacement
+// for the others, and nth refuses an n above BINET_MAX_N rather than print
+// a wrong answer
+const BINET_MAX_N: usize = 75;
+
+fn fib_binet(n: usize) -> u64 {
+    let sqrt5 = 5f64.sqrt();
+    let phi = (1.0 + sqrt5) / 2.0;
+    // .powi() raises to an integer power, by repeated multiplication, which
+    // stays accurate for longer than .powf() does for this. It takes an i32,
+    // and any n too large for one gives u64::MAX all the same
+    let n = n.min(i32::MAX as usize) as i32;
+    (phi.powi(n) / sqrt5).round() as u64
+}
+
+// A 2x2 matrix, stored row by row
+type Matrix = [[usize; 2]; 2];
+
+fn mat_mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut result = [[0; 2]; 2];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, cell) in row.iter_mut().enumerate() {
+            *cell = a[i][0].wrapping_mul(b[0][j])
+                .wrapping_add(a[i][1].wrapping_mul(b[1][j]));
+        }
+    }
+    result
+}
+
+// Raising the matrix [[1, 1], [1, 0]] to the power n gives
+//   [[F(n+1), F(n)], [F(n), F(n-1)]]
+// and exponentiation by squaring gets there in about log2(n) multiplications.
+// Like fib_fast(), the intermediate powers overflow before F(n) does, hence
+// the wrapping arithmetic in mat_mul().
+//
+// The result is right for as long as F(n) fits in a usize: up to n = 93 with
+// a 64 bit usize, F(93) = 12200160415121876738, and up to n = 47 with a 32 bit
+// one. Past that it is F(n) modulo 2^64 (or 2^32), which is to say garbage;
+// fib_matrix_big() has no such limit
+fn fib_matrix(n: usize) -> usize {
+    let mut result: Matrix = [[1, 0], [0, 1]];
+    let mut base: Matrix = [[1, 1], [1, 0]];
+    let mut exponent = n;
+
+    while exponent > 0 {
+        if exponent % 2 == 1 {
+            result = mat_mul(&result, &base);
+        }
+        base = mat_mul(&base, &base);
+        exponent /= 2;
+    }
+
+    result[0][1]
+}
+
+// The same 2x2 matrices, with BigUints in them. BigUint is not Copy, so the
+// arrays cannot be copied around like Matrix; they are built afresh instead
+type BigMatrix = [[BigUint; 2]; 2];
+
+fn big_mat_mul(a: &BigMatrix, b: &BigMatrix) -> BigMatrix {
+    let cell = |i: usize, j: usize| &a[i][0] * &b[0][j] + &a[i][1] * &b[1][j];
+    [[cell(0, 0), cell(0, 1)], [cell(1, 0), cell(1, 1)]]
+}
+
+// fib_matrix() without the overflow. Most of the time goes into multiplying
+// the large numbers of the last few steps, which is still far less work than
+// the n additions of fib_big() for a large n
+fn fib_matrix_big(n: usize) -> BigUint {
+    let number = |value: u32| BigUint::from(value);
+    let mut result: BigMatrix = [[number(1), number(0)], [number(0), number(1)]];
+    let mut base: BigMatrix = [[number(1), number(1)], [number(1), number(0)]];
+    let mut exponent = n;
+
+    while exponent > 0 {
+        if exponent % 2 == 1 {
+            result = big_mat_mul(&result, &base);
+        }
+        base = big_mat_mul(&base, &base);
+        exponent /= 2;
+    }
+
+    // A pattern can move a value out of an array, which indexing cannot
+    let [[_, fib], _] = result;
+    fib
+}
+
+// The largest n whose F(n) fits in a usize: F(93) = 12200160415121876738 with
+// a 64 bit usize, F(47) = 2971215073 with a 32 bit one. Past it fib_dp() and
+// the others built on "+" panic on the overflow, while fib_fast() and
+// fib_matrix() wrap around and return garbage, so a larger n is refused
+// before any of them gets to see it
+const MAX_N: usize = if usize::BITS == 64 { 93 } else { 47 };
+
+// The different ways of computing a Fibonacci number which can be picked from
+// the command line with --algo
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+    Recursive,
+    Memo,
+    Dp,
+    Fast,
+    Matrix,
+    Binet,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Result<Algorithm, String> {
+        match name {
+            "recursive" => Ok(Algorithm::Recursive),
+            "memo" => Ok(Algorithm::Memo),
+            "dp" => Ok(Algorithm::Dp),
+            "fast" => Ok(Algorithm::Fast),
+            "matrix" => Ok(Algorithm::Matrix),
+            "binet" => Ok(Algorithm::Binet),
+            _ => Err(format!("Unknown algorithm '{}', expected one of: \
+                              recursive, memo, dp, fast, matrix, binet", name)),
+        }
+    }
+
+    // The largest n function() gives the right F(n) for
+    fn max_n(self) -> usize {
+        match self {
+            Algorithm::Binet => BINET_MAX_N,
+            _ => MAX_N,
+        }
+    }
+
+    // Functions are values too: fn(usize) -> usize is the type of a pointer
+    // to any function taking a usize and returning a usize, which all of our
+    // implementations do. A closure which captures nothing can be one as
+    // well, which is how fib_memo() gets a fresh cache
+    fn function(self) -> fn(usize) -> usize {
+        match self {
+            Algorithm::Recursive => fib_recursive,
+            Algorithm::Memo => |n| fib_memo(n, &mut HashMap::new()),
+            Algorithm::Dp => fib_dp,
+            Algorithm::Fast => fib_fast,
+            Algorithm::Matrix => fib_matrix,
+            Algorithm::Binet => |n| fib_binet(n) as usize,
+        }
+    }
+}
+
+// Walks the sequence until it brackets target, i.e. until we have two
+// consecutive Fibonacci numbers with low < target <= high, and returns
+// whichever of the two is closer.
+//
+// When target sits exactly halfway between them, e.g. 17 between 13 and 21,
+// the larger one wins.
+//
+// F(93) is the largest Fibonacci number that fits in a u64, so for targets
+// above it F(93) is returned even if the (unrepresentable) F(94) is closer
+fn nearest_fibonacci(target: u64) -> u64 {
+    let mut low: u64 = 0;
+    let mut high: u64 = 1;
+
+    while high < target {
+        // .checked_add() returns None instead of overflowing
+        match low.checked_add(high) {
+            Some(next) => {
+                low = high;
+                high = next;
+            },
+            None => return high,
+        }
+    }
+
+    if target - low < high - target {
+        low
+    } else {
+        high
+    }
+}
+
+// Euclid's algorithm: the greatest common divisor of a and b is also the one
+// of b and the remainder of a / b, and the remainder shrinks at every step
+// until it reaches 0. gcd(a, 0) is a, and in particular gcd(0, 0) is 0
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// Zeckendorf's theorem: every positive integer can be written in exactly one
+// way as a sum of Fibonacci numbers, no two of which are consecutive in the
+// sequence. E.g. 100 = 89 + 8 + 3.
+//
+// The greedy approach finds it: take the largest Fibonacci number which
+// fits, then repeat with what is left. Returns the terms, largest first; 0 is
+// the empty sum
+fn zeckendorf(n: u64) -> Vec<u64> {
+    // The distinct Fibonacci numbers 1, 2, 3, 5, ... up to n. F(1) = F(2) = 1
+    // would otherwise be there twice
+    let mut fibs: Vec<u64> = vec![1, 2];
+    loop {
+        let len = fibs.len();
+        match fibs[len - 1].checked_add(fibs[len - 2]) {
+            Some(next) if next <= n => fibs.push(next),
+            _ => break,
+        }
+    }
+
+    let mut terms = Vec::new();
+    let mut remaining = n;
+    // Walk back down the sequence. After taking a number we also step over
+    // its predecessor, so two consecutive Fibonacci numbers can never both
+    // end up in the sum. (The predecessor could not fit anyway: what is left
+    // is always smaller than it, or we would have taken the next larger
+    // number instead.)
+    let mut index = fibs.len();
+    while remaining > 0 && index > 0 {
+        index -= 1;
+        if fibs[index] <= remaining {
+            terms.push(fibs[index]);
+            remaining -= fibs[index];
+            index = index.saturating_sub(1);
+        }
+    }
+
+    terms
+}
+
+// The inverse of zeckendorf(): adding the terms back up. .sum() works on any
+// iterator whose items can be added together
+fn from_zeckendorf(terms: &[u64]) -> u64 {
+    terms.iter().sum()
+}
+
+// What the user asked for on the command line
+#[derive(Debug, PartialEq)]
+enum Command {
+    // Compute F(n) both recursively and with dynamic programming, as for an
+    // n entered at the prompt
+    Compare(usize),
+    // Compute F(n) with the given algorithm
+    Nth(usize, Algorithm),
+    // Compute F(n) however large it is, with fib_big() or fib_matrix_big()
+    Big(usize, Algorithm),
+    // List the first n Fibonacci numbers
+    Seq(usize),
+    // Find the Fibonacci number closest to the given value
+    Nearest(u64),
+    // Show that gcd(F(m), F(n)) = F(gcd(m, n)) for the given m and n
+    Gcd(usize, usize),
+    // Write n as a sum of non-consecutive Fibonacci numbers
+    Zeckendorf(u64),
+}
+
+const USAGE: &str = "Usage: fibonacci [N]
+       fibonacci nth N [--algo recursive|memo|dp|fast|matrix|binet]
+       fibonacci big N [--algo dp|matrix]
+       fibonacci seq COUNT
+       fibonacci nearest TARGET
+       fibonacci gcd M N
+       fibonacci zeckendorf N";
+
+// Parses the command line arguments, without the name of the program:
+//   30
+//   nth 40 --algo fast
+//   big 500 --algo matrix
+//   seq 20
+//   nearest 20
+//   gcd 12 18
+//   zeckendorf 100
+// Returns Ok(None) when there are no arguments at all, in which case we ask
+// for n interactively instead
+fn parse_args(args: &[String]) -> Result<Option<Command>, String> {
+    if args.is_empty() {
+        return Ok(None);
+    }
+
+    // Just n, what we would ask for otherwise. Anything starting with a
+    // digit is taken for a number, so that "3O" is reported as the invalid
+    // number it is rather than as an unknown command
+    if args[0].starts_with(|c: char| c.is_ascii_digit()) {
+        if args.len() > 1 {
+            return Err(format!("Unexpected arguments: {}", args[1..].join(" ")));
+        }
+        let n = args[0].parse()
+            .map_err(|_| format!("Invalid number '{}'", args[0]))?;
+        if n > MAX_N {
+            return Err(format!("F({}) is too large, the largest n is {}; 'big' has no limit",
+                               n, MAX_N));
+        }
+        return Ok(Some(Command::Compare(n)));
+    }
+
+    // .get() returns an Option rather than panicking when the index is out
+    // of bounds
+    let number = match args.get(1) {
+        Some(number) => number,
+        None => return Err(format!("Missing the number after '{}'", args[0])),
+    };
+
+    match args[0].as_str() {
+        "nth" => {
+            let n = number.parse()
+                .map_err(|_| format!("Invalid number '{}'", number))?;
+
+            // Slice patterns let us match on the shape of what is left
+            let algorithm = match &args[2..] {
+                [] => Algorithm::Dp,
+                [flag, name] if flag == "--algo" => Algorithm::from_name(name)?,
+                _ => return Err(format!("Unexpected arguments: {}",
+                                        args[2..].join(" "))),
+            };
+            if n > algorithm.max_n() {
+                return Err(format!("F({}) is too large for this algorithm, which goes up \
+                                    to n = {}; 'big' has no limit", n, algorithm.max_n()));
+            }
+
+            Ok(Some(Command::Nth(n, algorithm)))
+        },
+        "big" => {
+            let n = number.parse()
+                .map_err(|_| format!("Invalid number '{}'", number))?;
+            // Only these two have a BigUint version
+            let algorithm = match &args[2..] {
+                [] => Algorithm::Dp,
+                [flag, name] if flag == "--algo" => match Algorithm::from_name(name)? {
+                    algorithm @ Algorithm::Dp | algorithm @ Algorithm::Matrix => algorithm,
+                    _ => return Err(format!("'{}' does not work with 'big', \
+                                             expected one of: dp, matrix", name)),
+                },
+                _ => return Err(format!("Unexpected arguments: {}",
+                                        args[2..].join(" "))),
+            };
+            Ok(Some(Command::Big(n, algorithm)))
+        },
+        "seq" => {
+            if args.len() > 2 {
+                return Err(format!("Unexpected arguments: {}",
+                                   args[2..].join(" ")));
+            }
+            let count = number.parse()
+                .map_err(|_| format!("Invalid number '{}'", number))?;
+            Ok(Some(Command::Seq(count)))
+        },
+        "nearest" | "zeckendorf" => {
+            if args.len() > 2 {
+                return Err(format!("Unexpected arguments: {}",
+                                   args[2..].join(" ")));
+            }
+            let value = number.parse()
+                .map_err(|_| format!("Invalid number '{}'", number))?;
+            if args[0] == "nearest" {
+                Ok(Some(Command::Nearest(value)))
+            } else {
+                Ok(Some(Command::Zeckendorf(value)))
+            }
+        },
+        "gcd" => {
+            // Slice patterns let us take both numbers in one go, and anything
+            // other than exactly two of them ends up in the error branch
+            match &args[1..] {
+                [m, n] => {
+                    let m = m.parse().map_err(|_| format!("Invalid number '{}'", m))?;
+                    let n = n.parse().map_err(|_| format!("Invalid number '{}'", n))?;
+                    // Both F(m) and F(n) are computed with fib_dp()
+                    if m > MAX_N || n > MAX_N {
+                        return Err(format!("Expected numbers up to {} after 'gcd'", MAX_N));
+                    }
+                    Ok(Some(Command::Gcd(m, n)))
+                },
+                _ => Err("Expected exactly two numbers after 'gcd'".to_owned()),
+            }
+        },
+        command => Err(format!("Unknown command '{}'", command)),
+    }
+}
+
+// What can go wrong when asking for n
+#[derive(Debug)]
+enum InputError {
+    // Reading from STDIN failed
+    Io(io::Error),
+    // STDIN was closed, e.g. with Ctrl-D, before a line was entered
+    Closed,
+    // Only an empty line, or whitespace
+    Empty,
+    // Something which is not a number we can compute the Fibonacci number of,
+    // like "abc" or "-3"
+    NotANumber(String),
+    // A number whose Fibonacci number is too large for a usize, see MAX_N
+    TooLarge(usize),
+}
+
+// Display is what "{}" uses, so the error can be shown to the user
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InputError::Io(ref e) => write!(f, "Failed to read from STDIN: {}", e),
+            InputError::Closed => write!(f, "No input"),
+            InputError::Empty => write!(f, "Nothing entered"),
+            InputError::NotANumber(ref input) => write!(f, "'{}' is not a number", input),
+            InputError::TooLarge(n) => {
+                write!(f, "F({}) is too large, the largest n is {}", n, MAX_N)
+            },
+        }
+    }
+}
+
+// The From trait is what the "?" operator uses to convert errors: with this in
+// place, "?" on an io::Result turns the io::Error into an InputError by itself
+impl From<io::Error> for InputError {
+    fn from(e: io::Error) -> InputError {
+        InputError::Io(e)
+    }
+}
+
+// Turns a line the user entered into n. The line break at the end, and any
+// other whitespace around the number, is left out by .trim()
+//
+// .parse() uses the trait FromStr to determine whether a type supports this
+// operation (converting to something from a String). It returns a Result, an
+// enum which is either Ok with the value or Err with what went wrong, and
+// .map_err() swaps the error for one of ours
+fn parse_n(line: &str) -> Result<usize, InputError> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Err(InputError::Empty);
+    }
+    let n = line.parse().map_err(|_| InputError::NotANumber(line.to_owned()))?;
+    if n > MAX_N {
+        return Err(InputError::TooLarge(n));
+    }
+    Ok(n)
+}
+
+// Reads a line from STDIN and makes n out of it
+fn read_n() -> Result<usize, InputError> {
+    // ::new() is an associated function implemented on a type, in this case
+    // on type String, rather than an instance of String. They are basically
     // equivalent to C++'s static methods
     //
     // Making the variable "mut" (mutable) allows us to change it. In Rust
     // variables are constant (unmutable) by default
-    let mut n = String::new();
+    let mut line = String::new();
 
-    // stdin() returns an instance of std::io::Stdin, which represents a handle
-    // to the STD output. Equivalent to C++'s std::cin
-    //
-    // .read_line() is used to read until a newline character is found
+    // stdin() returns an instance of std::io::Stdin, which represents a
+    // handle to the STD input. Equivalent to C++'s std::cin
     //
-    // .expect() checks the return type of .read_line(), which is an enum, and
-    // uses the match control operator to check whether the function returned
-    // an error enum variant or a success enum variant. On success, it returns
-    // the value which the function returns upon success, whereas upon failure
-    // it will panic and exit the program after printing the message we pased to
-    // it.
-    //
-    // See enums in the Rust book and std::result, std::result::Result for more
-    // information
-    io::stdin()
-        .read_line(&mut n)
-        .expect("Failed to read from STDIN!");
-
-    // In Rust we can perform variable "shadowing". Here the previous
-    // declaration of "n" was of type String but now we declare a new variable
-    // with name "n" but of numerical type, which is obtained by running the
-    // .parse() method. When referring to "n" we will use the latest instance
-    // of a type named "n" and not the String instance anymore.
-    //
-    // .parse() uses the trait FromStr to determine whether
-    // a type supports this operation (converting to something from a String)
-    let n: u64 = n.trim().parse().expect("Invalid input!");
+    // .read_line() is used to read until a newline character is found, and
+    // returns how many bytes it read: none at all means STDIN is closed.
+    // "?" returns early with the error if it failed
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Err(InputError::Closed);
+    }
+    parse_n(&line)
+}
+
+// Asks for n until we get an answer we can use
+fn prompt_n() -> usize {
+    loop {
+        // "print!" is like "println!" which is used in most examples in the
+        // rust book but it doesn't output a newline
+        print!("Enter what Fibonacci number you want to compute: ");
+        // We need to flush the buffer because print!() does not output a
+        // newline character and does not flush; it uses STDOUT which is
+        // buffered
+        io::stdout().flush().unwrap();
 
+        match read_n() {
+            Ok(n) => return n,
+            // Asking again would not help with these
+            Err(error @ InputError::Io(_)) | Err(error @ InputError::Closed) => {
+                eprintln!("\n{}", error);
+                process::exit(1);
+            },
+            Err(error) => eprintln!("{}, please try again", error),
+        }
+    }
+}
+
+fn main() {
+    // The first argument is the path of the executable, which we skip
+    let args: Vec<String> = env::args().skip(1).collect();
+    let n = match parse_args(&args) {
+        Ok(Some(Command::Compare(n))) => n,
+        Ok(Some(Command::Nth(n, algorithm))) => {
+            println!("{}", algorithm.function()(n));
+            return;
+        },
+        // BigUint implements Display, so it prints like any other number
+        Ok(Some(Command::Big(n, algorithm))) => {
+            if algorithm == Algorithm::Matrix {
+                println!("{}", fib_matrix_big(n));
+            } else {
+                println!("{}", fib_big(n));
+            }
+            return;
+        },
+        // There are only 187 numbers to give before the iterator runs out,
+        // so asking for more gets those
+        Ok(Some(Command::Seq(count))) => {
+            for fib in FibIter::new().take(count) {
+                println!("{}", fib);
+            }
+            return;
+        },
+        Ok(Some(Command::Nearest(target))) => {
+            println!("{}", nearest_fibonacci(target));
+            return;
+        },
+        Ok(Some(Command::Gcd(m, n))) => {
+            // "as" converts between numeric types; usize and u64 are the same
+            // size on 64 bit platforms, so nothing is lost
+            let left = gcd(fib_dp(m) as u64, fib_dp(n) as u64);
+            let right = fib_dp(gcd(m as u64, n as u64) as usize) as u64;
+            println!("gcd(F({}), F({})) = {}", m, n, left);
+            println!("F(gcd({}, {})) = {}", m, n, right);
+            return;
+        },
+        Ok(Some(Command::Zeckendorf(n))) => {
+            let terms = zeckendorf(n);
+            // Turn every term into a String so that they can be joined
+            let sum: Vec<String> = terms.iter().map(|term| term.to_string()).collect();
+            if sum.is_empty() {
+                // Zero is the sum of no numbers at all
+                println!("0 = the empty sum");
+            } else {
+                println!("{} = {}", from_zeckendorf(&terms), sum.join(" + "));
+            }
+            return;
+        },
+        Ok(None) => prompt_n(),
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            process::exit(2);
+        },
+    };
 
     println!("The computed value, computed recursively, is: {}",
              fib_recursive(n));
     println!("The computed value, computed functionally, is: {}", fib_dp(n));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_owned).collect()
+    }
+
+    // The first few Fibonacci numbers, to check the algorithms against
+    const FIRST: [usize; 12] = [0, 1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89];
+
+    #[test]
+    fn every_algorithm_agrees() {
+        let names = ["recursive", "memo", "dp", "fast", "matrix", "binet"];
+        for name in &names {
+            let fib = Algorithm::from_name(name).unwrap().function();
+            for (n, &expected) in FIRST.iter().enumerate() {
+                assert_eq!(fib(n), expected, "{} for n = {}", name, n);
+            }
+        }
+        assert_eq!(fib_fast(MAX_N), fib_dp(MAX_N));
+        assert_eq!(fib_fast(90), 2_880_067_194_370_816_120);
+    }
+
+    #[test]
+    fn picks_the_algorithm_with_algo() {
+        assert_eq!(parse_args(&args("nth 40 --algo fast")),
+                   Ok(Some(Command::Nth(40, Algorithm::Fast))));
+        assert_eq!(parse_args(&args("nth 40")), Ok(Some(Command::Nth(40, Algorithm::Dp))));
+        assert!(parse_args(&args("nth 40 --algo slow")).unwrap_err().contains("Unknown algorithm"));
+        assert!(parse_args(&args("nth 40 --algo")).is_err());
+        assert!(parse_args(&args("nth 94 --algo fast")).unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn finds_the_nearest_fibonacci_number() {
+        assert_eq!(nearest_fibonacci(0), 0);
+        assert_eq!(nearest_fibonacci(1), 1);
+        assert_eq!(nearest_fibonacci(20), 21);
+        assert_eq!(nearest_fibonacci(15), 13);
+        // Halfway between 13 and 21, the larger one wins
+        assert_eq!(nearest_fibonacci(17), 21);
+        assert_eq!(nearest_fibonacci(89), 89);
+        assert_eq!(nearest_fibonacci(u64::MAX), fib_dp(93) as u64);
+        assert_eq!(parse_args(&args("nearest 20")), Ok(Some(Command::Nearest(20))));
+    }
+
+    #[test]
+    fn gcd_of_fibonacci_numbers_is_a_fibonacci_number() {
+        assert_eq!(gcd(12, 18), 6);
+        assert_eq!(gcd(7, 0), 7);
+        assert_eq!(gcd(0, 0), 0);
+        for m in 0..30 {
+            for n in 0..30 {
+                let (fm, fn_) = (fib_dp(m) as u64, fib_dp(n) as u64);
+                assert_eq!(gcd(fm, fn_), fib_dp(gcd(m as u64, n as u64) as usize) as u64);
+            }
+        }
+        assert_eq!(parse_args(&args("gcd 12 18")), Ok(Some(Command::Gcd(12, 18))));
+        assert!(parse_args(&args("gcd 12")).is_err());
+        assert!(parse_args(&args("gcd 12 100")).is_err());
+    }
+
+    #[test]
+    fn writes_numbers_as_sums_of_non_consecutive_fibonacci_numbers() {
+        assert_eq!(zeckendorf(0), Vec::<u64>::new());
+        assert_eq!(zeckendorf(1), vec![1]);
+        assert_eq!(zeckendorf(100), vec![89, 8, 3]);
+        assert_eq!(zeckendorf(4), vec![3, 1]);
+        for n in 0..2000 {
+            let terms = zeckendorf(n);
+            assert_eq!(from_zeckendorf(&terms), n);
+            // No two terms next to each other in the sequence: one term
+            // apart, F(k + 2) = F(k + 1) + F(k) is more than twice F(k)
+            for pair in terms.windows(2) {
+                assert!(pair[0] > pair[1] + pair[1], "{:?} for {}", terms, n);
+            }
+        }
+        assert_eq!(from_zeckendorf(&zeckendorf(u64::MAX)), u64::MAX);
+    }
+
+    #[test]
+    fn memo_fills_and_reuses_its_cache() {
+        let mut cache = HashMap::new();
+        assert_eq!(fib_memo(50, &mut cache), 12_586_269_025);
+        // Every F(i) from F(2) on was computed once, and kept
+        assert_eq!(cache.len(), 49);
+        assert_eq!(cache[&30], 832_040);
+        // Deep down the recursion, no further than MAX_N
+        assert_eq!(fib_memo(MAX_N, &mut cache), fib_dp(MAX_N));
+        assert_eq!(cache.len(), MAX_N - 1);
+    }
+
+    #[test]
+    fn big_fibonacci_numbers_go_past_u64() {
+        assert_eq!(fib_big(0), BigUint::from(0u32));
+        assert_eq!(fib_big(93), BigUint::from(fib_dp(93) as u64));
+        assert_eq!(fib_big(100).to_string(), "354224848179261915075");
+        assert_eq!(fib_big(500).to_string().len(), 105);
+        assert_eq!(parse_args(&args("big 500")), Ok(Some(Command::Big(500, Algorithm::Dp))));
+        assert!(parse_args(&args("big 500 --algo fast")).is_err());
+    }
+
+    #[test]
+    fn matrix_fibonacci_matches_the_others() {
+        for n in 0..=MAX_N {
+            assert_eq!(fib_matrix(n), fib_dp(n), "n = {}", n);
+        }
+        for &n in &[0, 1, 2, 93, 100, 500, 1000] {
+            assert_eq!(fib_matrix_big(n), fib_big(n), "n = {}", n);
+        }
+        assert_eq!(parse_args(&args("big 1000 --algo matrix")),
+                   Ok(Some(Command::Big(1000, Algorithm::Matrix))));
+    }
+
+    #[test]
+    fn iterates_over_the_sequence() {
+        let first: Vec<u128> = FibIter::new().take(FIRST.len()).collect();
+        let expected: Vec<u128> = FIRST.iter().map(|&f| f as u128).collect();
+        assert_eq!(first, expected);
+        // It ends after F(186), the last one which fits in a u128
+        assert_eq!(FibIter::new().count(), 187);
+        assert_eq!(FibIter::new().last().map(|f| f.to_string()),
+                   Some(fib_big(186).to_string()));
+        assert_eq!(parse_args(&args("seq 20")), Ok(Some(Command::Seq(20))));
+    }
+
+    #[test]
+    fn parses_n_or_says_what_is_wrong() {
+        assert_eq!(parse_n(" 42\n").unwrap(), 42);
+        assert_eq!(parse_n(&MAX_N.to_string()).unwrap(), MAX_N);
+        assert!(matches!(parse_n("\n"), Err(InputError::Empty)));
+        assert!(matches!(parse_n("abc"), Err(InputError::NotANumber(ref input)) if input == "abc"));
+        assert!(matches!(parse_n("-3"), Err(InputError::NotANumber(_))));
+        assert!(matches!(parse_n("94"), Err(InputError::TooLarge(94))));
+        assert_eq!(InputError::TooLarge(94).to_string(),
+                   format!("F(94) is too large, the largest n is {}", MAX_N));
+    }
+
+    #[test]
+    fn takes_n_from_the_command_line() {
+        assert_eq!(parse_args(&[]), Ok(None));
+        assert_eq!(parse_args(&args("30")), Ok(Some(Command::Compare(30))));
+        assert_eq!(parse_args(&args("zeckendorf 100")), Ok(Some(Command::Zeckendorf(100))));
+        assert_eq!(parse_args(&args("3O")), Err("Invalid number '3O'".to_owned()));
+        assert!(parse_args(&args("30 40")).unwrap_err().contains("Unexpected"));
+        assert!(parse_args(&args("94")).unwrap_err().contains("too large"));
+        assert_eq!(parse_args(&args("nth")), Err("Missing the number after 'nth'".to_owned()));
+        assert_eq!(parse_args(&args("frobnicate 3")),
+                   Err("Unknown command 'frobnicate'".to_owned()));
+    }
+
+    #[test]
+    fn binet_is_exact_up_to_its_limit() {
+        for n in 0..=BINET_MAX_N {
+            assert_eq!(fib_binet(n), fib_dp(n) as u64, "n = {}", n);
+        }
+        assert_eq!(Algorithm::Binet.max_n(), BINET_MAX_N);
+        assert!(parse_args(&args("nth 76 --algo binet")).is_err());
+        assert_eq!(parse_args(&args("nth 75 --algo binet")),
+                   Ok(Some(Command::Nth(75, Algorithm::Binet))));
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::ops;
 
@@ -7,6 +8,17 @@ use std::ops;
 // very similar to the one used to declare class templates in C++ but in rust
 // we do not need to add a prelued with "template<typename T>".
 //
+// #[derive(...)] asks the compiler to write the implementation of some common
+// traits for us. For a generic struct the generated code only applies when T
+// itself implements the trait, i.e. the derive expands to something like
+//   impl<T: Hash> Hash for Point<T> { ... }
+// Hash and Eq are what HashMap and HashSet require from their keys. This means
+// Point<i32> can be used as a key, but Point<f64> cannot: floats are only
+// PartialEq, since NaN is not equal to itself
+//
+// Clone and Copy let us duplicate a point simply by assigning it, like we
+// would do with an integer, instead of moving it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct Point<T> {
     x: T,
     y: T,
@@ -27,7 +39,7 @@ struct Point<T> {
 // expect to use
 impl<T: fmt::Display> fmt::Display for Point<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "x:{}, y:{}", self.x, self.x)
+        write!(f, "x:{}, y:{}", self.x, self.y)
     }
 }
 
@@ -44,6 +56,251 @@ impl<T: ops::Sub<Output = T>> ops::Sub for Point<T> {
     }
 }
 
+// Addition works the same way as subtraction above
+impl<T: ops::Add<Output = T>> ops::Add for Point<T> {
+    type Output = Point<T>;
+    fn add(self, rhs: Self::Output) -> Self::Output {
+        Point {x: self.x + rhs.x, y: self.y + rhs.y}
+    }
+}
+
+// Adds up everything in the slice. The bounds say all we need from T: that
+// two of them can be added into a third, and that they can be copied out of
+// the slice. Numbers qualify, and so does Point<T> thanks to the impl above
+// (derive(Copy) plus ops::Add), without sum_all() knowing anything about
+// points.
+//
+// There is no "zero" we could start from for an arbitrary T, so we start
+// from the first element instead, and an empty slice has no sum at all
+fn sum_all<T: ops::Add<Output = T> + Copy>(items: &[T]) -> Option<T> {
+    // .split_first() gives us the first element and the rest of the slice;
+    // .map() runs the closure only when the slice was not empty
+    items.split_first().map(|(&first, rest)| {
+        rest.iter().fold(first, |total, &item| total + item)
+    })
+}
+
+// The standard library gives every signed number type an .abs() method, but
+// each is defined on the type itself and there is no trait tying them
+// together, so generic code cannot call it. We fill the gap with our own trait
+trait Abs {
+    fn abs(self) -> Self;
+}
+
+// Writing the same impl for every numeric type by hand would be tedious, so we
+// let a macro do it. macro_rules! matches its input against a pattern; here
+// "$($t:ty),*" means "a comma-separated list of types", and everything inside
+// $(...)* is repeated once for each of them
+macro_rules! impl_abs {
+    ($($t:ty),*) => {
+        $(
+            impl Abs for $t {
+                // Calls the inherent method of the number type, as inherent
+                // methods take precedence over trait methods
+                fn abs(self) -> $t {
+                    self.abs()
+                }
+            }
+        )*
+    };
+}
+
+impl_abs!(i8, i16, i32, i64, i128, isize, f32, f64);
+
+// Only the impl block needs the bound: abs() exists for any Point whose
+// coordinates implement Abs
+impl<T: Abs> Point<T> {
+    // Makes both coordinates non-negative, e.g. to turn the difference of two
+    // points into a distance along each axis
+    fn abs(self) -> Point<T> {
+        Point {x: self.x.abs(), y: self.y.abs()}
+    }
+}
+
+// A generic type does not have to be implemented generically: here we add
+// methods which only exist on Point<i32>, since "the cell next to this one"
+// only makes sense on an integer grid. Calling .neighbors4() on a Point<f64>
+// would be a compile-time error
+impl Point<i32> {
+    // The cells sharing an edge with this one, clockwise starting from above.
+    // Arrays have their length as part of their type, so the signature alone
+    // tells the caller exactly how many points to expect
+    fn neighbors4(&self) -> [Point<i32>; 4] {
+        [
+            Point {x: self.x, y: self.y - 1},
+            Point {x: self.x + 1, y: self.y},
+            Point {x: self.x, y: self.y + 1},
+            Point {x: self.x - 1, y: self.y},
+        ]
+    }
+
+    // The cells sharing an edge or a corner with this one, clockwise starting
+    // from the top-left corner
+    fn neighbors8(&self) -> [Point<i32>; 8] {
+        [
+            Point {x: self.x - 1, y: self.y - 1},
+            Point {x: self.x, y: self.y - 1},
+            Point {x: self.x + 1, y: self.y - 1},
+            Point {x: self.x + 1, y: self.y},
+            Point {x: self.x + 1, y: self.y + 1},
+            Point {x: self.x, y: self.y + 1},
+            Point {x: self.x - 1, y: self.y + 1},
+            Point {x: self.x - 1, y: self.y},
+        ]
+    }
+}
+
+// Points with float coordinates can be treated as 2D vectors, going from the
+// origin to the point
+impl Point<f64> {
+    // The dot product: the product of the two lengths times the cosine of the
+    // angle between the vectors, which is 0 when they are perpendicular
+    fn dot(&self, other: &Point<f64>) -> f64 {
+        self.x * other.x + self.y * other.y
+    }
+
+    // The length of the vector, by Pythagoras. .hypot() computes
+    // sqrt(x^2 + y^2) without overflowing for very large coordinates
+    fn magnitude(&self) -> f64 {
+        self.x.hypot(self.y)
+    }
+
+    // A vector with the same direction and a length of 1. The zero vector has
+    // no direction, so there is nothing sensible to return for it
+    fn normalize(&self) -> Option<Point<f64>> {
+        let magnitude = self.magnitude();
+        if magnitude == 0.0 {
+            None
+        } else {
+            Some(Point {x: self.x / magnitude, y: self.y / magnitude})
+        }
+    }
+
+    // The vector projection of self onto other, i.e. the "shadow" self casts
+    // on the line going through other:
+    //   (self . other / other . other) * other
+    // A zero-length other does not define a direction to project onto, and
+    // would make us divide by zero; we return the origin instead
+    fn project_onto(&self, other: &Point<f64>) -> Point<f64> {
+        let length_squared = other.dot(other);
+        if length_squared == 0.0 {
+            return Point {x: 0.0, y: 0.0};
+        }
+
+        let scale = self.dot(other) / length_squared;
+        Point {x: scale * other.x, y: scale * other.y}
+    }
+
+    // Floating point arithmetic rounds at every step, so 0.1 + 0.2 is not
+    // exactly 0.3 and == between computed points is rarely what we want.
+    // Instead we accept any difference up to epsilon on each coordinate
+    fn approx_eq(&self, other: &Point<f64>, epsilon: f64) -> bool {
+        (self.x - other.x).abs() <= epsilon && (self.y - other.y).abs() <= epsilon
+    }
+}
+
+// The four functions below all do the same thing, walking through the points
+// and keeping the best one seen so far; only what "best" means changes. So
+// they share this helper, which takes the comparison as a closure: F is any
+// type which can be called like a function with two points, returning true
+// when the first one should replace the second.
+//
+// The "?" after .next() returns None straight away for an empty slice. When
+// several points are equally good, the first of them is kept
+fn select_by<T, F>(points: &[Point<T>], is_better: F) -> Option<Point<T>>
+    where T: Copy, F: Fn(&Point<T>, &Point<T>) -> bool {
+    let mut iter = points.iter();
+    let mut best = *iter.next()?;
+    for point in iter {
+        if is_better(point, &best) {
+            best = *point;
+        }
+    }
+    Some(best)
+}
+
+// These require PartialOrd rather than Ord, so they work with float points
+// too. Copy is needed to hand back a copy of the point rather than a
+// reference into the slice
+fn min_x<T: PartialOrd + Copy>(points: &[Point<T>]) -> Option<Point<T>> {
+    select_by(points, |a, b| a.x < b.x)
+}
+
+fn max_x<T: PartialOrd + Copy>(points: &[Point<T>]) -> Option<Point<T>> {
+    select_by(points, |a, b| a.x > b.x)
+}
+
+fn min_y<T: PartialOrd + Copy>(points: &[Point<T>]) -> Option<Point<T>> {
+    select_by(points, |a, b| a.y < b.y)
+}
+
+fn max_y<T: PartialOrd + Copy>(points: &[Point<T>]) -> Option<Point<T>> {
+    select_by(points, |a, b| a.y > b.y)
+}
+
+// Breadth-first search: finds one of the shortest paths from start to goal,
+// moving one cell up, down, left or right at a time and never entering a
+// blocked cell. The path includes both start and goal; None means that goal
+// cannot be reached.
+//
+// The grid has no edges, so if goal is walled off the search would go on
+// forever. To avoid that we only explore the smallest rectangle containing
+// start, goal and every blocked cell, grown by one cell on each side: that
+// ring around the obstacles is always free, so a shortest path never needs to
+// step outside of it
+fn bfs(start: Point<i32>, goal: Point<i32>, blocked: &HashSet<Point<i32>>)
+       -> Option<Vec<Point<i32>>> {
+    if blocked.contains(&start) || blocked.contains(&goal) {
+        return None;
+    }
+
+    // .fold() threads an accumulator, the bounds found so far, through every
+    // element of the iterator and returns it at the end
+    let (min_x, min_y, max_x, max_y) = blocked.iter().fold(
+        (start.x.min(goal.x), start.y.min(goal.y),
+         start.x.max(goal.x), start.y.max(goal.y)),
+        |(min_x, min_y, max_x, max_y), p| {
+            (min_x.min(p.x), min_y.min(p.y), max_x.max(p.x), max_y.max(p.y))
+        });
+    let in_bounds = |p: &Point<i32>| {
+        p.x >= min_x - 1 && p.x <= max_x + 1 &&
+        p.y >= min_y - 1 && p.y <= max_y + 1
+    };
+
+    // For every cell we reach we remember the cell we came from; walking
+    // these links backwards from goal gives us the path. The map doubles as
+    // the set of visited cells
+    let mut came_from = HashMap::new();
+    let mut frontier = VecDeque::new();
+    came_from.insert(start, start);
+    frontier.push_back(start);
+
+    // "while let" keeps looping for as long as the pattern matches, i.e. until
+    // .pop_front() returns None because the queue is empty
+    while let Some(current) = frontier.pop_front() {
+        if current == goal {
+            let mut path = vec![current];
+            let mut step = current;
+            while step != start {
+                step = came_from[&step];
+                path.push(step);
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        for next in &current.neighbors4() {
+            if in_bounds(next) && !blocked.contains(next) &&
+               !came_from.contains_key(next) {
+                came_from.insert(*next, current);
+                frontier.push_back(*next);
+            }
+        }
+    }
+
+    None
+}
+
 fn main() {
     // First we create variables of type Point
     let a = Point{x: 12, y: 13};
@@ -52,4 +309,203 @@ fn main() {
     // Then we use the newly defined trait implementations
     let c = a - b;
     println!("Point c: {}", c);
+
+    // Because Point<i32> is hashable, we can keep track of the cells of a grid
+    // which have been visited. Inserting the same point twice leaves the set
+    // unchanged: .insert() returns false to tell us it was already there
+    let mut visited = HashSet::new();
+    visited.insert(Point{x: 0, y: 0});
+    visited.insert(Point{x: 1, y: 0});
+    let was_new = visited.insert(Point{x: 0, y: 0});
+    println!("Visited {} cells, (0, 0) inserted twice: {}",
+             visited.len(), !was_new);
+    println!("Has (1, 0) been visited? {}",
+             visited.contains(&Point{x: 1, y: 0}));
+
+    // Iterating over an array by reference yields references to its elements
+    let origin = Point{x: 0, y: 0};
+    for neighbor in &origin.neighbors4() {
+        println!("Edge neighbor of the origin: {}", neighbor);
+    }
+    println!("The origin has {} neighbors counting corners",
+             origin.neighbors8().len());
+
+    // A wall with a gap at the bottom: the path has to go around it
+    let wall: HashSet<_> = (-1..2).map(|y| Point{x: 1, y}).collect();
+    let goal = Point{x: 2, y: 0};
+    match bfs(origin, goal, &wall) {
+        Some(path) => {
+            println!("Shortest path to {} takes {} steps:", goal, path.len() - 1);
+            for step in &path {
+                println!("  {}", step);
+            }
+        },
+        None => println!("{} cannot be reached", goal),
+    }
+
+    // The selection functions work on any slice of points, here some floats.
+    // {:?} prints the Option with the Debug formatting derived for Point
+    let points = [Point{x: 1.5, y: -2.0}, Point{x: -3.0, y: 0.5},
+                  Point{x: 4.0, y: 1.0}];
+    println!("Leftmost: {:?}, rightmost: {:?}", min_x(&points), max_x(&points));
+    println!("Lowest: {:?}, highest: {:?}", min_y(&points), max_y(&points));
+    let no_points: [Point<f64>; 0] = [];
+    println!("Leftmost of no points at all: {:?}", min_x(&no_points));
+
+    // Projecting onto the x axis keeps the x component only; projecting onto
+    // a perpendicular vector leaves nothing
+    let v = Point{x: 3.0, y: 4.0};
+    let x_axis = Point{x: 2.0, y: 0.0};
+    println!("{} projected onto {} is {}", v, x_axis, v.project_onto(&x_axis));
+    println!("{} projected onto {} is {}", v, Point{x: -4.0, y: 3.0},
+             v.project_onto(&Point{x: -4.0, y: 3.0}));
+
+    println!("Absolute values: {} and {}",
+             Point{x: -3, y: 7}.abs(), Point{x: -0.5, y: -2.5}.abs());
+
+    // The same generic function sums numbers and points alike
+    println!("Sum of integers: {:?}", sum_all(&[1, 2, 3, 4]));
+    println!("Sum of points: {:?}",
+             sum_all(&[Point{x: 1, y: 2}, Point{x: 3, y: 4}, Point{x: -1, y: 0}]));
+    let nothing: [i32; 0] = [];
+    println!("Sum of nothing: {:?}", sum_all(&nothing));
+
+    // 0.1 + 0.2 ends up a tiny bit above 0.3
+    let computed = Point{x: 0.1, y: 0.2} + Point{x: 0.2, y: 0.1};
+    let expected = Point{x: 0.3, y: 0.3};
+    println!("{:?} == {:?}? {}, approximately? {}", computed, expected,
+             computed == expected, computed.approx_eq(&expected, 1e-9));
+
+    // if let is a match with a single arm we care about
+    if let Some(unit) = v.normalize() {
+        println!("{} normalized is {}, of length {}", v, unit, unit.magnitude());
+    }
+    println!("The zero vector normalized: {:?}", Point{x: 0.0, y: 0.0}.normalize());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_points_hash_alike() {
+        let mut names = HashMap::new();
+        names.insert(Point {x: 1, y: 2}, "a");
+        names.insert(Point {x: 1, y: 2}, "b");
+        names.insert(Point {x: 2, y: 1}, "c");
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[&Point {x: 1, y: 2}], "b");
+    }
+
+    #[test]
+    fn lists_the_neighbors_clockwise() {
+        let p = Point {x: 5, y: 5};
+        assert_eq!(p.neighbors4(), [Point {x: 5, y: 4}, Point {x: 6, y: 5},
+                                    Point {x: 5, y: 6}, Point {x: 4, y: 5}]);
+        let eight = p.neighbors8();
+        assert_eq!(eight[0], Point {x: 4, y: 4});
+        assert_eq!(eight[7], Point {x: 4, y: 5});
+        // Every edge neighbor is a corner neighbor too, and none is p itself
+        assert!(p.neighbors4().iter().all(|n| eight.contains(n)));
+        assert!(!eight.contains(&p));
+        assert_eq!(eight.iter().collect::<HashSet<_>>().len(), 8);
+    }
+
+    #[test]
+    fn bfs_goes_around_a_wall() {
+        let wall: HashSet<_> = (-1..2).map(|y| Point {x: 1, y}).collect();
+        let path = bfs(Point {x: 0, y: 0}, Point {x: 2, y: 0}, &wall).unwrap();
+        assert_eq!(path.len() - 1, 6);
+        assert_eq!(path[0], Point {x: 0, y: 0});
+        assert_eq!(path[path.len() - 1], Point {x: 2, y: 0});
+        // Every step goes to an edge neighbor which is not blocked
+        for step in path.windows(2) {
+            assert!(step[0].neighbors4().contains(&step[1]));
+            assert!(!wall.contains(&step[1]));
+        }
+    }
+
+    #[test]
+    fn bfs_gives_up_on_a_walled_off_goal() {
+        let goal = Point {x: 3, y: 3};
+        let walls: HashSet<_> = goal.neighbors4().iter().cloned().collect();
+        assert_eq!(bfs(Point {x: 0, y: 0}, goal, &walls), None);
+        assert_eq!(bfs(Point {x: 0, y: 0}, goal, &[goal].iter().cloned().collect()), None);
+    }
+
+    #[test]
+    fn bfs_to_the_start_is_the_start() {
+        let start = Point {x: 1, y: 1};
+        assert_eq!(bfs(start, start, &HashSet::new()), Some(vec![start]));
+    }
+
+    #[test]
+    fn selects_by_coordinate() {
+        let points = [Point {x: 1.5, y: -2.0}, Point {x: -3.0, y: 0.5}, Point {x: 4.0, y: 1.0}];
+        assert_eq!(min_x(&points), Some(points[1]));
+        assert_eq!(max_x(&points), Some(points[2]));
+        assert_eq!(min_y(&points), Some(points[0]));
+        assert_eq!(max_y(&points), Some(points[2]));
+        let none: [Point<i32>; 0] = [];
+        assert_eq!(min_x(&none), None);
+    }
+
+    #[test]
+    fn keeps_the_first_of_equally_good_points() {
+        let points = [Point {x: 0, y: 1}, Point {x: 0, y: 2}];
+        assert_eq!(min_x(&points), Some(points[0]));
+        assert_eq!(max_x(&points), Some(points[0]));
+    }
+
+    #[test]
+    fn projects_onto_another_vector() {
+        let v = Point {x: 3.0, y: 4.0};
+        assert_eq!(v.project_onto(&Point {x: 2.0, y: 0.0}), Point {x: 3.0, y: 0.0});
+        assert_eq!(v.project_onto(&Point {x: -4.0, y: 3.0}), Point {x: 0.0, y: 0.0});
+        assert!(v.project_onto(&Point {x: 1.0, y: 1.0}).approx_eq(&Point {x: 3.5, y: 3.5}, 1e-12));
+        // Onto itself, a vector stays where it is
+        assert!(v.project_onto(&v).approx_eq(&v, 1e-12));
+    }
+
+    #[test]
+    fn projects_onto_the_zero_vector_as_the_origin() {
+        let v = Point {x: 3.0, y: 4.0};
+        assert_eq!(v.project_onto(&Point {x: 0.0, y: 0.0}), Point {x: 0.0, y: 0.0});
+    }
+
+    #[test]
+    fn takes_the_absolute_value_of_each_coordinate() {
+        assert_eq!(Point {x: -3, y: 7}.abs(), Point {x: 3, y: 7});
+        assert_eq!(Point {x: -0.5, y: -2.5}.abs(), Point {x: 0.5, y: 2.5});
+        assert_eq!(Point {x: 0i8, y: -127}.abs(), Point {x: 0, y: 127});
+    }
+
+    #[test]
+    fn sums_numbers_and_points_alike() {
+        assert_eq!(sum_all(&[1, 2, 3, 4]), Some(10));
+        assert_eq!(sum_all(&[Point {x: 1, y: 2}, Point {x: 3, y: 4}, Point {x: -1, y: 0}]),
+                   Some(Point {x: 3, y: 6}));
+        assert_eq!(sum_all(&[2.5]), Some(2.5));
+        let nothing: [i32; 0] = [];
+        assert_eq!(sum_all(&nothing), None);
+    }
+
+    #[test]
+    fn compares_float_points_with_a_tolerance() {
+        let computed = Point {x: 0.1, y: 0.2} + Point {x: 0.2, y: 0.1};
+        let expected = Point {x: 0.3, y: 0.3};
+        assert_ne!(computed, expected);
+        assert!(computed.approx_eq(&expected, 1e-9));
+        assert!(!computed.approx_eq(&Point {x: 0.3, y: 0.31}, 1e-9));
+        // The tolerance itself is still close enough
+        assert!(Point {x: 1.0, y: 1.0}.approx_eq(&Point {x: 1.5, y: 0.5}, 0.5));
+    }
+
+    #[test]
+    fn normalizes_to_unit_length() {
+        let unit = Point {x: 3.0, y: 4.0}.normalize().unwrap();
+        assert!(unit.approx_eq(&Point {x: 0.6, y: 0.8}, 1e-12));
+        assert!((unit.magnitude() - 1.0).abs() < 1e-12);
+        assert_eq!(Point {x: 0.0, y: 0.0}.normalize(), None);
+    }
 }
@@ -0,0 +1,140 @@
+// Which addresses may talk to the server at all, as lists of networks in
+// CIDR notation, the address of the network and how many of its leading bits
+// count:
+//   cargo run -- --allow 10.0.0.0/8 --allow 192.168.1.0/24 --deny 10.0.0.13
+// A bare address is a network of one. Anybody else gets a 403 Forbidden.
+//
+// A client in both lists is denied, so that a few bad apples can be taken
+// out of a network which is allowed otherwise. Without any --allow, every
+// address not denied is allowed
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    // Whether `ip` is on this network: its first `prefix` bits are the same
+    // as the network's. IPv4 addresses are never on IPv6 networks, nor the
+    // other way around
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // An IPv4 client reaching an IPv6 socket shows up as ::ffff:a.b.c.d,
+        // which is still the IPv4 address as far as we are concerned
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                same_prefix(u32::from(network).into(), u32::from(ip).into(), 32, self.prefix)
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                same_prefix(u128::from(network), u128::from(ip), 128, self.prefix)
+            },
+            _ => false,
+        }
+    }
+}
+
+// Whether the first `prefix` of the `bits` low bits of a and b are equal.
+// A shift by the full width of the type is not allowed, hence the special
+// case for a prefix of 0, which any two addresses share
+fn same_prefix(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    prefix == 0 || (a ^ b) >> (bits - prefix) == 0
+}
+
+// Implementing FromStr is what lets us write "10.0.0.0/8".parse::<Cidr>()
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let invalid = || format!("Invalid network: {}", s);
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse() {
+                Ok(prefix) if prefix <= bits => prefix,
+                _ => return Err(invalid()),
+            },
+            None => bits,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessControl {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks_and_bare_addresses() {
+        assert_eq!(cidr("10.0.0.0/8"), Cidr { network: ip("10.0.0.0"), prefix: 8 });
+        assert_eq!(cidr("10.0.0.13"), Cidr { network: ip("10.0.0.13"), prefix: 32 });
+        assert_eq!(cidr("fd00::/8"), Cidr { network: ip("fd00::"), prefix: 8 });
+        assert_eq!(cidr("::1"), Cidr { network: ip("::1"), prefix: 128 });
+        for bad in &["10.0.0.0/33", "10.0.0.0/", "10.0.0/8", "::/129", "localhost"] {
+            assert_eq!(bad.parse::<Cidr>(), Err(format!("Invalid network: {}", bad)));
+        }
+    }
+
+    #[test]
+    fn matches_the_prefix_bits_only() {
+        let network = cidr("192.168.1.0/24");
+        assert!(network.contains(ip("192.168.1.0")));
+        assert!(network.contains(ip("192.168.1.255")));
+        assert!(!network.contains(ip("192.168.2.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(cidr("fd00::/8").contains(ip("fdab::1")));
+        assert!(!cidr("fd00::/8").contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn keeps_the_address_families_apart_except_for_mapped_ipv4() {
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(!cidr("::/0").contains(ip("127.0.0.1")));
+        assert!(cidr("127.0.0.0/8").contains(ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn denies_before_it_allows() {
+        let everybody = AccessControl::default();
+        assert!(everybody.allows(ip("203.0.113.7")));
+
+        let access = AccessControl {
+            allow: vec![cidr("10.0.0.0/8")],
+            deny: vec![cidr("10.0.0.13")],
+        };
+        assert!(access.allows(ip("10.1.2.3")));
+        assert!(!access.allows(ip("10.0.0.13")));
+        assert!(!access.allows(ip("192.168.1.1")));
+
+        let access = AccessControl { allow: vec![], deny: vec![cidr("10.0.0.13")] };
+        assert!(access.allows(ip("192.168.1.1")));
+        assert!(!access.allows(ip("10.0.0.13")));
+    }
+}
@@ -0,0 +1,268 @@
+// One line for every request the server answers: who asked for what, how it
+// went and how long it took. The line comes in three flavours, picked with
+// --log-format:
+// - text, meant for humans:
+//     2023-11-14T22:13:20.123Z 127.0.0.1 GET /health 200 49 0ms 6553f100-00000007
+// - combined, the "combined log format" of Apache and nginx, which plenty of
+//   log analysers read. The timestamp is RFC 3339 rather than the usual
+//   [14/Nov/2023:22:13:20 +0000], and the two dashes stand for fields we do
+//   not know (the identd user and the authenticated user):
+//     127.0.0.1 - - [2023-11-14T22:13:20.123Z] "GET /health HTTP/1.1" 200 49
+//     "-" "curl/8.4.0" "6553f100-00000007"
+//   with the request ID added at the end, the way nginx users add fields.
+// - json, one JSON object per line ("JSON lines"), meant for log pipelines.
+//   ts is in seconds since the Unix epoch, which is what those expect:
+//     {"ts":1700000000.123,"ip":"127.0.0.1","method":"GET","path":"/health",
+//      "status":200,"bytes":49,"dur_ms":0,"request_id":"6553f100-00000007"}
+// The request ID is the X-Request-Id of the response, which lets a line be
+// matched up with what the client saw, or with what other servers logged
+// for the same request.
+//
+// The lines go to stdout, or to the file given with --access-log
+use std::fs::OpenOptions;
+use std::io::{self, LineWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use date::rfc3339;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Combined,
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_name(name: &str) -> Result<LogFormat, String> {
+        match name {
+            "text" => Ok(LogFormat::Text),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {}", name)),
+        }
+    }
+}
+
+// Everything we record about a request. The lifetime 'a says that the
+// entry borrows the method and path from somewhere else (the request) and
+// cannot outlive them
+pub struct Entry<'a> {
+    pub time: SystemTime,
+    pub ip: IpAddr,
+    pub method: &'a str,
+    pub path: &'a str,
+    // E.g. "HTTP/1.1"
+    pub version: &'a str,
+    pub status: u16,
+    // The size of the body we sent back
+    pub bytes: usize,
+    pub duration: Duration,
+    // The page which linked to the one requested, if the client said
+    pub referer: Option<&'a str>,
+    // The client software, if it said
+    pub user_agent: Option<&'a str>,
+    pub request_id: &'a str,
+}
+
+impl<'a> Entry<'a> {
+    // Formats the entry as a single line, without the trailing newline
+    pub fn format(&self, format: LogFormat) -> String {
+        let dur_ms = self.duration.as_millis();
+
+        match format {
+            LogFormat::Text => format!("{} {} {} {} {} {} {}ms {}",
+                                       rfc3339(self.time), self.ip, self.method,
+                                       self.path, self.status, self.bytes, dur_ms,
+                                       self.request_id),
+            // The request line, the referer and the user agent come straight
+            // from the client and are quoted, so any quotes in them have to
+            // be escaped for the line to still be readable
+            LogFormat::Combined => format!(
+                "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" \"{}\"",
+                self.ip, rfc3339(self.time), escape(self.method), escape(self.path),
+                escape(self.version), self.status, self.bytes,
+                escape(self.referer.unwrap_or("-")),
+                escape(self.user_agent.unwrap_or("-")), escape(self.request_id)),
+            LogFormat::Json => {
+                // Seconds since the Unix epoch, with millisecond precision. A
+                // clock set before 1970 is not worth crashing over, so it
+                // reads as 0
+                let ts = self.time.duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs_f64())
+                    .unwrap_or(0.0);
+                format!("{{\"ts\":{:.3},\"ip\":{},\"method\":{},\"path\":{},\
+                         \"status\":{},\"bytes\":{},\"dur_ms\":{},\"request_id\":{}}}",
+                        ts, json_string(&self.ip.to_string()), json_string(self.method),
+                        json_string(self.path), self.status, self.bytes, dur_ms,
+                        json_string(self.request_id))
+            },
+        }
+    }
+}
+
+// Where the lines are written. Every worker thread logs through the same
+// AccessLog, so the output sits behind a Mutex: a line is always written in
+// one go, never interleaved with somebody else's.
+//
+// Box<dyn Write + Send> can hold anything we can write to and hand over to
+// another thread, here either stdout or a file
+pub struct AccessLog {
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn stdout() -> AccessLog {
+        AccessLog {
+            output: Mutex::new(Box::new(io::stdout())),
+        }
+    }
+
+    // Lines are added to the end of the file, which is created if needed.
+    // A LineWriter writes its buffer out at the end of every line, so the
+    // file is up to date even if the server is killed
+    pub fn open(path: &Path) -> io::Result<AccessLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog {
+            output: Mutex::new(Box::new(LineWriter::new(file))),
+        })
+    }
+
+    // Writes the entry out as a line. Failing to log a request is no reason
+    // to fail the request itself, so errors are only reported on stderr. A
+    // poisoned lock still holds a usable writer
+    pub fn write(&self, entry: &Entry, format: LogFormat) {
+        let mut output = self.output.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = writeln!(output, "{}", entry.format(format)) {
+            eprintln!("Cannot write to the access log: {}", e);
+        }
+    }
+}
+
+// Escapes the quotes and backslashes in a field of the combined format, as
+// well as control characters, which could otherwise be used to forge extra
+// log lines
+fn escape(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            c if c.is_control() => output.push_str(&format!("\\x{:02x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+// Turns a str into a JSON string literal, quotes included. The method and
+// path come straight from the client, so they may contain quotes, backslashes
+// or control characters which would otherwise break the JSON
+fn json_string(value: &str) -> String {
+    let mut output = String::with_capacity(value.len() + 2);
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            // Any other control character is written as a \u escape of
+            // its code, as 4 hex digits
+            c if (c as u32) < 0x20 => {
+                output.push_str(&format!("\\u{:04x}", c as u32))
+            },
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use test_support::TempDir;
+
+    // The entry in the examples at the top of the file
+    fn entry<'a>(method: &'a str, path: &'a str) -> Entry<'a> {
+        Entry {
+            time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            ip: "127.0.0.1".parse().unwrap(),
+            method,
+            path,
+            version: "HTTP/1.1",
+            status: 200,
+            bytes: 49,
+            duration: Duration::from_micros(1500),
+            referer: None,
+            user_agent: Some("curl/8.4.0"),
+            request_id: "6553f100-00000007",
+        }
+    }
+
+    #[test]
+    fn formats_a_json_line() {
+        assert_eq!(entry("GET", "/health").format(LogFormat::Json),
+                   r#"{"ts":1700000000.123,"ip":"127.0.0.1","method":"GET","path":"/health","#
+                   .to_owned() +
+                   r#""status":200,"bytes":49,"dur_ms":1,"request_id":"6553f100-00000007"}"#);
+    }
+
+    #[test]
+    fn escapes_what_the_client_sent_in_a_json_line() {
+        let line = entry("GET", "/a\"b\\c\nd\u{1}").format(LogFormat::Json);
+        assert!(line.contains(r#""path":"/a\"b\\c\nd\u0001""#), "{}", line);
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["path"], "/a\"b\\c\nd\u{1}");
+    }
+
+    #[test]
+    fn knows_the_log_formats_by_name() {
+        assert_eq!(LogFormat::from_name("json"), Ok(LogFormat::Json));
+        assert_eq!(LogFormat::from_name("text"), Ok(LogFormat::Text));
+        assert_eq!(LogFormat::from_name("combined"), Ok(LogFormat::Combined));
+        assert_eq!(LogFormat::from_name("xml"), Err("Unknown log format: xml".to_owned()));
+    }
+
+    #[test]
+    fn formats_a_text_line() {
+        assert_eq!(entry("GET", "/health").format(LogFormat::Text),
+                   "2023-11-14T22:13:20.123Z 127.0.0.1 GET /health 200 49 1ms 6553f100-00000007");
+    }
+
+    #[test]
+    fn formats_a_combined_line() {
+        assert_eq!(entry("GET", "/health").format(LogFormat::Combined),
+                   "127.0.0.1 - - [2023-11-14T22:13:20.123Z] \"GET /health HTTP/1.1\" 200 49 \
+                    \"-\" \"curl/8.4.0\" \"6553f100-00000007\"");
+    }
+
+    #[test]
+    fn escapes_what_the_client_sent_in_a_combined_line() {
+        let line = entry("GET", "/\" 200 0\n127.0.0.2 - - \\").format(LogFormat::Combined);
+        assert!(line.contains(r#""GET /\" 200 0\x0a127.0.0.2 - - \\ HTTP/1.1""#), "{}", line);
+        assert!(!line.contains('\n'));
+    }
+
+    #[test]
+    fn appends_lines_to_the_access_log_file() {
+        let dir = TempDir::new();
+        let path = dir.file("access.log", "earlier line\n");
+        let log = AccessLog::open(&path).unwrap();
+        log.write(&entry("GET", "/a"), LogFormat::Text);
+        log.write(&entry("POST", "/b"), LogFormat::Text);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "earlier line");
+        assert!(lines[1].contains(" GET /a 200 "));
+        assert!(lines[2].contains(" POST /b 200 "));
+    }
+}
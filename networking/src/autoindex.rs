@@ -0,0 +1,102 @@
+// The page shown for a directory without an index.html, when --autoindex is
+// given: a list of links to what is in it, with a trailing "/" on the
+// directories, like the listings of Apache and nginx.
+//
+// File names end up both in URLs and in HTML, and may contain anything
+// which means something in either, like "&", "<" or "#", so they are
+// percent-encoded for the former and escaped for the latter
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use request::percent_encode;
+
+// Lists `dir`, which is what the request for `url_path` resolved to. The
+// path is the decoded one, and ends in "/": links are relative to it.
+//
+// Hidden files, the ones starting with a ".", are left out. Entries are
+// sorted by name, ignoring case, so that "b" stays between "a" and "C"
+pub fn render(dir: &Path, url_path: &str) -> io::Result<String> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // Names which are not valid UTF-8 could not be linked to anyway
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        // .is_dir() on the path (unlike .file_type()) follows symbolic links,
+        // so a link to a directory is listed as one
+        let is_dir = entry.path().is_dir();
+        entries.push((name, is_dir));
+    }
+    entries.sort_by(|(a, _), (b, _)| a.to_lowercase().cmp(&b.to_lowercase()).then(a.cmp(b)));
+
+    let title = format!("Index of {}", escape(url_path));
+    let mut page = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                            <title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n<ul>\n",
+                           title, title);
+    if url_path != "/" {
+        page.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        page.push_str(&format!("<li><a href=\"{}{}\">{}{}</a></li>\n",
+                               percent_encode(&name), slash, escape(&name), slash));
+    }
+    page.push_str("</ul>\n</body>\n</html>\n");
+    Ok(page)
+}
+
+// Escapes the characters which have a meaning in HTML, quotes included so
+// that the result can go in an attribute too
+fn escape(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#39;"),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::TempDir;
+
+    #[test]
+    fn lists_a_directory_sorted_without_hidden_files() {
+        let dir = TempDir::new();
+        dir.file("b.txt", "");
+        dir.file("C.txt", "");
+        dir.file("a/inner.txt", "");
+        dir.file(".hidden", "");
+        let page = render(dir.path(), "/docs/").unwrap();
+        assert!(page.contains("<title>Index of /docs/</title>"));
+        let links: Vec<&str> = page.lines().filter(|line| line.starts_with("<li>")).collect();
+        assert_eq!(links, vec!["<li><a href=\"../\">../</a></li>",
+                               "<li><a href=\"a/\">a/</a></li>",
+                               "<li><a href=\"b.txt\">b.txt</a></li>",
+                               "<li><a href=\"C.txt\">C.txt</a></li>"]);
+    }
+
+    #[test]
+    fn escapes_names_in_links_and_text() {
+        let dir = TempDir::new();
+        dir.file("R&D <1> #2.txt", "");
+        let page = render(dir.path(), "/").unwrap();
+        assert!(!page.contains("../"));
+        assert!(page.contains("<li><a href=\"R%26D%20%3C1%3E%20%232.txt\">\
+                               R&amp;D &lt;1&gt; #2.txt</a></li>"), "{}", page);
+        assert_eq!(escape("\"it's\""), "&quot;it&#39;s&quot;");
+    }
+}
@@ -0,0 +1,115 @@
+// Scratch buffers which are handed back once used, for the next request to
+// reuse, rather than every request allocating one of its own and freeing it
+// again. Under load that is a lot of trips to the allocator, and each buffer
+// would start out small and grow, reallocating, as the request arrives; a
+// reused one already has the room.
+//
+// Buffers are taken with .take(), and go back to the pool by themselves when
+// the Buffer is dropped, emptied but keeping their capacity
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard};
+
+pub struct BufferPool {
+    // The buffers nobody is using. A Mutex because every worker thread
+    // takes from and gives back to the same pool
+    buffers: Mutex<Vec<Vec<u8>>>,
+    // The most buffers the pool keeps; any more which are given back, after
+    // a burst of requests, are freed
+    max_buffers: usize,
+    // Buffers which grew larger than this are freed too, rather than
+    // keeping the memory for good
+    max_capacity: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize, max_capacity: usize) -> BufferPool {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            max_buffers,
+            max_capacity,
+        }
+    }
+
+    // A buffer from the pool, or a new one if it is empty
+    pub fn take(&self) -> Buffer<'_> {
+        let buffer = self.lock().pop().unwrap_or_default();
+        Buffer { pool: self, buffer }
+    }
+
+    // A panic while the lock was held cannot have left the buffers in a
+    // broken state, so the lock is taken anyway
+    fn lock(&self) -> MutexGuard<'_, Vec<Vec<u8>>> {
+        self.buffers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// A buffer taken from a pool. Deref lets it be used as the Vec<u8> inside
+pub struct Buffer<'a> {
+    pool: &'a BufferPool,
+    buffer: Vec<u8>,
+}
+
+impl<'a> Deref for Buffer<'a> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl<'a> DerefMut for Buffer<'a> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+// Whatever the buffer held is cleared, so that nothing of one request can
+// turn up in another
+impl<'a> Drop for Buffer<'a> {
+    fn drop(&mut self) {
+        if self.buffer.capacity() > self.pool.max_capacity {
+            return;
+        }
+        let mut buffer = mem::take(&mut self.buffer);
+        buffer.clear();
+        let mut buffers = self.pool.lock();
+        if buffers.len() < self.pool.max_buffers {
+            buffers.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn hands_back_a_used_buffer_empty_but_with_its_room() {
+        let pool = BufferPool::new(4, 1024);
+        {
+            let mut buffer = pool.take();
+            buffer.extend_from_slice(b"secret");
+            buffer.reserve(100);
+        }
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 106);
+        // Nothing is left in the pool while it is taken
+        assert_eq!(pool.take().capacity(), 0);
+    }
+
+    #[test]
+    fn frees_buffers_which_grew_too_large() {
+        let pool = BufferPool::new(4, 1024);
+        pool.take().reserve(2048);
+        assert!(pool.lock().is_empty());
+    }
+
+    #[test]
+    fn keeps_at_most_max_buffers() {
+        let pool = BufferPool::new(2, 1024);
+        let buffers: Vec<Buffer> = (0..3).map(|_| pool.take()).collect();
+        drop(buffers);
+        assert_eq!(pool.lock().len(), 2);
+    }
+}
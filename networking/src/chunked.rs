@@ -0,0 +1,150 @@
+// Request bodies sent with "Transfer-Encoding: chunked" rather than a
+// Content-Length, the way a client sends a body it does not know the length
+// of up front. The body comes in chunks, each one its length in hex on a
+// line of its own, then the bytes and a "\r\n"; a chunk of length 0 ends it:
+//   5\r\n
+//   hello\r\n
+//   6\r\n
+//    world\r\n
+//   0\r\n
+//   \r\n
+// The size line may carry extensions after a ";", which nobody uses and we
+// ignore, and the last chunk may be followed by "trailer" headers, which we
+// skip (RFC 7230, section 4.1)
+use std::io::{self, BufRead, Read};
+
+use error::ServerError;
+
+// Size lines and trailers are short; a client sending endless ones is up to
+// no good
+const MAX_LINE_BYTES: u64 = 1024;
+
+// Reads a whole chunked body, refusing with TooLarge a body which grows past
+// `limit` bytes. A size which is not a hex number, or a chunk which is not
+// followed by "\r\n", makes a bad request, and so does a body which ends, or
+// stalls until the read timeout, before its last chunk
+pub fn read_chunked<R: BufRead>(reader: &mut R, limit: u64) -> Result<Vec<u8>, ServerError> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        // Extensions, if any, come after a ";"
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = if !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            u64::from_str_radix(size, 16).ok()
+        } else {
+            None
+        };
+        let size = size.ok_or_else(|| {
+            ServerError::BadRequest(format!("Invalid chunk size: {}", line))
+        })?;
+
+        if size == 0 {
+            break;
+        }
+        // Compared this way round, as a size near u64::MAX would overflow
+        // the sum
+        if size > limit.saturating_sub(body.len() as u64) {
+            return Err(ServerError::TooLarge);
+        }
+
+        let start = body.len();
+        reader.take(size).read_to_end(&mut body).map_err(cut_short)?;
+        if ((body.len() - start) as u64) < size {
+            return Err(cut_short(io::ErrorKind::UnexpectedEof.into()));
+        }
+        if !read_line(reader)?.is_empty() {
+            return Err(ServerError::BadRequest(
+                "Chunk is not followed by CRLF".to_owned()));
+        }
+    }
+
+    // Trailers, up to the empty line which ends the body
+    while !read_line(reader)?.is_empty() {}
+    Ok(body)
+}
+
+// Reads a line, without its "\r\n" (or lone "\n")
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, ServerError> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_BYTES).read_until(b'\n', &mut line).map_err(cut_short)?;
+    if line.len() as u64 == MAX_LINE_BYTES && !line.ends_with(b"\n") {
+        return Err(ServerError::BadRequest("Chunk size line is too long".to_owned()));
+    }
+    if !line.ends_with(b"\n") {
+        return Err(cut_short(io::ErrorKind::UnexpectedEof.into()));
+    }
+    let line = String::from_utf8_lossy(&line);
+    Ok(line.trim_end_matches('\n').trim_end_matches('\r').to_owned())
+}
+
+// The body ending early, whether because the client closed the connection or
+// went quiet, is the client's fault. Anything else is a real I/O error
+fn cut_short(e: io::Error) -> ServerError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            ServerError::BadRequest("Chunked body ended before its last chunk".to_owned())
+        },
+        _ => ServerError::Io(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(body: &str, limit: u64) -> Result<Vec<u8>, ServerError> {
+        read_chunked(&mut body.as_bytes(), limit)
+    }
+
+    #[test]
+    fn joins_the_chunks() {
+        let body = read("5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n", 1024).unwrap();
+        assert_eq!(body, b"hello world");
+    }
+
+    #[test]
+    fn skips_trailers() {
+        let body = read("3\r\nabc\r\n0\r\nX-Trailer: 1\r\n\r\n", 1024).unwrap();
+        assert_eq!(body, b"abc");
+    }
+
+    #[test]
+    fn refuses_a_body_past_the_limit() {
+        match read("5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n", 8) {
+            Err(ServerError::TooLarge) => {},
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_a_huge_chunk_size_without_overflowing() {
+        match read("1\r\na\r\nffffffffffffffff\r\n", 1024) {
+            Err(ServerError::TooLarge) => {},
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_a_size_which_is_not_hex() {
+        match read("zz\r\n", 1024) {
+            Err(ServerError::BadRequest(_)) => {},
+            other => panic!("expected BadRequest, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_a_chunk_without_its_crlf() {
+        match read("3\r\nabcd\r\n0\r\n\r\n", 1024) {
+            Err(ServerError::BadRequest(_)) => {},
+            other => panic!("expected BadRequest, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_a_body_cut_short() {
+        match read("5\r\nhel", 1024) {
+            Err(ServerError::BadRequest(_)) => {},
+            other => panic!("expected BadRequest, got {:?}", other),
+        }
+    }
+}
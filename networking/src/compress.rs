@@ -0,0 +1,162 @@
+// gzip compression of response bodies. Text shrinks a lot when compressed,
+// HTML and JSON often to a fifth of their size, and clients which can
+// unpack it say so in every request:
+//   Accept-Encoding: gzip, deflate, br
+// The compressed body is then sent with
+//   Content-Encoding: gzip
+// and a Content-Length which, as always, .head() works out from the body.
+//
+// Images, videos and archives are compressed already, and would only get a
+// little larger, so only text-like types are touched. So are small bodies,
+// which gain little and cost time.
+//
+// The compression itself is done by the flate2 crate
+use std::io::Write;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use request::Request;
+use response::Response;
+
+// Bodies smaller than this are sent as they are
+const MIN_SIZE: usize = 1024;
+
+// Compresses the body of the response if the client accepts gzip and the
+// body is worth it, and leaves the response alone otherwise.
+//
+// Responses to range requests are left alone too, whether they are a 206 or
+// not: ranges are of the uncompressed bytes, and compressing a piece would
+// make a mess of it. A client asking for a range and gzip both gets the
+// range, uncompressed
+pub fn gzip(request: &Request, response: Response) -> Response {
+    let worth_it = accepts_gzip(request)
+        && request.header("Range").is_none()
+        && response.body_bytes().len() >= MIN_SIZE
+        && response.get_header("Content-Encoding").is_none()
+        && response.status() != 206
+        && response.get_header("Content-Type").map(is_compressible).unwrap_or(false);
+    if !worth_it {
+        return response;
+    }
+
+    // Writing into a Vec in memory cannot fail, so neither can this
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = encoder.write_all(response.body_bytes())
+        .and_then(|_| encoder.finish());
+    let compressed = match compressed {
+        Ok(compressed) => compressed,
+        Err(_) => return response,
+    };
+
+    // Caches have to know that the body depends on the Accept-Encoding of
+    // the request, besides whatever it depended on already
+    let vary = match response.get_header("Vary") {
+        Some(vary) => format!("{}, Accept-Encoding", vary),
+        None => "Accept-Encoding".to_owned(),
+    };
+    // The compressed body is not byte for byte the same as the file, so an
+    // ETag naming the file can only be a weak one from now on
+    let etag = response.get_header("ETag")
+        .map(|etag| if etag.starts_with("W/") { etag.to_owned() } else { format!("W/{}", etag) });
+
+    let response = response.header("Content-Encoding", "gzip")
+        .header("Vary", &vary)
+        .body(compressed);
+    match etag {
+        Some(etag) => response.header("ETag", &etag),
+        None => response,
+    }
+}
+
+// Whether the Accept-Encoding header of the request lists gzip, or "*", as
+// in "gzip, deflate". Either may come with a "q" weight, e.g. "gzip;q=0.5",
+// and a weight of 0 means "anything but this"
+pub fn accepts_gzip(request: &Request) -> bool {
+    let accept_encoding = match request.header("Accept-Encoding") {
+        Some(accept_encoding) => accept_encoding,
+        None => return false,
+    };
+
+    accept_encoding.split(',').any(|coding| {
+        let mut parameters = coding.split(';');
+        let name = parameters.next().unwrap_or("").trim();
+        let refused = parameters
+            .filter_map(|parameter| parameter.split_once('='))
+            .any(|(key, value)| {
+                key.trim() == "q" && value.trim().parse::<f32>().map(|q| q == 0.0).unwrap_or(false)
+            });
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+// The media types worth compressing: text of any kind, and the formats
+// which are text in all but name
+fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || ["application/json", "application/javascript", "application/xml",
+            "application/wasm"].contains(&media_type.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use test_support::RequestBuilder;
+
+    fn request(builder: RequestBuilder) -> Request {
+        Request::parse(&builder.build_bytes()).unwrap()
+    }
+
+    fn text(len: usize) -> Response {
+        Response::new(200).header("Content-Type", "text/plain").body("a".repeat(len))
+    }
+
+    #[test]
+    fn compresses_text_for_a_client_accepting_gzip() {
+        let request = request(RequestBuilder::get("/").header("Accept-Encoding", "deflate, gzip"));
+        let response = gzip(&request, text(4096).header("ETag", "\"1\""));
+        assert_eq!(response.get_header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.get_header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.get_header("ETag"), Some("W/\"1\""));
+
+        let mut unpacked = String::new();
+        GzDecoder::new(response.body_bytes()).read_to_string(&mut unpacked).unwrap();
+        assert_eq!(unpacked, "a".repeat(4096));
+    }
+
+    #[test]
+    fn leaves_small_and_binary_bodies_alone() {
+        let request = request(RequestBuilder::get("/").header("Accept-Encoding", "gzip"));
+        assert_eq!(gzip(&request, text(100)).get_header("Content-Encoding"), None);
+        let image = Response::new(200).header("Content-Type", "image/png").body(vec![0; 4096]);
+        assert_eq!(gzip(&request, image).get_header("Content-Encoding"), None);
+    }
+
+    #[test]
+    fn leaves_range_requests_alone() {
+        let request = request(RequestBuilder::get("/")
+                              .header("Accept-Encoding", "gzip")
+                              .header("Range", "bytes=0-9"));
+        assert_eq!(gzip(&request, text(4096)).get_header("Content-Encoding"), None);
+    }
+
+    #[test]
+    fn understands_accept_encoding() {
+        let accepts = |value: &str| {
+            accepts_gzip(&request(RequestBuilder::get("/").header("Accept-Encoding", value)))
+        };
+        assert!(accepts("gzip"));
+        assert!(accepts("br, GZIP;q=0.5"));
+        assert!(accepts("*"));
+        assert!(!accepts("br"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts_gzip(&request(RequestBuilder::get("/"))));
+    }
+}
@@ -0,0 +1,667 @@
+// Everything the server needs to know before it starts accepting connections
+// lives in ServerConfig. Keeping it in its own module (this file) keeps main.rs
+// focused on the networking itself; main.rs pulls it in with "mod config;"
+//
+// Values come from the command line, e.g.
+//   cargo run -- --address 0.0.0.0:8080 --backlog 1024 --config server.conf
+//   cargo run -- --root ./public --autoindex --allow-put
+//   cargo run -- --trust-proxy --log-format json --threads 8
+//   cargo run -- --read-timeout 30 --idle-timeout 60 --no-healthz --max-connections 512
+//   cargo run -- --threads 16 --max-in-flight 4 --slot-timeout 10
+//   cargo run -- --log-format combined --access-log access.log
+//   cargo run -- --cors-origin https://example.com --cors-credentials
+//   cargo run -- --tls-cert cert.pem --tls-key key.pem
+//   cargo run -- --rate-limit 10 --rate-burst 20
+//   cargo run -- --allow 10.0.0.0/8 --deny 10.0.0.13
+//   cargo run -- --cache-ttl 60
+//   cargo run -- --basic-auth alice:secret --auth-realm "Staging"
+// and anything which is not specified keeps its default value.
+//
+// The settings which can change while the server is running live in the
+// configuration file instead, one "key = value" pair per line:
+//   # Lines starting with a # are comments
+//   doc_root = ./public
+//   log_level = info
+//   debug = false
+//   upload_dir = ./uploads
+//   max_body_bytes = 10485760
+//   error_page = 404 ./errors/404.html
+//   virtual_host = example.com ./sites/example
+// The file is read again when the server receives a SIGHUP
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use access_control::AccessControl;
+use access_log::LogFormat;
+use cors::CorsConfig;
+use error_pages::ErrorPages;
+
+// How much the server prints while it works. Deriving PartialOrd lets us
+// compare levels with < and >: variants declared later compare as greater, so
+// "log_level >= LogLevel::Info" reads as "at least as chatty as Info"
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    // Only problems
+    Error,
+    // One line for every connection
+    Info,
+    // Full dumps of requests and responses
+    Debug,
+}
+
+impl LogLevel {
+    fn from_name(name: &str) -> Result<LogLevel, String> {
+        match name {
+            "error" => Ok(LogLevel::Error),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("Unknown log level: {}", name)),
+        }
+    }
+}
+
+// A struct used to hold the configuration. "pub" makes the type and its fields
+// visible outside of this module, i.e. from main.rs
+//
+// Deriving Clone allows every reload to start from a copy of the command line
+// configuration and only replace what the file sets
+#[derive(Clone)]
+pub struct ServerConfig {
+    // The address to bind the listening socket to, e.g. "127.0.0.1:8080"
+    pub address: String,
+    // How many connections the OS may queue up for us while we are busy and
+    // have not accepted them yet. Once the queue is full, new connections
+    // are refused (or dropped, depending on the platform)
+    pub backlog: u32,
+    // How many connections are handled at the same time, each one on its own
+    // worker thread
+    pub threads: usize,
+    // How many connections may be open at the same time, those waiting for
+    // a free worker thread included. Any more are turned away with a 503
+    // Service Unavailable, rather than queueing up without end. No limit
+    // without --max-connections
+    pub max_connections: Option<usize>,
+    // How many connections are handled at the same time, if fewer than there
+    // are threads. One past the limit waits for another to finish, up to
+    // slot_timeout, and is only then turned away with a 503; see
+    // semaphore.rs. No limit without --max-in-flight; --slot-timeout is in
+    // seconds
+    pub max_in_flight: Option<usize>,
+    pub slot_timeout: Duration,
+    // How long a read from a connection may wait for the client while it is
+    // sending a request, the first one included. A client which takes
+    // longer is disconnected, so that a slow or stalled one cannot keep a
+    // worker busy for good. --read-timeout is in seconds
+    pub read_timeout: Duration,
+    // How long a kept-alive connection may sit idle, waiting for the client
+    // to start its next request, before it is closed. Told to the client in
+    // the Keep-Alive header. --idle-timeout is in seconds
+    pub idle_timeout: Duration,
+    // The configuration file given with --config, if any
+    pub config_file: Option<PathBuf>,
+    // The directory the served files are looked up in, set with --root or in
+    // the configuration file. The file has the last word, as it is read after
+    // the command line
+    pub doc_root: PathBuf,
+    // Document roots of their own for some of the names the server goes by,
+    // so that one server can host several sites. The Host header of the
+    // request says which one it is for; see doc_root_for(). Set with one
+    // virtual_host line per name, lowercase and without a port
+    pub virtual_hosts: HashMap<String, PathBuf>,
+    // Whether a directory without an index.html is shown as a list of what
+    // is in it, rather than as not found
+    pub autoindex: bool,
+    // Whether clients may PUT files into the document root, creating them or
+    // replacing what is there. Off unless --allow-put is given: anybody who
+    // can reach the server could change the site otherwise
+    pub allow_put: bool,
+    // Whether GET /healthz is answered by the server itself. --no-healthz
+    // leaves it to the document root like any other path
+    pub healthz: bool,
+    pub log_level: LogLevel,
+    // When set, error responses carry details meant for developers, such as
+    // the message of a panic. Not something to show to the whole internet
+    pub debug: bool,
+    // Where PUT /upload/<name> stores the files it receives
+    pub upload_dir: PathBuf,
+    // The largest request body we accept. Bodies are kept in memory, so
+    // without a limit a single request could use up all of it; larger
+    // ones are refused with a 413. Set in the file, or with --max-body-bytes
+    pub max_body_bytes: u64,
+    // The longest request line ("GET /path HTTP/1.1") we read. A longer one
+    // is answered with a 414 URI Too Long, the path being what makes a
+    // request line long. It counts towards the limit on the whole head too,
+    // so anything above 8 KiB makes no difference. Set with --max-request-line
+    pub max_request_line: u64,
+    // Pages to send for error responses instead of the built-in messages,
+    // see error_pages.rs. Set with one error_page line per status code
+    pub error_pages: ErrorPages,
+    // Whether we sit behind a reverse proxy whose X-Forwarded-For header can
+    // be believed. Without a proxy, anyone can send that header and claim to
+    // be whoever they like
+    pub trust_proxy: bool,
+    // How the access log lines are written out
+    pub log_format: LogFormat,
+    // The file the access log is appended to; without one it goes to stdout
+    pub access_log: Option<PathBuf>,
+    // The certificate and private key to serve HTTPS with, see tls.rs. It
+    // takes both; without them the server speaks plain HTTP
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // Which other sites' pages may use our responses, see cors.rs.
+    // --cors-origin can be given once for every origin to allow;
+    // --cors-methods and --cors-headers take comma-separated lists
+    pub cors: CorsConfig,
+    // How many requests a second every client may make, see rate_limit.rs;
+    // no limit without --rate-limit. --rate-burst says how many may come in
+    // a row, and defaults to a second's worth
+    pub rate_limit: Option<f64>,
+    pub rate_burst: Option<u32>,
+    // Which client addresses are let in, see access_control.rs. --allow
+    // and --deny can each be given once for every network
+    pub access: AccessControl,
+    // How long files from the document root are kept in memory, see
+    // file_cache.rs; they are read from the disk every time without
+    // --cache-ttl, which is in seconds
+    pub cache_ttl: Option<Duration>,
+    // The users let in when a password is asked for, see basic_auth.rs;
+    // nobody is asked without any. --basic-auth USER:PASSWORD can be given
+    // once for every user
+    pub basic_auth: Vec<(String, String)>,
+    // What the password is for, as browsers show it when they ask
+    pub auth_realm: String,
+}
+
+// Implementing the Default trait is the idiomatic way of giving a type a set
+// of default values; ServerConfig::default() will return them
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            address: "127.0.0.1:8080".to_owned(),
+            // 128 is the value Rust's own TcpListener::bind() picks
+            backlog: 128,
+            threads: 4,
+            max_connections: None,
+            max_in_flight: None,
+            slot_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(5),
+            config_file: None,
+            doc_root: PathBuf::from("."),
+            virtual_hosts: HashMap::new(),
+            autoindex: false,
+            allow_put: false,
+            healthz: true,
+            log_level: LogLevel::Debug,
+            debug: false,
+            upload_dir: PathBuf::from("uploads"),
+            max_body_bytes: 10 * 1024 * 1024,
+            max_request_line: 4 * 1024,
+            error_pages: ErrorPages::default(),
+            trust_proxy: false,
+            log_format: LogFormat::Text,
+            access_log: None,
+            tls_cert: None,
+            tls_key: None,
+            cors: CorsConfig::default(),
+            rate_limit: None,
+            rate_burst: None,
+            access: AccessControl::default(),
+            cache_ttl: None,
+            basic_auth: Vec::new(),
+            auth_realm: "networking".to_owned(),
+        }
+    }
+}
+
+// The items of a comma-separated list, without the spaces around them
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+impl ServerConfig {
+    // Builds a configuration out of command line arguments. The function is
+    // generic over any iterator of Strings so that it can be fed both
+    // std::env::args() and a hand-written list of arguments.
+    //
+    // The configuration file is not read here: with_file() does that, on a
+    // copy, so that this configuration can be kept around for reloads.
+    //
+    // On failure the error is a String describing what was wrong, ready to be
+    // shown to the user
+    pub fn from_args<I>(args: I) -> Result<ServerConfig, String>
+        where I: IntoIterator<Item = String> {
+        let mut config = ServerConfig::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            // Flags are options without a value: their presence is all that
+            // matters
+            if arg == "--trust-proxy" {
+                config.trust_proxy = true;
+                continue;
+            }
+            if arg == "--autoindex" {
+                config.autoindex = true;
+                continue;
+            }
+            if arg == "--allow-put" {
+                config.allow_put = true;
+                continue;
+            }
+            if arg == "--no-healthz" {
+                config.healthz = false;
+                continue;
+            }
+            if arg == "--cors-credentials" {
+                config.cors.allow_credentials = true;
+                continue;
+            }
+
+            // Every other option is followed by its value, so we grab the next
+            // argument straight away. .ok_or_else() turns the Option into a
+            // Result, and "?" returns early from the function with the error
+            // if it is the Err variant
+            let value = args.next()
+                .ok_or_else(|| format!("Missing value for {}", arg))?;
+
+            match arg.as_str() {
+                "--address" => config.address = value,
+                "--backlog" => {
+                    config.backlog = value.parse()
+                        .map_err(|_| format!("Invalid backlog: {}", value))?;
+                },
+                "--threads" => {
+                    config.threads = match value.parse() {
+                        Ok(threads) if threads > 0 => threads,
+                        _ => return Err(format!("Invalid number of threads: {}",
+                                                value)),
+                    };
+                },
+                "--max-connections" => {
+                    config.max_connections = match value.parse() {
+                        Ok(max) if max > 0 => Some(max),
+                        _ => return Err(format!("Invalid number of connections: {}", value)),
+                    };
+                },
+                "--max-in-flight" => {
+                    config.max_in_flight = match value.parse() {
+                        Ok(max) if max > 0 => Some(max),
+                        _ => return Err(format!("Invalid number of connections: {}", value)),
+                    };
+                },
+                "--slot-timeout" => {
+                    config.slot_timeout = value.parse().map(Duration::from_secs)
+                        .map_err(|_| format!("Invalid slot timeout: {}", value))?;
+                },
+                // A timeout of 0 would mean "no timeout" to
+                // .set_read_timeout(), which refuses it outright
+                "--read-timeout" => {
+                    config.read_timeout = match value.parse() {
+                        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                        _ => return Err(format!("Invalid read timeout: {}", value)),
+                    };
+                },
+                "--idle-timeout" => {
+                    config.idle_timeout = match value.parse() {
+                        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                        _ => return Err(format!("Invalid idle timeout: {}", value)),
+                    };
+                },
+                "--cache-ttl" => {
+                    config.cache_ttl = match value.parse() {
+                        Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+                        _ => return Err(format!("Invalid cache TTL: {}", value)),
+                    };
+                },
+                "--max-body-bytes" => {
+                    config.max_body_bytes = value.parse()
+                        .map_err(|_| format!("Invalid size: {}", value))?;
+                },
+                // Shorter than "GET / HTTP/1.1" would leave no request we
+                // could read
+                "--max-request-line" => {
+                    config.max_request_line = match value.parse() {
+                        Ok(max) if max >= 16 => max,
+                        _ => return Err(format!("Invalid request line length: {}", value)),
+                    };
+                },
+                "--rate-limit" => {
+                    config.rate_limit = match value.parse::<f64>() {
+                        Ok(rate) if rate > 0.0 && rate.is_finite() => Some(rate),
+                        _ => return Err(format!("Invalid rate limit: {}", value)),
+                    };
+                },
+                "--rate-burst" => {
+                    config.rate_burst = match value.parse() {
+                        Ok(burst) if burst > 0 => Some(burst),
+                        _ => return Err(format!("Invalid burst: {}", value)),
+                    };
+                },
+                "--allow" => config.access.allow.push(value.parse()?),
+                "--deny" => config.access.deny.push(value.parse()?),
+                // The user name cannot have a ":" in it, the password can
+                "--basic-auth" => {
+                    let (user, password) = match value.split_once(':') {
+                        Some((user, password)) if !user.is_empty() => (user, password),
+                        _ => return Err(format!("Expected USER:PASSWORD, got: {}", value)),
+                    };
+                    config.basic_auth.push((user.to_owned(), password.to_owned()));
+                },
+                "--auth-realm" => config.auth_realm = value,
+                "--root" => config.doc_root = PathBuf::from(value),
+                "--config" => config.config_file = Some(PathBuf::from(value)),
+                "--log-format" => config.log_format = LogFormat::from_name(&value)?,
+                "--access-log" => config.access_log = Some(PathBuf::from(value)),
+                "--tls-cert" => config.tls_cert = Some(PathBuf::from(value)),
+                "--tls-key" => config.tls_key = Some(PathBuf::from(value)),
+                "--cors-origin" => config.cors.allowed_origins.push(value),
+                // .collect() can build a Result<Vec<_>, _> out of Results:
+                // the first error stops it
+                "--cors-methods" => {
+                    config.cors.allowed_methods = list(&value)
+                        .map(|method| method.to_ascii_uppercase().parse()
+                             .map_err(|_| format!("Unknown method: {}", method)))
+                        .collect::<Result<_, _>>()?;
+                },
+                "--cors-headers" => {
+                    config.cors.allowed_headers = list(&value).map(str::to_owned).collect();
+                },
+                _ => return Err(format!("Unknown option: {}", arg)),
+            }
+        }
+
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err("--tls-cert and --tls-key go together".to_owned());
+        }
+
+        if config.rate_burst.is_some() && config.rate_limit.is_none() {
+            return Err("--rate-burst needs --rate-limit".to_owned());
+        }
+
+        Ok(config)
+    }
+
+    // Returns a copy of the configuration with the file applied on top of it,
+    // if there is one. Called on the configuration from_args() returned, both
+    // at startup and on every reload, so that a setting removed from the file
+    // goes back to what the command line or the default says rather than
+    // staying at what the file used to say.
+    //
+    // If the file cannot be read or contains mistakes the error is returned
+    // and the caller can simply keep using the configuration it has
+    pub fn with_file(&self) -> Result<ServerConfig, String> {
+        let mut config = self.clone();
+        config.load_file()?;
+        Ok(config)
+    }
+
+    // The document root for a request with the given Host header: that of
+    // the virtual host of that name, or doc_root for any other name, or no
+    // name at all. The header may come with a port, as in "example.com:8080",
+    // and an IPv6 address is in brackets, as in "[::1]:8080"
+    pub fn doc_root_for(&self, host: Option<&str>) -> &Path {
+        let name = host.map(|host| {
+            let host = host.trim();
+            let name = if host.starts_with('[') {
+                host.split_once(']').map(|(address, _)| &host[..=address.len()]).unwrap_or(host)
+            } else {
+                host.rsplit_once(':').map(|(name, _)| name).unwrap_or(host)
+            };
+            // "example.com." is the same name as "example.com"
+            name.trim_end_matches('.').to_ascii_lowercase()
+        });
+        name.and_then(|name| self.virtual_hosts.get(&name))
+            .unwrap_or(&self.doc_root)
+    }
+
+    // Applies the settings in the configuration file, if there is one
+    fn load_file(&mut self) -> Result<(), String> {
+        // .clone() so that we do not keep borrowing self while modifying it
+        let path = match self.config_file.clone() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+
+        // .enumerate() pairs every line with its index, which we use to point
+        // at the offending line in error messages
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |message: String| {
+                format!("{}:{}: {}", path.display(), index + 1, message)
+            };
+
+            // .splitn(2, ...) splits on the first "=" only, so values are
+            // allowed to contain one
+            let mut pair = line.splitn(2, '=');
+            let key = pair.next().unwrap_or("").trim();
+            let value = match pair.next() {
+                Some(value) => value.trim(),
+                None => return Err(error(format!("Expected key = value, got: {}",
+                                                 line))),
+            };
+
+            match key {
+                "doc_root" => self.doc_root = PathBuf::from(value),
+                "log_level" => self.log_level = LogLevel::from_name(value)
+                    .map_err(&error)?,
+                // bool implements FromStr, accepting "true" and "false"
+                "debug" => self.debug = value.parse()
+                    .map_err(|_| error(format!("Expected true or false, got: {}",
+                                               value)))?,
+                "upload_dir" => self.upload_dir = PathBuf::from(value),
+                // max_upload_bytes is what the setting used to be called,
+                // and still works
+                "max_body_bytes" | "max_upload_bytes" => self.max_body_bytes = value.parse()
+                    .map_err(|_| error(format!("Invalid size: {}", value)))?,
+                // error_page = <status> <file>. Only error statuses make
+                // sense here
+                "error_page" => {
+                    let (status, page) = value.split_once(char::is_whitespace)
+                        .ok_or_else(|| error(format!("Expected a status and a file, got: {}",
+                                                     value)))?;
+                    let status = match status.parse() {
+                        Ok(status) if (400..600).contains(&status) => status,
+                        _ => return Err(error(format!("Invalid error status: {}", status))),
+                    };
+                    self.error_pages.set(status, PathBuf::from(page.trim()));
+                },
+                // virtual_host = <name> <document root>
+                "virtual_host" => {
+                    let (name, root) = value.split_once(char::is_whitespace)
+                        .ok_or_else(|| error(format!("Expected a name and a directory, got: {}",
+                                                     value)))?;
+                    self.virtual_hosts.insert(name.trim_end_matches('.').to_ascii_lowercase(),
+                                              PathBuf::from(root.trim()));
+                },
+                _ => return Err(error(format!("Unknown setting: {}", key))),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use test_support::TempDir;
+
+    fn parse(line: &str) -> Result<ServerConfig, String> {
+        ServerConfig::from_args(line.split_whitespace().map(str::to_owned))
+    }
+
+    fn error(line: &str) -> String {
+        match parse(line) {
+            Ok(_) => panic!("'{}' was accepted", line),
+            Err(message) => message,
+        }
+    }
+
+    #[test]
+    fn takes_the_backlog_from_the_command_line() {
+        assert_eq!(parse("").unwrap().backlog, 128);
+        assert_eq!(parse("--backlog 1024").unwrap().backlog, 1024);
+        assert_eq!(error("--backlog lots"), "Invalid backlog: lots");
+        assert_eq!(error("--backlog"), "Missing value for --backlog");
+        assert_eq!(error("--bakclog 1"), "Unknown option: --bakclog");
+    }
+
+    #[test]
+    fn applies_the_file_on_top_of_the_command_line() {
+        let dir = TempDir::new();
+        let file = dir.file("server.conf", "# A comment\n\ndoc_root = ./public\n\
+                                            log_level = info\nmax_body_bytes = 1024\n");
+        let base = parse(&format!("--root ./elsewhere --config {}", file.display())).unwrap();
+        // from_args() leaves the file for with_file()
+        assert_eq!(base.doc_root, PathBuf::from("./elsewhere"));
+
+        let config = base.with_file().unwrap();
+        assert_eq!(config.doc_root, PathBuf::from("./public"));
+        assert_eq!(config.log_level, LogLevel::Info);
+        assert_eq!(config.max_body_bytes, 1024);
+    }
+
+    #[test]
+    fn reloading_forgets_what_was_removed_from_the_file() {
+        let dir = TempDir::new();
+        let file = dir.file("server.conf", "doc_root = ./public\nerror_page = 404 404.html\n\
+                                            virtual_host = Example.com. ./example\n");
+        let base = parse(&format!("--config {}", file.display())).unwrap();
+        let config = base.with_file().unwrap();
+        assert_eq!(config.doc_root_for(Some("example.com:8080")), Path::new("./example"));
+        assert_eq!(config.error_pages.get(404), Some(Path::new("404.html")));
+
+        fs::write(&file, "# Nothing left\n").unwrap();
+        let reloaded = base.with_file().unwrap();
+        assert_eq!(reloaded.doc_root, PathBuf::from("."));
+        assert_eq!(reloaded.doc_root_for(Some("example.com")), Path::new("."));
+        assert_eq!(reloaded.error_pages.get(404), None);
+    }
+
+    #[test]
+    fn points_at_the_line_with_the_mistake() {
+        let dir = TempDir::new();
+        let file = dir.file("server.conf", "debug = true\ndebug = maybe\n");
+        let base = parse(&format!("--config {}", file.display())).unwrap();
+        let message = base.with_file().err().unwrap();
+        assert_eq!(message, format!("{}:2: Expected true or false, got: maybe", file.display()));
+
+        fs::write(&file, "colour = blue\n").unwrap();
+        assert!(base.with_file().err().unwrap().ends_with(":1: Unknown setting: colour"));
+
+        fs::remove_file(&file).unwrap();
+        assert!(base.with_file().err().unwrap().starts_with("Cannot read "));
+    }
+
+    #[test]
+    fn takes_the_wait_queue_limits_from_the_command_line() {
+        let config = parse("").unwrap();
+        assert_eq!(config.max_in_flight, None);
+        assert_eq!(config.slot_timeout, Duration::from_secs(5));
+
+        let config = parse("--max-in-flight 4 --slot-timeout 10").unwrap();
+        assert_eq!(config.max_in_flight, Some(4));
+        assert_eq!(config.slot_timeout, Duration::from_secs(10));
+        assert_eq!(error("--max-in-flight 0"), "Invalid number of connections: 0");
+        assert_eq!(error("--slot-timeout soon"), "Invalid slot timeout: soon");
+    }
+
+    #[test]
+    fn takes_only_error_statuses_for_error_pages() {
+        let dir = TempDir::new();
+        let file = dir.file("server.conf", "error_page = 500   ./errors/oops.html \n");
+        let base = parse(&format!("--config {}", file.display())).unwrap();
+        assert_eq!(base.with_file().unwrap().error_pages.get(500),
+                   Some(Path::new("./errors/oops.html")));
+
+        fs::write(&file, "error_page = 200 ok.html\n").unwrap();
+        assert!(base.with_file().err().unwrap().ends_with(":1: Invalid error status: 200"));
+        fs::write(&file, "error_page = 404\n").unwrap();
+        assert!(base.with_file().err().unwrap()
+                .ends_with(":1: Expected a status and a file, got: 404"));
+    }
+
+    #[test]
+    fn takes_the_read_timeout_from_the_command_line() {
+        assert_eq!(parse("").unwrap().read_timeout, Duration::from_secs(5));
+        assert_eq!(parse("--read-timeout 30").unwrap().read_timeout, Duration::from_secs(30));
+        assert_eq!(error("--read-timeout 0"), "Invalid read timeout: 0");
+        assert_eq!(error("--read-timeout -1"), "Invalid read timeout: -1");
+    }
+
+    #[test]
+    fn takes_the_rate_limit_from_the_command_line() {
+        let config = parse("--rate-limit 2.5 --rate-burst 10").unwrap();
+        assert_eq!(config.rate_limit, Some(2.5));
+        assert_eq!(config.rate_burst, Some(10));
+        assert_eq!(error("--rate-limit 0"), "Invalid rate limit: 0");
+        assert_eq!(error("--rate-limit inf"), "Invalid rate limit: inf");
+        assert_eq!(error("--rate-limit 1 --rate-burst 0"), "Invalid burst: 0");
+        assert_eq!(error("--rate-burst 10"), "--rate-burst needs --rate-limit");
+    }
+
+    #[test]
+    fn takes_the_cache_ttl_from_the_command_line() {
+        assert_eq!(parse("").unwrap().cache_ttl, None);
+        assert_eq!(parse("--cache-ttl 60").unwrap().cache_ttl, Some(Duration::from_secs(60)));
+        assert_eq!(error("--cache-ttl 0"), "Invalid cache TTL: 0");
+    }
+
+    #[test]
+    fn picks_the_document_root_by_host_name() {
+        let mut config = ServerConfig::default();
+        config.virtual_hosts.insert("example.com".to_owned(), PathBuf::from("./example"));
+        config.virtual_hosts.insert("[::1]".to_owned(), PathBuf::from("./local"));
+
+        for host in &["example.com", "EXAMPLE.com:8080", " example.com. ", "example.com.:80"] {
+            assert_eq!(config.doc_root_for(Some(host)), Path::new("./example"), "{}", host);
+        }
+        assert_eq!(config.doc_root_for(Some("[::1]:8080")), Path::new("./local"));
+        assert_eq!(config.doc_root_for(Some("[::1]")), Path::new("./local"));
+        assert_eq!(config.doc_root_for(Some("www.example.com")), Path::new("."));
+        assert_eq!(config.doc_root_for(None), Path::new("."));
+    }
+
+    #[test]
+    fn refuses_a_virtual_host_without_a_directory() {
+        let dir = TempDir::new();
+        let file = dir.file("server.conf", "virtual_host = example.com\n");
+        let base = parse(&format!("--config {}", file.display())).unwrap();
+        assert!(base.with_file().err().unwrap()
+                .ends_with(":1: Expected a name and a directory, got: example.com"));
+    }
+
+    #[test]
+    fn takes_the_users_from_the_command_line() {
+        let config = parse("--basic-auth alice:se:cret --auth-realm Staging").unwrap();
+        assert_eq!(config.basic_auth, [("alice".to_owned(), "se:cret".to_owned())]);
+        assert_eq!(config.auth_realm, "Staging");
+        assert_eq!(error("--basic-auth alice"), "Expected USER:PASSWORD, got: alice");
+        assert_eq!(error("--basic-auth :secret"), "Expected USER:PASSWORD, got: :secret");
+    }
+
+    #[test]
+    fn takes_the_idle_timeout_from_the_command_line() {
+        assert_eq!(parse("--idle-timeout 60").unwrap().idle_timeout, Duration::from_secs(60));
+        assert_eq!(error("--idle-timeout 0"), "Invalid idle timeout: 0");
+    }
+
+    #[test]
+    fn takes_the_request_line_limit_from_the_command_line() {
+        assert_eq!(parse("").unwrap().max_request_line, 4096);
+        assert_eq!(parse("--max-request-line 16").unwrap().max_request_line, 16);
+        assert_eq!(error("--max-request-line 15"), "Invalid request line length: 15");
+    }
+}
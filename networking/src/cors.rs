@@ -0,0 +1,205 @@
+// Cross-Origin Resource Sharing (CORS). A browser running a page from
+// https://example.com refuses to let it read our responses unless they say
+// that https://example.com may:
+//   Access-Control-Allow-Origin: https://example.com
+//
+// Requests the browser considers risky, e.g. a PUT or a POST of JSON, are
+// preceded by a "preflight": an OPTIONS request asking whether the real one
+// would be allowed,
+//   OPTIONS /upload/notes.txt HTTP/1.1
+//   Origin: https://example.com
+//   Access-Control-Request-Method: PUT
+// which we answer with a 204 listing what is allowed. The browser then goes
+// ahead with the request, or not, on its own.
+//
+// CORS is set up on the command line and stays off until at least one origin
+// is allowed:
+//   cargo run -- --cors-origin https://example.com --cors-methods GET,PUT
+//
+// The router answers the preflights, as it knows which methods every path
+// has; the headers are added to the other responses by CorsConfig as a
+// middleware, see middleware.rs
+use error::ServerError;
+use middleware::{Middleware, Next};
+use request::{Method, Request};
+use response::Response;
+use router::Context;
+
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    // The origins whose pages may read our responses, as scheme://host[:port]
+    // exactly as browsers send them in the Origin header. "*" allows any
+    pub allowed_origins: Vec<String>,
+    // The methods and request headers a preflight is told are allowed
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<String>,
+    // Whether the browser may send cookies and the like along. When it does,
+    // the origin has to be named: browsers do not accept "*" for requests
+    // with credentials
+    pub allow_credentials: bool,
+}
+
+// No origin allowed, so no CORS headers at all. The methods and headers are
+// what a preflight is told once some origin is allowed: the methods we have
+// routes for other than PUT, and Content-Type, which any JSON POST needs
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec![Method::Get, Method::Head, Method::Post],
+            allowed_headers: vec!["Content-Type".to_owned()],
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsConfig {
+    // The Access-Control-Allow-Origin to answer a request from `origin` with,
+    // or None if that origin is not allowed. "*" is only sent back as it is
+    // when credentials are not allowed; otherwise the origin itself is
+    fn allow_origin<'a>(&'a self, origin: &'a str) -> Option<&'a str> {
+        let any = self.allowed_origins.iter().any(|allowed| allowed == "*");
+        if any && !self.allow_credentials {
+            Some("*")
+        } else if any || self.allowed_origins.iter().any(|allowed| allowed == origin) {
+            Some(origin)
+        } else {
+            None
+        }
+    }
+
+    // Adds the CORS headers to the response to a request which came with an
+    // Origin header, if that origin is allowed. The response then depends on
+    // the Origin of the request, which Vary tells caches about
+    pub fn apply(&self, request: &Request, response: Response) -> Response {
+        let origin = match request.header("Origin") {
+            Some(origin) => origin,
+            None => return response,
+        };
+        let allowed = match self.allow_origin(origin) {
+            Some(allowed) => allowed,
+            None => return response,
+        };
+
+        let response = response.header("Access-Control-Allow-Origin", allowed);
+        // Added to whatever Vary the response has already, e.g. the
+        // Accept-Encoding of a precompressed file
+        let response = if allowed == "*" {
+            response
+        } else {
+            let vary = match response.get_header("Vary") {
+                Some(vary) => format!("{}, Origin", vary),
+                None => "Origin".to_owned(),
+            };
+            response.header("Vary", &vary)
+        };
+        if self.allow_credentials {
+            response.header("Access-Control-Allow-Credentials", "true")
+        } else {
+            response
+        }
+    }
+
+    // Answers an OPTIONS request. For a preflight from an allowed origin the
+    // answer lists the allowed methods and headers; anybody else just gets
+    // the 204, and the browser, finding no CORS headers in it, blocks the
+    // request which was to follow. Like every other response, this one still
+    // has to go through .apply()
+    pub fn preflight(&self, request: &Request) -> Response {
+        let response = Response::new(204);
+        let is_preflight = request.header("Access-Control-Request-Method").is_some();
+        let allowed = request.header("Origin")
+            .and_then(|origin| self.allow_origin(origin))
+            .is_some();
+        if !is_preflight || !allowed {
+            return response;
+        }
+
+        let methods: Vec<&str> = self.allowed_methods.iter().map(Method::as_str).collect();
+        response
+            .header("Access-Control-Allow-Methods", &methods.join(", "))
+            .header("Access-Control-Allow-Headers", &self.allowed_headers.join(", "))
+    }
+}
+
+// Errors are turned into responses further up, without CORS headers: a
+// cross-origin page can tell that its request failed, but not why
+impl Middleware for CorsConfig {
+    fn handle(&self, request: &Request, _: &Context, next: Next)
+              -> Result<Response, ServerError> {
+        next(request).map(|response| self.apply(request, response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::RequestBuilder;
+
+    fn config(origins: &[&str], allow_credentials: bool) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: origins.iter().map(|&origin| origin.to_owned()).collect(),
+            allow_credentials,
+            ..CorsConfig::default()
+        }
+    }
+
+    fn request(builder: RequestBuilder) -> Request {
+        Request::parse(&builder.build_bytes()).unwrap()
+    }
+
+    #[test]
+    fn allows_a_listed_origin() {
+        let cors = config(&["https://example.com"], false);
+        let request = request(RequestBuilder::get("/").header("Origin", "https://example.com"));
+        let response = cors.apply(&request, Response::new(200));
+        assert_eq!(response.get_header("Access-Control-Allow-Origin"), Some("https://example.com"));
+        assert_eq!(response.get_header("Vary"), Some("Origin"));
+        assert_eq!(response.get_header("Access-Control-Allow-Credentials"), None);
+    }
+
+    #[test]
+    fn leaves_other_origins_alone() {
+        let cors = config(&["https://example.com"], false);
+        let request = request(RequestBuilder::get("/").header("Origin", "https://evil.example"));
+        let response = cors.apply(&request, Response::new(200));
+        assert_eq!(response.get_header("Access-Control-Allow-Origin"), None);
+        assert_eq!(response.get_header("Vary"), None);
+    }
+
+    #[test]
+    fn adds_origin_to_an_existing_vary() {
+        let cors = config(&["https://example.com"], false);
+        let request = request(RequestBuilder::get("/").header("Origin", "https://example.com"));
+        let response = cors.apply(&request, Response::new(200).header("Vary", "Accept-Encoding"));
+        assert_eq!(response.get_header("Vary"), Some("Accept-Encoding, Origin"));
+    }
+
+    #[test]
+    fn names_the_origin_when_credentials_are_allowed() {
+        let request = request(RequestBuilder::get("/").header("Origin", "https://a.example"));
+        let response = config(&["*"], false).apply(&request, Response::new(200));
+        assert_eq!(response.get_header("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(response.get_header("Vary"), None);
+
+        let response = config(&["*"], true).apply(&request, Response::new(200));
+        assert_eq!(response.get_header("Access-Control-Allow-Origin"), Some("https://a.example"));
+        assert_eq!(response.get_header("Access-Control-Allow-Credentials"), Some("true"));
+    }
+
+    #[test]
+    fn answers_a_preflight() {
+        let cors = config(&["https://example.com"], false);
+        let preflight = request(RequestBuilder::new(Method::Options, "/fib")
+                                .header("Origin", "https://example.com")
+                                .header("Access-Control-Request-Method", "POST"));
+        let response = cors.preflight(&preflight);
+        assert_eq!(response.status(), 204);
+        assert_eq!(response.get_header("Access-Control-Allow-Methods"), Some("GET, HEAD, POST"));
+        assert_eq!(response.get_header("Access-Control-Allow-Headers"), Some("Content-Type"));
+
+        let plain = request(RequestBuilder::new(Method::Options, "/fib")
+                            .header("Origin", "https://example.com"));
+        assert_eq!(cors.preflight(&plain).get_header("Access-Control-Allow-Methods"), None);
+    }
+}
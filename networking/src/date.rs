@@ -0,0 +1,215 @@
+// Dates the way HTTP writes them, e.g. in the Date header:
+//   Sun, 06 Nov 1994 08:49:37 GMT
+// This is the "IMF-fixdate" format of RFC 7231, section 7.1.1.1: always in
+// GMT, always in English, always the same width.
+//
+// Clients send such dates back to us, e.g. in If-Modified-Since, and may
+// still use one of two older formats for them, which parse_http_date()
+// understands as well (RFC 7231 requires it).
+//
+// Logs use RFC 3339 instead, which sorts nicely and which every log tool
+// understands:
+//   1994-11-06T08:49:37.000Z
+//
+// The standard library only knows about seconds since the Unix epoch
+// (1970-01-01 00:00:00 UTC), so turning them into a calendar date is up to us
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                            "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// The last year parse_http_date() accepts. HTTP dates have four-digit years,
+// and a client's bigger one would only overflow the sums below
+const MAX_YEAR: u64 = 9999;
+
+// Formats the time as an IMF-fixdate. Times before 1970 are not worth the
+// trouble and come out as the epoch itself
+pub fn http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+
+    // The epoch was a Thursday
+    let weekday = DAYS[((days + 4) % 7) as usize];
+
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            weekday, day, MONTHS[(month - 1) as usize], year,
+            secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+// Formats the time as RFC 3339, in UTC (the "Z") and with milliseconds
+pub fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days(secs / 86400);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60,
+            secs_of_day % 60, since.subsec_millis())
+}
+
+// Parses a date in any of the three formats HTTP has used over time:
+//   Sun, 06 Nov 1994 08:49:37 GMT   (IMF-fixdate, what everybody sends today)
+//   Sunday, 06-Nov-94 08:49:37 GMT  (RFC 850, with a two-digit year)
+//   Sun Nov  6 08:49:37 1994        (the format of C's asctime())
+// Anything else, including dates before 1970 or after MAX_YEAR, gives None. The day of the
+// week is redundant, so it is not checked
+pub fn parse_http_date(text: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let (day, month, year, time) = match parts[..] {
+        [weekday, day, month, year, time, "GMT"] if weekday.ends_with(',') => {
+            (day, month, year.parse().ok()?, time)
+        },
+        [weekday, date, time, "GMT"] if weekday.ends_with(',') => {
+            let mut pieces = date.split('-');
+            let (day, month, year) = (pieces.next()?, pieces.next()?, pieces.next()?);
+            // Two-digit years are taken to be within 1970-2069
+            let year: u64 = year.parse().ok()?;
+            (day, month, if year < 70 { 2000 + year } else { 1900 + year }, time)
+        },
+        [_, month, day, time, year] => (day, month, year.parse().ok()?, time),
+        _ => return None,
+    };
+
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|&name| name == month)? as u64 + 1;
+    let mut clock = time.split(':').map(|piece| piece.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (clock.next()??, clock.next()??, clock.next()??);
+    if !(1970..=MAX_YEAR).contains(&year) || !(1..=31).contains(&day) || hours > 23 || minutes > 59 ||
+        seconds > 60 || clock.next().is_some() {
+        return None;
+    }
+
+    let secs = days_from_civil(year, month, day) * 86400
+        + hours * 3600 + minutes * 60 + seconds;
+    // Even year 9999 fits easily, but SystemTime's own range depends on the
+    // platform
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// The number of days from the epoch to a date of the Gregorian calendar, the
+// other way around from civil_from_days() below. Only meant for dates from
+// 1970 on
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // Years starting in March again
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Turns a number of days since the epoch into a (year, month, day) date of
+// the Gregorian calendar, month and day counting from 1.
+//
+// This is Howard Hinnant's days_from_civil algorithm run backwards. The trick
+// is to start the year in March, which puts the leap day at the very end of
+// it, and to count in eras of 400 years: the calendar repeats itself exactly
+// every 400 years (146097 days)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Days since 0000-03-01 rather than 1970-01-01
+    let days = days + 719_468;
+    let era = days / 146_097;
+    // Day and year of the era, from 0
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+                       - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4
+                                    - year_of_era / 100);
+    // Months counted from March, from 0. 153 days make up 5 months
+    let month_from_march = (5 * day_of_year + 2) / 153;
+
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    // January and February still belong to the year which started the
+    // previous March
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 784111777 is Sun, 06 Nov 1994 08:49:37 GMT, the example date of the
+    // RFCs
+    fn example() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(784_111_777)
+    }
+
+    #[test]
+    fn formats_an_imf_fixdate() {
+        assert_eq!(http_date(example()), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        // The leap day of a year divisible by 400
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+                   "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn formats_rfc_3339() {
+        assert_eq!(rfc3339(example() + Duration::from_millis(5)), "1994-11-06T08:49:37.005Z");
+    }
+
+    #[test]
+    fn parses_what_it_formats() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(parse_http_date(&http_date(time)), Some(time));
+    }
+
+    #[test]
+    fn parses_an_imf_fixdate() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(example()));
+    }
+
+    #[test]
+    fn parses_an_rfc_850_date() {
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), Some(example()));
+    }
+
+    #[test]
+    fn parses_an_asctime_date() {
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(example()));
+    }
+
+    #[test]
+    fn takes_two_digit_years_before_70_as_this_century() {
+        let date = parse_http_date("Friday, 01-Jan-21 00:00:00 GMT").unwrap();
+        assert_eq!(date.duration_since(UNIX_EPOCH).unwrap().as_secs(), 1_609_459_200);
+    }
+
+    #[test]
+    fn refuses_years_out_of_range() {
+        assert_eq!(parse_http_date("Wed, 31 Dec 1969 23:59:59 GMT"), None);
+        assert_eq!(parse_http_date("Sat, 01 Jan 10000 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 18446744073709551615 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 99999999999999"), None);
+    }
+
+    #[test]
+    fn parses_the_last_year_in_range() {
+        assert!(parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT").is_some());
+    }
+
+    #[test]
+    fn refuses_garbage() {
+        assert_eq!(parse_http_date(""), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+    }
+}
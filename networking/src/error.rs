@@ -0,0 +1,234 @@
+// The ways in which handling a request can go wrong.
+//
+// Rather than passing io::Error around for everything, or picking a status
+// code wherever something fails, code returns one of these and a single place
+// (status_for() below) decides what the client is told.
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use serde_json;
+
+// What can be wrong with the request line and headers sent by the client.
+// Clone is derived so that the same error can be both reported to the client
+// and logged
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    // Nothing arrived, or only blank lines
+    MissingRequestLine,
+    // The request line is not terminated by "\r\n"
+    MissingCrlf,
+    // The first line is not "METHOD PATH HTTP/x.y"
+    MalformedRequestLine(String),
+    // The last part of the request line is not of the form HTTP/x.y
+    InvalidVersion(String),
+    // The middle part of the request line is neither a path nor "*"
+    InvalidTarget(String),
+    // A header line without a colon in it
+    MalformedHeader(String),
+    // The request line and headers go on for longer than we are willing to
+    // read
+    HeadersTooLong,
+    // The request line goes on for longer than config.max_request_line
+    RequestLineTooLong,
+    // A method we do not implement, e.g. CONNECT
+    UnknownMethod(String),
+    // A "%" escape which is not followed by two hex digits, or escapes which
+    // do not decode to UTF-8
+    InvalidPercentEncoding(String),
+    // A multipart/form-data body which does not follow its own boundaries
+    InvalidMultipart(String),
+}
+
+// Display is what "{}" uses. It is also required by the Error trait
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::MissingRequestLine => write!(f, "Missing request line"),
+            ParseError::MissingCrlf => {
+                write!(f, "Request line is not terminated by CRLF")
+            },
+            ParseError::MalformedRequestLine(ref line) => {
+                write!(f, "Malformed request line: {}", line)
+            },
+            ParseError::InvalidVersion(ref version) => {
+                write!(f, "Invalid HTTP version: {}", version)
+            },
+            ParseError::InvalidTarget(ref target) => {
+                write!(f, "Invalid request target: {}", target)
+            },
+            ParseError::MalformedHeader(ref line) => {
+                write!(f, "Malformed header: {}", line)
+            },
+            ParseError::HeadersTooLong => write!(f, "Request headers are too long"),
+            ParseError::RequestLineTooLong => write!(f, "Request line is too long"),
+            ParseError::UnknownMethod(ref method) => {
+                write!(f, "Method not implemented: {}", method)
+            },
+            ParseError::InvalidPercentEncoding(ref text) => {
+                write!(f, "Invalid percent-encoding: {}", text)
+            },
+            ParseError::InvalidMultipart(ref reason) => {
+                write!(f, "Invalid multipart body: {}", reason)
+            },
+        }
+    }
+}
+
+// Implementing Error (its methods all have default implementations) lets a
+// ParseError be used wherever the standard library expects an error type
+impl Error for ParseError {}
+
+#[derive(Debug)]
+pub enum ServerError {
+    // Reading from or writing to the connection or the disk failed
+    Io(io::Error),
+    // The request could not be understood
+    Parse(ParseError),
+    // The request is larger than we are willing to accept
+    TooLarge,
+    // The client took too long to send the request
+    Timeout,
+    // The request was understood, but makes no sense, e.g. a missing header
+    BadRequest(String),
+    // The request asks for something we do not support, e.g. a transfer
+    // coding other than chunked
+    NotImplemented(String),
+    // The request asks for something it is not allowed to have, e.g. a file
+    // outside of the document root
+    Forbidden,
+    // The client made more requests than it is allowed to, see
+    // rate_limit.rs. Holds how many seconds it has to wait
+    TooManyRequests(u64),
+    // The body is not of a type the handler accepts, e.g. text where it
+    // wants JSON. Holds the Content-Type the client sent
+    UnsupportedMediaType(String),
+    // The body claims to be JSON, but is not, or not what the handler
+    // expected
+    InvalidJson(serde_json::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerError::Io(ref e) => write!(f, "I/O error: {}", e),
+            ServerError::Parse(ref e) => write!(f, "{}", e),
+            ServerError::TooLarge => write!(f, "Request too large"),
+            ServerError::Timeout => write!(f, "Timed out waiting for the request"),
+            ServerError::BadRequest(ref message) => write!(f, "{}", message),
+            ServerError::NotImplemented(ref message) => write!(f, "{}", message),
+            ServerError::Forbidden => write!(f, "Forbidden"),
+            ServerError::TooManyRequests(_) => write!(f, "Too many requests"),
+            ServerError::UnsupportedMediaType(ref content_type) if content_type.is_empty() => {
+                write!(f, "Missing Content-Type")
+            },
+            ServerError::UnsupportedMediaType(ref content_type) => {
+                write!(f, "Unsupported Content-Type: {}", content_type)
+            },
+            ServerError::InvalidJson(ref e) => write!(f, "Invalid JSON: {}", e),
+        }
+    }
+}
+
+impl Error for ServerError {
+    // The underlying error, if this one wraps another; lets error reporters
+    // print the whole chain of causes
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ServerError::Io(ref e) => Some(e),
+            ServerError::Parse(ref e) => Some(e),
+            ServerError::InvalidJson(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// The From trait is what the "?" operator uses to convert errors: with this
+// in place, "?" on an io::Result inside a function returning
+// Result<_, ServerError> turns the io::Error into a ServerError by itself.
+//
+// A read on a socket with a timeout set fails with TimedOut or WouldBlock
+// (depending on the platform) once the time is up; those become Timeout
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> ServerError {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ServerError::Timeout,
+            _ => ServerError::Io(e),
+        }
+    }
+}
+
+impl From<ParseError> for ServerError {
+    fn from(e: ParseError) -> ServerError {
+        ServerError::Parse(e)
+    }
+}
+
+// The HTTP status code to answer with for each kind of error
+pub fn status_for(error: &ServerError) -> u16 {
+    match *error {
+        ServerError::Io(_) => 500,
+        // The request is fine, we just do not know how to carry it out
+        ServerError::Parse(ParseError::UnknownMethod(_)) => 501,
+        // URI Too Long: it is the path which makes a request line long
+        ServerError::Parse(ParseError::RequestLineTooLong) => 414,
+        ServerError::Parse(_) => 400,
+        ServerError::TooLarge => 413,
+        ServerError::Timeout => 408,
+        ServerError::BadRequest(_) => 400,
+        ServerError::NotImplemented(_) => 501,
+        ServerError::Forbidden => 403,
+        ServerError::TooManyRequests(_) => 429,
+        ServerError::UnsupportedMediaType(_) => 415,
+        ServerError::InvalidJson(_) => 400,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_a_status_for_every_error() {
+        let json_error = serde_json::from_str::<u8>("x").unwrap_err();
+        let cases = vec![
+            (ServerError::Io(io::Error::other("disk on fire")), 500),
+            (ParseError::UnknownMethod("BREW".to_owned()).into(), 501),
+            (ParseError::RequestLineTooLong.into(), 414),
+            (ParseError::HeadersTooLong.into(), 400),
+            (ParseError::MissingCrlf.into(), 400),
+            (ParseError::InvalidMultipart("no end".to_owned()).into(), 400),
+            (ServerError::TooLarge, 413),
+            (ServerError::Timeout, 408),
+            (ServerError::BadRequest("Missing Host".to_owned()), 400),
+            (ServerError::NotImplemented("gzip".to_owned()), 501),
+            (ServerError::Forbidden, 403),
+            (ServerError::TooManyRequests(3), 429),
+            (ServerError::UnsupportedMediaType("text/plain".to_owned()), 415),
+            (ServerError::InvalidJson(json_error), 400),
+        ];
+        for (error, status) in cases {
+            assert_eq!(status_for(&error), status, "{}", error);
+        }
+    }
+
+    #[test]
+    fn turns_read_timeouts_into_408s() {
+        for kind in &[io::ErrorKind::TimedOut, io::ErrorKind::WouldBlock] {
+            let error = ServerError::from(io::Error::from(*kind));
+            assert!(matches!(error, ServerError::Timeout), "{:?}", error);
+        }
+        let error = ServerError::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(matches!(error, ServerError::Io(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn keeps_the_cause_of_an_error() {
+        let error = ServerError::from(ParseError::MissingRequestLine);
+        assert_eq!(error.to_string(), "Missing request line");
+        assert_eq!(error.source().unwrap().to_string(), "Missing request line");
+        assert!(ServerError::Forbidden.source().is_none());
+        assert_eq!(ServerError::UnsupportedMediaType(String::new()).to_string(),
+                   "Missing Content-Type");
+    }
+}
@@ -0,0 +1,84 @@
+// Pages of the operator's own making for error responses, set in the
+// configuration file one status code at a time, nginx-style:
+//   error_page = 404 ./errors/not_found.html
+//   error_page = 500 ./errors/oops.html
+// A status without a page of its own gets the short plain text message the
+// server would send anyway.
+//
+// Relative paths are relative to the directory the server runs in, not to
+// the document root: error pages need not be served as files of their own
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use mime::content_type_for;
+use response::Response;
+
+const TEXT: &str = "text/plain; charset=utf-8";
+
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    pages: HashMap<u16, PathBuf>,
+}
+
+impl ErrorPages {
+    // Uses the file at `path` for responses with the given status code,
+    // replacing any page it had already
+    pub fn set(&mut self, status: u16, path: PathBuf) {
+        self.pages.insert(status, path);
+    }
+
+    pub fn get(&self, status: u16) -> Option<&Path> {
+        self.pages.get(&status).map(PathBuf::as_path)
+    }
+
+    // A response with the given status, and the page set for it as the body,
+    // or `fallback` as plain text if there is none.
+    //
+    // A page which cannot be read is reported on stderr and replaced by
+    // the fallback too: failing to say what went wrong because of another
+    // error would not help anybody
+    pub fn response(&self, status: u16, fallback: &str) -> Response {
+        let response = Response::new(status);
+        if let Some(path) = self.get(status) {
+            match fs::read(path) {
+                Ok(page) => {
+                    let content_type = content_type_for(&path.to_string_lossy());
+                    return response.header("Content-Type", content_type).body(page);
+                },
+                Err(e) => eprintln!("Cannot read the error page {}: {}", path.display(), e),
+            }
+        }
+        response.header("Content-Type", TEXT).body(fallback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::TempDir;
+
+    #[test]
+    fn uses_the_page_set_for_a_status() {
+        let dir = TempDir::new();
+        let mut pages = ErrorPages::default();
+        pages.set(404, dir.file("404.html", "<h1>Gone</h1>"));
+        let response = pages.response(404, "Not found\n");
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.get_header("Content-Type"), Some("text/html; charset=utf-8"));
+        assert_eq!(response.body_bytes(), b"<h1>Gone</h1>");
+    }
+
+    #[test]
+    fn falls_back_to_the_message_without_a_readable_page() {
+        let dir = TempDir::new();
+        let mut pages = ErrorPages::default();
+        pages.set(500, dir.path().join("missing.html"));
+        for &status in &[404, 500] {
+            let response = pages.response(status, "Something\n");
+            assert_eq!(response.status(), status);
+            assert_eq!(response.get_header("Content-Type"), Some(TEXT));
+            assert_eq!(response.body_bytes(), b"Something\n");
+        }
+    }
+}
@@ -0,0 +1,61 @@
+// Fibonacci numbers for the /fib route, remembered across requests.
+//
+// The numbers computed so far live in a single Vec shared by the whole
+// process: F(i) is at index i. Asking for an n we have already reached is a
+// lookup, and asking for a larger one only computes the missing entries.
+use std::sync::Mutex;
+
+// F(93) is the largest Fibonacci number which fits in a u64. This is also what
+// keeps the cache from growing without bounds: it never holds more than
+// MAX_N + 1 numbers
+pub const MAX_N: usize = 93;
+
+// A static lives for the whole run of the program, and there is only one of
+// it. Statics must be safe to share between threads, which a plain Vec being
+// modified is not, hence the Mutex. Mutex::new() and Vec::new() are const
+// functions, which is what allows calling them in a static's initializer
+static CACHE: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+// Returns F(n), or None when it would not fit in a u64
+pub fn fib_memo(n: usize) -> Option<u64> {
+    if n > MAX_N {
+        return None;
+    }
+
+    // A Mutex is "poisoned" when a thread panics while holding it, in case
+    // it left the data half-updated. We only ever push complete entries, so
+    // the data is fine either way and we take it back with .into_inner()
+    let mut cache = CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    if cache.is_empty() {
+        cache.push(0);
+        cache.push(1);
+    }
+    while cache.len() <= n {
+        let next = cache[cache.len() - 1] + cache[cache.len() - 2];
+        cache.push(next);
+    }
+
+    Some(cache[n])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_the_first_numbers() {
+        let first: Vec<_> = (0..10).map(|n| fib_memo(n).unwrap()).collect();
+        assert_eq!(first, vec![0, 1, 1, 2, 3, 5, 8, 13, 21, 34]);
+    }
+
+    #[test]
+    fn reaches_the_largest_number_fitting_in_a_u64() {
+        assert_eq!(fib_memo(MAX_N), Some(12_200_160_415_121_876_738));
+        assert_eq!(fib_memo(MAX_N + 1), None);
+        assert_eq!(fib_memo(usize::MAX), None);
+        // Asking again, with the cache filled, gives the same answers
+        assert_eq!(fib_memo(50), Some(12_586_269_025));
+        assert_eq!(fib_memo(MAX_N), Some(12_200_160_415_121_876_738));
+    }
+}
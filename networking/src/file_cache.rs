@@ -0,0 +1,205 @@
+// Keeps the files served from the document root in memory, so that a file
+// asked for over and over is read from the disk once rather than every
+// time:
+//   cargo run -- --cache-ttl 60
+// keeps every file for up to a minute.
+//
+// The file's metadata is still looked at for every request, as serve_file()
+// needs it for the ETag anyway: a file whose modification time or length
+// has changed since we read it is read again straight away. The TTL is for
+// the changes this misses, e.g. two writes within the same tick of the
+// filesystem's clock.
+//
+// The cache holds up to MAX_CACHE_BYTES of files. Caching another one which
+// does not fit makes room by forgetting the files asked for least recently
+// ("least recently used", LRU): a site with more files than that keeps the
+// ones in demand in memory, and reads the rest from the disk
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+// Larger files are read from the disk every time, so that a few big videos
+// cannot take up all of the memory
+const MAX_FILE_BYTES: u64 = 1024 * 1024;
+
+// The most bytes of files the cache holds, all of them together
+const MAX_CACHE_BYTES: u64 = 64 * 1024 * 1024;
+
+struct Entry {
+    contents: Vec<u8>,
+    // What the file looked like when we read it
+    modified: SystemTime,
+    len: u64,
+    cached_at: Instant,
+    // When the file was last served from the cache, or cached
+    used: Instant,
+}
+
+struct Entries {
+    files: HashMap<PathBuf, Entry>,
+    // The size of all of the contents in `files` together
+    bytes: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.files.remove(path) {
+            self.bytes -= entry.contents.len() as u64;
+        }
+    }
+
+    // Forgets the least recently used files until `bytes` more fit. Finding
+    // the least recently used one goes through all of them, but a file
+    // only has to be found that way when another one is cached
+    fn make_room(&mut self, bytes: u64) {
+        while self.bytes + bytes > MAX_CACHE_BYTES {
+            let oldest = self.files.iter()
+                .min_by_key(|&(_, entry)| entry.used)
+                .map(|(path, _)| path.clone());
+            match oldest {
+                Some(path) => self.remove(&path),
+                None => break,
+            }
+        }
+    }
+}
+
+// Shared by all of the worker threads, hence the Mutex, as in stats.rs
+pub struct FileCache {
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl FileCache {
+    pub fn new(ttl: Duration) -> FileCache {
+        FileCache {
+            ttl,
+            entries: Mutex::new(Entries { files: HashMap::new(), bytes: 0 }),
+        }
+    }
+
+    // The contents of the file at `path`, whose current metadata is
+    // `metadata`: from memory if we have them and they are still good, and
+    // from the disk otherwise.
+    //
+    // The lock is not held while reading the file, so that a slow disk only
+    // holds up the thread waiting for it. Two threads may then both read a
+    // file which is not cached yet, which does no harm
+    pub fn read(&self, path: &Path, metadata: &Metadata) -> io::Result<Vec<u8>> {
+        let modified = metadata.modified()?;
+        {
+            let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(entry) = entries.files.get_mut(path) {
+                if entry.modified == modified && entry.len == metadata.len()
+                    && entry.cached_at.elapsed() < self.ttl {
+                    entry.used = Instant::now();
+                    return Ok(entry.contents.clone());
+                }
+            }
+        }
+
+        let contents = fs::read(path)?;
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Whatever we had of the file goes, as it is out of date, or it may
+        // have been small enough to cache before
+        entries.remove(path);
+        if metadata.len() <= MAX_FILE_BYTES && contents.len() as u64 <= MAX_FILE_BYTES {
+            entries.make_room(contents.len() as u64);
+            entries.bytes += contents.len() as u64;
+            let now = Instant::now();
+            entries.files.insert(path.to_owned(), Entry {
+                contents: contents.clone(),
+                modified,
+                len: metadata.len(),
+                cached_at: now,
+                used: now,
+            });
+        }
+        Ok(contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::thread;
+
+    use super::*;
+    use test_support::TempDir;
+    // Changes the file, leaving its length and modification time as they
+    // were, which is what the TTL is there for
+    fn rewrite_unnoticed(path: &Path, contents: &str) {
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        fs::write(path, contents).unwrap();
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    fn read(cache: &FileCache, path: &Path) -> Vec<u8> {
+        cache.read(path, &fs::metadata(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn serves_a_file_from_memory_until_the_ttl_runs_out() {
+        let dir = TempDir::new();
+        let path = dir.file("a.txt", "old");
+        let cache = FileCache::new(Duration::from_millis(200));
+        assert_eq!(read(&cache, &path), b"old");
+
+        rewrite_unnoticed(&path, "new");
+        assert_eq!(read(&cache, &path), b"old");
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(read(&cache, &path), b"new");
+    }
+
+    #[test]
+    fn reads_a_file_again_once_it_changed() {
+        let dir = TempDir::new();
+        let path = dir.file("a.txt", "old");
+        let cache = FileCache::new(Duration::from_secs(60));
+        assert_eq!(read(&cache, &path), b"old");
+
+        fs::write(&path, "longer").unwrap();
+        assert_eq!(read(&cache, &path), b"longer");
+        assert_eq!(cache.entries.lock().unwrap().bytes, 6);
+    }
+
+    #[test]
+    fn leaves_large_files_on_the_disk() {
+        let dir = TempDir::new();
+        let path = dir.file("big.bin", vec![0; MAX_FILE_BYTES as usize + 1]);
+        let cache = FileCache::new(Duration::from_secs(60));
+        assert_eq!(read(&cache, &path).len(), MAX_FILE_BYTES as usize + 1);
+        assert!(cache.entries.lock().unwrap().files.is_empty());
+    }
+
+    #[test]
+    fn makes_room_by_forgetting_the_least_recently_used() {
+        let mut entries = Entries { files: HashMap::new(), bytes: 0 };
+        let start = Instant::now();
+        let full = MAX_CACHE_BYTES / MAX_FILE_BYTES;
+        for n in 0..full {
+            let used = start + Duration::from_secs(n);
+            entries.files.insert(PathBuf::from(n.to_string()), Entry {
+                contents: vec![0; MAX_FILE_BYTES as usize],
+                modified: SystemTime::now(),
+                len: MAX_FILE_BYTES,
+                cached_at: start,
+                used,
+            });
+            entries.bytes += MAX_FILE_BYTES;
+        }
+        // "0" was cached first, but used since
+        entries.files.get_mut(Path::new("0")).unwrap().used = start + Duration::from_secs(full);
+
+        entries.make_room(MAX_FILE_BYTES + 1);
+        assert_eq!(entries.files.len() as u64, full - 2);
+        assert_eq!(entries.bytes, (full - 2) * MAX_FILE_BYTES);
+        assert!(entries.files.contains_key(Path::new("0")));
+        assert!(!entries.files.contains_key(Path::new("1")));
+        assert!(!entries.files.contains_key(Path::new("2")));
+        assert!(entries.files.contains_key(Path::new("3")));
+    }
+}
@@ -9,85 +9,1601 @@
 // types implement into scope before using the functions defined for such traits
 //
 // std::fs::File is used to open and manipule files on the filesystem
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
-use std::fs::File;
+//
+// socket2 is an external crate (see Cargo.toml) which gives us access to the
+// socket options that std::net does not expose, like the listen backlog. In
+// the 2015 edition of Rust external crates need to be declared with
+// "extern crate" before they can be used
+extern crate socket2;
+// signal-hook is only needed, and only available, on Unix-like systems.
+// #[cfg(unix)] makes the compiler skip the item everywhere else
+#[cfg(unix)]
+extern crate signal_hook;
+// libc has the names of the C error codes, like EMFILE
+#[cfg(unix)]
+extern crate libc;
+// flate2 does the gzip compression of responses, see compress.rs
+extern crate flate2;
+// rustls does the TLS of HTTPS, see tls.rs
+extern crate rustls;
+// serde turns Rust values into JSON and back, see Request::json() and
+// Response::json()
+extern crate serde;
+extern crate serde_json;
+// sha1 and base64 work out the Sec-WebSocket-Accept, see websocket.rs
+extern crate base64;
+extern crate sha1;
 
-// The parameter type is mutable and copy. That means that the function takes
-// ownership of "stream", and "stream" will go out of scope and be deleted
-// when the function completes
-fn handle_connection(mut stream: TcpStream) {
-    // Create a slice of integers, inferring their type
-    let mut buffer = [0; 512];
+mod access_control;
+mod access_log;
+mod autoindex;
+mod basic_auth;
+mod buffer_pool;
+mod chunked;
+mod compress;
+mod config;
+mod cors;
+mod date;
+mod error;
+mod error_pages;
+mod fib;
+mod file_cache;
+mod metrics;
+mod middleware;
+mod mime;
+mod multipart;
+mod range;
+mod rate_limit;
+mod request;
+mod response;
+mod router;
+mod semaphore;
+mod sse;
+mod stats;
+// Helpers for the tests, see test_support.rs
+#[cfg(test)]
+mod test_support;
+mod thread_pool;
+mod tls;
+mod websocket;
 
-    // Read the incoming data into the buffer
-    let buf_size = stream.read(&mut buffer).unwrap();
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::io::{self, BufReader, SeekFrom};
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream, ToSocketAddrs};
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-    println!("= Read {} bytes.", buf_size);
+use access_log::{AccessLog, Entry};
+use basic_auth::BasicAuth;
+use buffer_pool::BufferPool;
+use config::{LogLevel, ServerConfig};
+use date::{http_date, parse_http_date};
+use error::{ParseError, ServerError, status_for};
+use file_cache::FileCache;
+use metrics::Metrics;
+use mime::content_type_for;
+use multipart::parse_multipart;
+use range::{ByteRange, parse_range};
+use rate_limit::RateLimiter;
+use request::{Method, Request};
+use response::Response;
+use router::{Context, Router};
+use semaphore::Semaphore;
+use sse::Event;
+use stats::RouteStats;
+use thread_pool::ThreadPool;
+use socket2::{Domain, Socket, Type};
+// The traits, and the #[derive()] macros which implement them, share a name
+use serde::{Deserialize, Serialize};
 
-    // ::from_utf8_lossy() takes a chunk of bytes representing utf-8 encoded
-    // unicode text and produces a string, replacing invalid utf-8 sequences
-    // with the unicode replacement character �
-    println!("= Request:\n{}\n", String::from_utf8_lossy(&buffer[..]));
+// env!() reads an environment variable while compiling. Cargo sets
+// CARGO_PKG_VERSION to the version in Cargo.toml, so this constant always
+// matches the version the binary was built from
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-    // Create a slice of raw bytes from a string by using "b" in front of the
-    // string literal
-    //
-    // This represents a request requesting for the root page of the server
-    let get_request = b"GET / HTTP/1.1\r\n";
+// The favicon served when the document root does not have one. include_bytes!()
+// embeds the contents of the file into the executable at compile time; the
+// path is relative to this source file
+const DEFAULT_FAVICON: &[u8] = include_bytes!("../favicon.ico");
 
-    // This syntax allows us to return a tuple of values from the if expression
-    // depending on which of the branches of the if expression was taken by the
-    // code. Since the branches are expressions, these can be assigned to a
-    // variable
-    //
-    // If the client requests the root page, we return our equivalent of
-    // an index.html; if not, simply return a 404
-    let (status_line, filename) = if buffer.starts_with(get_request) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello_rust.html")
-    }
-    else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
-    }; // The ; for the two variables which we are creating is here
+const HTML: &str = "text/html; charset=utf-8";
+const TEXT: &str = "text/plain; charset=utf-8";
+const ICON: &str = "image/x-icon";
 
+// Any error, e.g. the file not existing, is handed back to the caller by "?"
+// rather than unwrapped: a missing page is worth a 500, not a crashed worker
+fn read_file(filename: &Path) -> io::Result<Vec<u8>> {
     // ::open will create a File instance; it can be thought of as when calling
     // ::new on other types. (this might help some understand as it is somehow
     // more similar to using ::operator new() in C++
     //
-    // We make the file mut because the traits function .read_to_string (from
+    // We make the file mut because the traits function .read_to_end (from
     // the trait std::io::Read) uses a mut ref to self when calling the method
-    let mut file_to_serve = File::open(filename).unwrap();
+    let mut file_to_serve = File::open(filename)?;
 
-    // Store the contents of the file in a string; make it mut because it will
+    // Store the contents of the file in a vector of bytes rather than in a
+    // String, as not every file we serve is text; make it mut because it will
     // be filled later and not at creation
-    let mut content = String::new();
+    let mut content = Vec::new();
     // Place the whole contents of the file, until EOF is reached, into the
-    // String passed
-    file_to_serve.read_to_string(&mut content).unwrap();
-
-    // format!() is a macro which creates a value of type String by using the
-    // the syntax provided in the first argument. It can be thought of as a
-    // similar macro to print!() or println!() but instead of printing to the
-    // STDOUT, it "prints" the results into a String, and then returns such
-    // String.
+    // Vec passed
+    file_to_serve.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+// The address of the client which sent the request.
+//
+// Behind a reverse proxy, the peer of the TCP connection is the proxy itself;
+// the proxy tells us who the real client is by adding an X-Forwarded-For
+// header. Every proxy along the way appends the address it received the
+// request from, so the header looks like
+//   X-Forwarded-For: client, proxy1, proxy2
+// and the first entry is the original client. We only look at the header
+// when config.trust_proxy is set, and fall back to the peer address when it
+// is missing or does not hold a valid address. A request we could not parse
+// (None) has no headers to look at.
+//
+// `peer` is the address at the other end of the connection, see
+// handle_connection()
+fn client_ip(peer: IpAddr, request: Option<&Request>, config: &ServerConfig) -> IpAddr {
+    if config.trust_proxy {
+        let forwarded = request
+            .and_then(|request| request.header("X-Forwarded-For"))
+            .and_then(|value| value.split(',').next())
+            .and_then(|first| first.trim().parse().ok());
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+
+    peer
+}
+
+// Handles GET /fib?n=<n>
+fn fib(request: &Request, _: &Context) -> Result<Response, ServerError> {
+    Ok(fib_response(request.query.get("n"), "/fib?n=10"))
+}
+
+// Handles GET /fib/<n>, the same as /fib?n=<n>. /fib/seq is a route of its
+// own, and takes precedence
+fn fib_path(request: &Request, _: &Context) -> Result<Response, ServerError> {
+    Ok(fib_response(request.params.get("n"), "/fib/10"))
+}
+
+// F(n), or what is wrong with n. `example` shows how it should have been
+// asked for
+fn fib_response(n: Option<&String>, example: &str) -> Response {
+    match n.map(|n| n.parse::<usize>()) {
+        Some(Ok(n)) => match fib::fib_memo(n) {
+            Some(value) => Response::new(200)
+                .header("Content-Type", TEXT)
+                .body(format!("{}\n", value)),
+            None => Response::new(400)
+                .header("Content-Type", TEXT)
+                .body(format!("n can be at most {}\n", fib::MAX_N)),
+        },
+        _ => Response::new(400)
+            .header("Content-Type", TEXT)
+            .body(format!("Expected a number, as in {}\n", example)),
+    }
+}
+
+// Handles GET /fib/seq?n=<n>, every Fibonacci number from F(0) to F(n), one
+// per line. The lines are sent as they are worked out, each one a chunk of
+// its own thanks to the .flush(); see Response::chunked()
+fn fib_seq(request: &Request, _: &Context) -> Result<Response, ServerError> {
+    let n = match request.query.get("n").map(|n| n.parse::<usize>()) {
+        Some(Ok(n)) if n <= fib::MAX_N => n,
+        _ => {
+            return Err(ServerError::BadRequest(
+                format!("Expected a number up to {}, as in /fib/seq?n=10", fib::MAX_N)))
+        },
+    };
+
+    // "move" hands n over to the closure, which runs after we have returned
+    Ok(Response::new(200)
+       .header("Content-Type", TEXT)
+       .chunked(move |out| {
+           for i in 0..=n {
+               // fib_memo() only fails past MAX_N
+               writeln!(out, "{}", fib::fib_memo(i).unwrap_or(0))?;
+               out.flush()?;
+           }
+           Ok(())
+       }))
+}
+
+// Handles GET /events/ticks?count=<count>, an event stream with a tick every
+// second, `count` of them (10 if not given). A thread of its own sends them
+// down a channel, and sse::stream() passes them on to the client
+fn ticks(request: &Request, _: &Context) -> Result<Response, ServerError> {
+    let count = match request.query.get("count").map(|count| count.parse::<u32>()) {
+        None => 10,
+        Some(Ok(count)) if count <= 3600 => count,
+        _ => {
+            return Err(ServerError::BadRequest(
+                "Expected a count up to 3600, as in /events/ticks?count=5".to_owned()))
+        },
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for i in 1..=count {
+            let tick = Event {
+                name: Some("tick".to_owned()),
+                id: Some(i.to_string()),
+                data: i.to_string(),
+            };
+            // An error means the client has gone away
+            if sender.send(tick).is_err() {
+                return;
+            }
+            if i < count {
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    });
+    Ok(sse::stream(receiver))
+}
+
+// What POST /fib expects in its body, e.g. {"n": 10}. #[derive(Deserialize)]
+// writes the code which builds one from JSON, field by field
+#[derive(Deserialize)]
+struct FibRequest {
+    n: usize,
+}
+
+// ...and what it answers with: {"n": 10, "value": 55}
+#[derive(Serialize)]
+struct FibResponse {
+    n: usize,
+    value: u64,
+}
+
+// Handles POST /fib, the JSON flavour of GET /fib
+fn fib_json(request: &Request, _: &Context) -> Result<Response, ServerError> {
+    let FibRequest { n } = request.json()?;
+    match fib::fib_memo(n) {
+        Some(value) => Response::json(200, &FibResponse { n, value }),
+        None => Err(ServerError::BadRequest(format!("n can be at most {}", fib::MAX_N))),
+    }
+}
+
+// Splits a Content-Type header such as
+//   application/json; charset=utf-8
+// into the media type, "application/json", and the charset parameter, if
+// there is one. Media types are case-insensitive, so we lowercase them to
+// make comparisons easy. Parameter values may be quoted, as in
+// charset="utf-8".
+//
+// Headers are sent by the client and may be anything; parameters which are
+// not of the form name=value are skipped rather than treated as errors
+fn parse_content_type(header: &str) -> (String, Option<String>) {
+    let media_type = header.split(';').next().unwrap_or("").trim().to_lowercase();
+    (media_type, content_type_parameter(header, "charset"))
+}
+
+// The value of one of the parameters of a Content-Type header, e.g. the
+// boundary of
+//   multipart/form-data; boundary="XyZ"
+fn content_type_parameter(header: &str, name: &str) -> Option<String> {
+    header.split(';')
+        .skip(1)
+        .filter_map(|parameter| parameter.split_once('='))
+        .find(|&(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().trim_matches('"').to_owned())
+}
+
+// Uploaded files are stored as config.upload_dir/<name>. The name is used as
+// a file name as it is, so anything which could make it point somewhere else
+// (separators, ".", "..") is refused
+fn is_valid_upload_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." &&
+        !name.contains('/') && !name.contains('\\')
+}
+
+// A body has to come with a Content-Length or chunked for a PUT: without
+// either, an empty body could simply mean the client did not tell us how long
+// it is. Returns the 411 Length Required to answer with when it does not
+fn require_length(request: &Request) -> Option<Response> {
+    if request.header("Content-Length").is_none() && request.header("Transfer-Encoding").is_none() {
+        Some(Response::new(411)
+             .header("Content-Type", TEXT)
+             .body("Content-Length is required\n"))
+    } else {
+        None
+    }
+}
+
+// Handles PUT /upload/<name>, storing the body as it is. The body itself has
+// already been read, and checked against config.max_body_bytes, by
+// read_body()
+fn upload(request: &Request, context: &Context) -> Result<Response, ServerError> {
+    let (name, body, config) = (&request.path["/upload/".len()..],
+                                &request.body, context.config);
+    if !is_valid_upload_name(name) {
+        return Err(ServerError::BadRequest("Invalid upload name".to_owned()));
+    }
+    if let Some(response) = require_length(request) {
+        return Ok(response);
+    }
+
+    // Any other I/O error is turned into a ServerError by "?", thanks to the
+    // From<io::Error> implementation
+    let path = config.upload_dir.join(name);
+    fs::create_dir_all(&config.upload_dir)?;
+    // We store the bytes as they are, but tell the client what we think we
+    // received
+    let described = match request.header("Content-Type").map(parse_content_type) {
+        Some((media_type, Some(charset))) => format!(" of {} ({})", media_type, charset),
+        Some((media_type, None)) => format!(" of {}", media_type),
+        None => String::new(),
+    };
+    fs::write(&path, body)?;
+    Ok(Response::new(201)
+       .header("Content-Type", TEXT)
+       .body(format!("Stored {} bytes{} as {}\n", body.len(), described, name)))
+}
+
+// Handles POST /upload, the target of an HTML form like
+//   <form method="post" action="/upload" enctype="multipart/form-data">
+//     <input type="file" name="file">
+//   </form>
+// Every file in the form is stored under the name it had on the client's
+// side, as long as that makes a valid upload name; fields which are not
+// files are ignored. Like for PUT, read_body() has already checked the size
+// of the body
+fn upload_form(request: &Request, context: &Context) -> Result<Response, ServerError> {
+    let content_type = request.header("Content-Type").unwrap_or("");
+    let boundary = match parse_content_type(content_type) {
+        (ref media_type, _) if media_type == "multipart/form-data" => {
+            content_type_parameter(content_type, "boundary")
+                .ok_or_else(|| ServerError::BadRequest("Missing boundary".to_owned()))?
+        },
+        _ => {
+            return Err(ServerError::BadRequest(
+                "Expected a multipart/form-data body".to_owned()))
+        },
+    };
+
+    let parts = parse_multipart(&request.body, &boundary)?;
+    let files: Vec<_> = parts.iter()
+        .filter_map(|part| part.filename.as_ref().map(|filename| (filename, part)))
+        .collect();
+    // Browsers may send paths rather than bare names, which we would rather
+    // refuse than guess at
+    if let Some((filename, _)) = files.iter().find(|(filename, _)| !is_valid_upload_name(filename)) {
+        return Err(ServerError::BadRequest(format!("Invalid upload name: {}", filename)));
+    }
+
+    fs::create_dir_all(&context.config.upload_dir)?;
+    let mut stored = String::new();
+    for (filename, part) in files {
+        fs::write(context.config.upload_dir.join(filename), &part.data)?;
+        stored.push_str(&format!("Stored {} bytes of {} from field {} as {}\n",
+                                 part.data.len(), part.content_type, part.name, filename));
+    }
+    if stored.is_empty() {
+        stored.push_str("No files in the form\n");
+    }
+    Ok(Response::new(201).header("Content-Type", TEXT).body(stored))
+}
+
+// Builds the response telling the client what went wrong. When the fault is
+// on our side (5xx) the details may reveal internals such as file paths, so
+// they go to the server's log and the client only gets them in debug mode.
+// A 501 Not Implemented is the exception: it is about the request, and
+// nothing to worry about on our side.
+//
+// The operator may have set up a page of their own for the status, which
+// then replaces the message; see error_pages.rs
+//
+// A client which is told to slow down is also told for how long
+fn error_response(error: &ServerError, config: &ServerConfig) -> Response {
+    let code = status_for(error);
+    let body = if code >= 500 && code != 501 {
+        eprintln!("Error handling request: {}", error);
+        if config.debug {
+            format!("Internal server error\n\n{}\n", error)
+        } else {
+            "Internal server error\n".to_owned()
+        }
+    } else {
+        format!("{}\n", error)
+    };
+    let response = config.error_pages.response(code, &body);
+    match *error {
+        ServerError::TooManyRequests(secs) => response.header("Retry-After", &secs.to_string()),
+        _ => response,
+    }
+}
+
+// GET /, our hello_rust.html page. A document root of somebody else's
+// making is treated like any other directory, see serve_file()
+fn index(request: &Request, context: &Context) -> Result<Response, ServerError> {
+    let page = context.config.doc_root_for(request.header("Host")).join("hello_rust.html");
+    if page.is_file() {
+        Ok(Response::new(200).header("Content-Type", HTML).body(read_file(&page)?))
+    } else {
+        serve_file(request, context)
+    }
+}
+
+fn debug_stats(_: &Request, context: &Context) -> Result<Response, ServerError> {
+    Ok(Response::new(200).header("Content-Type", TEXT).body(context.stats.render()))
+}
+
+// For Prometheus, or anything else which scrapes its text format
+fn metrics(_: &Request, context: &Context) -> Result<Response, ServerError> {
+    Ok(Response::new(200)
+        .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+        .body(context.metrics.render()))
+}
+
+// What GET /health answers with. &'static str fields are fine for data which
+// only ever goes out: serde writes them as JSON strings like any other
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    uptime_secs: u64,
+    version: &'static str,
+}
+
+// A health check for load balancers
+fn health(_: &Request, context: &Context) -> Result<Response, ServerError> {
+    Response::json(200, &Health {
+        status: "ok",
+        uptime_secs: context.started.elapsed().as_secs(),
+        version: VERSION,
+    })
+}
+
+// What GET /healthz answers with
+#[derive(Serialize)]
+struct Liveness {
+    status: &'static str,
+}
+
+// The bare minimum of a health check, for load balancers which poll it
+// often: it answers as long as the server does, without looking at the
+// filesystem or anything else which could be slow
+fn healthz(_: &Request, _: &Context) -> Result<Response, ServerError> {
+    Response::json(200, &Liveness { status: "ok" })
+}
+
+// GET /ws/echo: a WebSocket sending every message back as it is, which
+// makes for an easy test of a client
+fn ws_echo(request: &Request, _: &Context) -> Result<Response, ServerError> {
+    websocket::handshake(request, |socket| {
+        while let Some(text) = socket.read_text()? {
+            socket.send_text(&text)?;
+        }
+        Ok(())
+    })
+}
+
+// Browsers ask for this on their own for every site they visit. A
+// favicon.ico in the document root takes precedence over the one built into
+// the server
+fn favicon(request: &Request, context: &Context) -> Result<Response, ServerError> {
+    let favicon = context.config.doc_root_for(request.header("Host")).join("favicon.ico");
+    let icon = if favicon.is_file() {
+        read_file(&favicon)?
+    } else {
+        DEFAULT_FAVICON.to_vec()
+    };
+    Ok(Response::new(200).header("Content-Type", ICON).body(icon))
+}
+
+// /old is where /new used to be
+fn old(_: &Request, _: &Context) -> Result<Response, ServerError> {
+    Response::redirect(301, "/new")
+}
+
+fn whoami(_: &Request, context: &Context) -> Result<Response, ServerError> {
+    Ok(Response::new(200)
+       .header("Content-Type", TEXT)
+       .body(format!("{}\n", context.client_ip)))
+}
+
+// Uses the 404 page set in the configuration file if there is one, or else
+// the document root's 404.html if it has one. A document root which does
+// not is not an error, so a short built-in message stands in for it
+fn not_found(request: &Request, context: &Context) -> Result<Response, ServerError> {
+    let error_pages = &context.config.error_pages;
+    let page = context.config.doc_root_for(request.header("Host")).join("404.html");
+    if error_pages.get(404).is_none() && page.is_file() {
+        Ok(Response::new(404).header("Content-Type", HTML).body(read_file(&page)?))
+    } else {
+        Ok(error_pages.response(404, "Not found\n"))
+    }
+}
+
+// Maps the path of a request onto a file or directory under the document
+// root. Returns None when there is no such thing.
+//
+// Whoever sends the request picks the path, so it may well try to climb out
+// of the document root with "..", as in /../../etc/passwd. The path is
+// resolved in two steps to make sure the result stays inside:
+// - "." and ".." are worked out by hand first, without looking at the disk.
+//   A ".." going above the root is refused straight away, whether the file
+//   exists or not
+// - .canonicalize() then asks the OS for the real, absolute path of the
+//   file, following any symbolic links; a link pointing outside the root is
+//   refused as well
+fn resolve_path(root: &Path, path: &str) -> Result<Option<PathBuf>, ServerError> {
+    let mut relative = PathBuf::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {},
+            // .pop() returns false when there is nothing left to remove,
+            // i.e. we are at the root already
+            ".." => if !relative.pop() {
+                return Err(ServerError::Forbidden);
+            },
+            part => relative.push(part),
+        }
+    }
+
+    // .canonicalize() fails when the path does not exist. A document root
+    // which does not exist has no files to serve
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(_) => return Ok(None),
+    };
+    let full = match root.join(&relative).canonicalize() {
+        Ok(full) => full,
+        Err(_) => return Ok(None),
+    };
+    // .starts_with() on paths compares whole components, so /srv/www-secret
+    // does not count as being inside /srv/www
+    if !full.starts_with(&root) {
+        return Err(ServerError::Forbidden);
+    }
+
+    Ok(Some(full))
+}
+
+// An ETag ("entity tag") names one version of a file: if the file changes,
+// so does its ETag. Hashing the contents would mean reading the whole file
+// on every request, so, like nginx, we use its size and the time it was
+// last modified instead, in hex, e.g. "5f1e2d3c4b5a6978-1a2b". The quotes
+// are part of the ETag
+fn etag_for(metadata: &fs::Metadata) -> String {
+    let modified = metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", modified, metadata.len())
+}
+
+// Whether an If-None-Match header lists the ETag. The header holds either
+// "*", which matches any version, or a comma-separated list of ETags. A
+// "W/" in front marks a weak ETag, which only promises that the content is
+// equivalent; for If-None-Match that is good enough (RFC 7232, section 3.2)
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.trim() == "*" ||
+        if_none_match.split(',')
+            .map(|candidate| candidate.trim())
+            .any(|candidate| candidate.trim_start_matches("W/") == etag)
+}
+
+// Whether a file last modified at `modified` is unchanged since the date in
+// an If-Modified-Since header. HTTP dates only go down to the second, so the
+// fraction of a second of the file's time is left out of the comparison. A
+// date we cannot make sense of could be anything, so the file counts as
+// modified, and the client gets all of it
+fn not_modified_since(modified: SystemTime, if_modified_since: &str) -> bool {
+    let modified = modified.duration_since(UNIX_EPOCH).map(|since| since.as_secs());
+    let since = parse_http_date(if_modified_since)
+        .and_then(|since| since.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs());
+    match (modified, since) {
+        (Ok(modified), Some(since)) => modified <= since,
+        _ => false,
+    }
+}
+
+// Files larger than this are not read into memory to be served, see
+// serve_file()
+const STREAM_BYTES: u64 = 1024 * 1024;
+
+// A file, opened for reading from `offset` on. .seek() moves there without
+// reading what comes before
+fn file_from(path: &Path, offset: u64) -> io::Result<File> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(file)
+}
+
+// The 206 for a request asking for several ranges of the file at `path`,
+// `length` bytes long: a multipart/byteranges body with a part for every
+// range,
+//   --<boundary>
+//   Content-Type: text/plain; charset=utf-8
+//   Content-Range: bytes 0-99/1000
+//
+//   <the bytes>
+// and "--<boundary>--" after the last one. Like any range, the bytes are
+// copied from the file while the response is being sent: the body is a
+// reader going through every part in turn, see Read::chain()
+//
+// The boundary must not turn up in any of the parts. A random one makes that
+// as good as certain; RandomState, which HashMap uses against attacks on its
+// hashing, is seeded with random keys the first time it is used
+fn byteranges(path: &Path, ranges: &[(u64, u64)], content_type: &str, length: u64)
+              -> io::Result<Response> {
+    let boundary = format!("{:016x}", RandomState::new().build_hasher().finish());
+
+    let mut body: Box<dyn Read> = Box::new(io::empty());
+    let mut body_length = 0;
+    for &(first, last) in ranges {
+        let part = format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                           boundary, content_type, first, last, length);
+        body_length += part.len() as u64 + last - first + 1;
+        let bytes = file_from(path, first)?.take(last - first + 1);
+        body = Box::new(body.chain(io::Cursor::new(part)).chain(bytes));
+    }
+    let end = format!("\r\n--{}--\r\n", boundary);
+    body_length += end.len() as u64;
+    let body = body.chain(io::Cursor::new(end));
+
+    Ok(Response::new(206)
+       .header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary))
+       .file(body, body_length))
+}
+
+// Handles PUT /<path> when --allow-put is given, storing the body as the file
+// at that path under the document root: a 201 Created for a new file, and a
+// 204 No Content for one which was replaced.
+//
+// The directory the file goes in has to exist, and goes through
+// resolve_path() like any other path, as does the file if it is there
+// already: fs::write() follows links, and a link pointing outside the root
+// must not become a way of writing there
+fn put_file(request: &Request, context: &Context) -> Result<Response, ServerError> {
+    let root = context.config.doc_root_for(request.header("Host"));
+    let (directory, name) = request.path.rsplit_once('/').unwrap_or(("", &request.path));
+    if !is_valid_upload_name(name) {
+        return Err(ServerError::BadRequest("Invalid file name".to_owned()));
+    }
+    if let Some(response) = require_length(request) {
+        return Ok(response);
+    }
+
+    let directory = match resolve_path(root, directory)? {
+        Some(directory) if directory.is_dir() => directory,
+        _ => return not_found(request, context),
+    };
+    let path = directory.join(name);
+    let replaced = match fs::symlink_metadata(&path) {
+        Ok(_) => match resolve_path(root, &request.path)? {
+            Some(ref existing) if existing.is_file() => true,
+            // A directory, or a link to nowhere
+            _ => return Err(ServerError::Forbidden),
+        },
+        Err(_) => false,
+    };
+
+    fs::write(&path, &request.body)?;
+    Ok(if replaced {
+        Response::new(204)
+    } else {
+        Response::new(201)
+            .header("Location", &request.target)
+            .header("Content-Type", TEXT)
+            .body(format!("Stored {} bytes as {}\n", request.body.len(), request.path))
+    })
+}
+
+// What every GET (or HEAD) request which none of the routes match ends up
+// at: the file of the same name under the document root, if there is one.
+//
+// Every file comes with its ETag and the time it was last modified. A
+// client which already has the file sends one of these back, and gets a 304
+// Not Modified, without the file, if it has not changed since. The ETag is
+// the better test of the two: when both are sent, If-Modified-Since is
+// ignored (RFC 7232, section 6).
+//
+// A Range header asks for only part of the file, see range.rs, or several
+// parts of it, see byteranges(). Accept-Ranges lets clients know that they
+// may ask.
+//
+// A directory is served as the index.html in it. Without one, we list what
+// is in the directory if --autoindex was given, see autoindex.rs, and say
+// there is nothing there otherwise.
+//
+// Files larger than STREAM_BYTES, and ranges of them, are copied to the
+// connection as they are sent rather than read into memory first, see
+// Response::file(). Smaller whole files are read in full, which lets them be
+// compressed, and come out of the cache when there is one.
+//
+// A file may have a copy compressed ahead of time next to it, e.g. app.js.gz
+// for app.js, as the build of many a site leaves behind. A client which
+// accepts gzip gets that copy, which saves compressing the file over and over
+// and is often smaller too, as it could take its time. Anybody else, and
+// range requests, which are for ranges of the file itself, get the file.
+//
+// With --allow-put, a PUT request which none of the routes match stores a
+// file instead, see put_file()
+fn serve_file(request: &Request, context: &Context) -> Result<Response, ServerError> {
+    if request.method == Method::Put && context.config.allow_put {
+        return put_file(request, context);
+    }
+    if request.method != Method::Get && request.method != Method::Head {
+        return not_found(request, context);
+    }
+
+    let root = context.config.doc_root_for(request.header("Host"));
+    let path = match resolve_path(root, &request.path)? {
+        Some(path) => path,
+        None => return not_found(request, context),
+    };
+
+    // The path of the file to serve as the client would ask for it
+    let (path, file_path, content_type) = if path.is_dir() {
+        // Links in the page of /docs/ are relative to /docs/, but links in
+        // that of /docs would be relative to /, so the client is sent to the
+        // version with the "/" first
+        if !request.path.ends_with('/') {
+            let location = match request.target.split_once('?') {
+                Some((path, query)) => format!("{}/?{}", path, query),
+                None => format!("{}/", request.target),
+            };
+            return Response::redirect(301, &location);
+        }
+
+        // The index.html may be a link pointing outside the root, so it goes
+        // through resolve_path() as well
+        let index_path = format!("{}index.html", request.path);
+        match resolve_path(root, &index_path)? {
+            Some(index) if index.is_file() => (index, index_path, HTML),
+            _ if context.config.autoindex => {
+                return Ok(Response::new(200)
+                          .header("Content-Type", HTML)
+                          .body(autoindex::render(&path, &request.path)?));
+            },
+            _ => return not_found(request, context),
+        }
+    } else if path.is_file() {
+        // The first bytes tell what the extension does not
+        let content_type = match content_type_for(&request.path) {
+            mime::UNKNOWN => mime::sniff_file(&path)?,
+            content_type => content_type,
+        };
+        (path, request.path.clone(), content_type)
+    } else {
+        return not_found(request, context);
+    };
+
+    // Whatever keeps the copy from being served, a link pointing outside
+    // the root included, leaves the file itself
+    let precompressed = match resolve_path(root, &format!("{}.gz", file_path)) {
+        Ok(Some(gz)) if gz.is_file() => Some(gz),
+        _ => None,
+    };
+    let gzipped = precompressed.is_some() && request.header("Range").is_none()
+        && compress::accepts_gzip(request);
+    let path = match precompressed {
+        Some(ref gz) if gzipped => gz.clone(),
+        _ => path,
+    };
+
+    let metadata = fs::metadata(&path)?;
+    let etag = etag_for(&metadata);
+    let modified = metadata.modified()?;
+
+    let unchanged = match (request.header("If-None-Match"), request.header("If-Modified-Since")) {
+        (Some(if_none_match), _) => etag_matches(if_none_match, &etag),
+        (None, Some(since)) => not_modified_since(modified, since),
+        (None, None) => false,
+    };
+    let length = metadata.len();
+    let range = request.header("Range").and_then(|range| parse_range(range, length));
+    let response = match range {
+        _ if unchanged => Response::new(304),
+        Some(ByteRange::Satisfiable(first, last)) => Response::new(206)
+            .header("Content-Type", content_type)
+            .header("Content-Range", &format!("bytes {}-{}/{}", first, last, length))
+            .file(file_from(&path, first)?, last - first + 1),
+        Some(ByteRange::Multiple(ref ranges)) => byteranges(&path, ranges, content_type, length)?,
+        // The Content-Range of a 416 says how long the file is, so that the
+        // client can ask again for a range which makes sense
+        Some(ByteRange::Unsatisfiable) => Response::new(416)
+            .header("Content-Type", TEXT)
+            .header("Content-Range", &format!("bytes */{}", length))
+            .body("Range not satisfiable\n"),
+        None if length > STREAM_BYTES => Response::new(200)
+            .header("Content-Type", content_type)
+            .file(file_from(&path, 0)?, length),
+        None => {
+            let contents = match context.file_cache {
+                Some(cache) => cache.read(&path, &metadata)?,
+                None => read_file(&path)?,
+            };
+            Response::new(200).header("Content-Type", content_type).body(contents)
+        },
+    };
+    let response = response.header("Accept-Ranges", "bytes")
+        .header("ETag", &etag)
+        .header("Last-Modified", &http_date(modified));
+    // Caches have to know that the file depends on the Accept-Encoding of
+    // the request, whichever version was sent
+    let response = match precompressed {
+        Some(_) => response.header("Vary", "Accept-Encoding"),
+        None => response,
+    };
+    Ok(if gzipped { response.header("Content-Encoding", "gzip") } else { response })
+}
+
+// Every route the server knows about. Each one gets its own hit counter at
+// /debug/stats, named after its pattern. Anything else is looked up in the
+// document root
+fn routes(config: &ServerConfig) -> Router {
+    let mut router = Router::new(serve_file);
+    router.set_cors(config.cors.clone());
+    // CORS first, so that a page whose request was turned away for the lack
+    // of a password is allowed to see the 401
+    router.add_middleware(config.cors.clone());
+    if !config.basic_auth.is_empty() {
+        router.add_middleware(BasicAuth::new(&config.auth_realm, &config.basic_auth));
+    }
+    router.add_route(Method::Get, "/", index);
+    router.add_route(Method::Get, "/debug/stats", debug_stats);
+    router.add_route(Method::Get, "/metrics", metrics);
+    router.add_route(Method::Get, "/health", health);
+    // Unless turned off, so that a site can have a /healthz of its own
+    if config.healthz {
+        router.add_route(Method::Get, "/healthz", healthz);
+    }
+    router.add_route(Method::Get, "/favicon.ico", favicon);
+    router.add_route(Method::Get, "/fib", fib);
+    router.add_route(Method::Post, "/fib", fib_json);
+    router.add_route(Method::Get, "/fib/seq", fib_seq);
+    router.add_route(Method::Get, "/fib/:n", fib_path);
+    router.add_route(Method::Get, "/events/ticks", ticks);
+    router.add_route(Method::Get, "/whoami", whoami);
+    router.add_route(Method::Get, "/ws/echo", ws_echo);
+    router.add_route(Method::Get, "/old", old);
+    router.add_route(Method::Post, "/upload", upload_form);
+    router.add_route(Method::Put, "/upload/*", upload);
+    router
+}
+
+// Writes the interim response to a request with "Expect: 100-continue", see
+// read_body(), and nothing to any other. HTTP/1.0 has no interim responses,
+// and a client speaking it cannot be waiting for one
+fn send_continue<S: Read + Write>(reader: &mut BufReader<S>, request: &Request)
+                                  -> io::Result<()> {
+    let expects_continue = request.version != "HTTP/1.0" && request.header("Expect")
+        .map(|expect| expect.trim().eq_ignore_ascii_case("100-continue"))
+        .unwrap_or(false);
+    if expects_continue {
+        let stream = reader.get_mut();
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+// The most we are willing to read of the request line and headers together.
+// Without a limit, a client sending an endless header would have us fill
+// up memory with it
+const MAX_HEAD_BYTES: u64 = 8 * 1024;
+
+// Reads the request line and headers, i.e. everything up to and including the
+// empty line which ends them, and not a byte further: the body, if any, is
+// left in the reader.
+//
+// BufRead is implemented by readers with a buffer, like BufReader, and is
+// what gives us .read_until(): it reads up to and including the given byte,
+// however many reads of the underlying stream that takes. .take() makes the
+// reader report the end of the data after MAX_HEAD_BYTES bytes, so a head
+// which is too long comes back cut short, without its empty line. The
+// request line gets a tighter limit of its own, max_line bytes, and one
+// which is too long comes back cut short, without its "\n", and nothing
+// after it.
+//
+// The bytes are added to `head` as they arrive, so that whatever came before
+// an error, e.g. a read timing out, is still there for the caller to look at.
+// There are fewer of them, possibly none at all, if the client stops sending
+fn read_head<R: BufRead>(reader: &mut R, head: &mut Vec<u8>, max_line: u64)
+                         -> io::Result<()> {
+    let mut limited = reader.take(MAX_HEAD_BYTES);
+    loop {
+        let start = head.len();
+        let read = if start == 0 {
+            limited.by_ref().take(max_line).read_until(b'\n', head)?
+        } else {
+            limited.read_until(b'\n', head)?
+        };
+        // Stopping short of a "\n" means there is nothing more: the client
+        // closed its end of the connection, or we hit a limit
+        if read == 0 || !head.ends_with(b"\n") {
+            break;
+        }
+        // Clients are supposed to end lines with "\r\n", but some only send
+        // "\n". RFC 7230 (section 3.5) lets a server take that for a line
+        // end as well, and we do, adding the "\r" which Request::parse()
+        // expects. The head may come out a little longer than it was sent
+        if !head[start..].ends_with(b"\r\n") {
+            let end = head.len() - 1;
+            head.insert(end, b'\r');
+        }
+        if &head[start..] == b"\r\n" {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Reads the body of the request. Content-Length says how many bytes of it
+// follow the headers; a request without the header has no body. A client
+// which sends fewer bytes than it promised, either closing the connection or
+// going quiet until the read timeout, sent a bad request.
+//
+// Instead of a Content-Length, the client may send the body in chunks, with
+// "Transfer-Encoding: chunked", see chunked.rs. Sending both is refused: a
+// proxy in front of us which believed the other header would see a
+// different request than we do ("request smuggling"). Transfer codings other
+// than chunked, such as gzip, are not something we do.
+//
+// Bodies are kept in memory, so anything larger than config.max_body_bytes
+// is refused with a 413 before we read any of it. The connection is closed
+// afterwards, as it is after any error, so the rest of the body is never read.
+//
+// A client sending "Expect: 100-continue" waits for us to say "go ahead"
+// before it sends the body, so that it does not send a large body only to
+// have it refused. We say so once we know we are not going to refuse it,
+// with an interim "100 Continue" response ahead of the real one. Writing it
+// takes the stream itself, not just a reader, and a BufReader hands us the
+// stream it reads from with .get_mut()
+fn read_body<S: Read + Write>(reader: &mut BufReader<S>, request: &Request,
+                              config: &ServerConfig) -> Result<Vec<u8>, ServerError> {
+    if let Some(transfer_encoding) = request.header("Transfer-Encoding") {
+        if request.header("Content-Length").is_some() {
+            return Err(ServerError::BadRequest(
+                "Transfer-Encoding and Content-Length cannot go together".to_owned()));
+        }
+        if !transfer_encoding.trim().eq_ignore_ascii_case("chunked") {
+            return Err(ServerError::NotImplemented(
+                format!("Transfer-Encoding not implemented: {}", transfer_encoding)));
+        }
+        // How long a chunked body will be is anybody's guess, so it is
+        // checked against the limit as it comes in
+        send_continue(reader, request)?;
+        return chunked::read_chunked(reader, config.max_body_bytes);
+    }
+
+    let length: u64 = match request.header("Content-Length").map(str::parse) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => {
+            return Err(ServerError::BadRequest("Invalid Content-Length".to_owned()))
+        },
+        None => return Ok(Vec::new()),
+    };
+    if length > config.max_body_bytes {
+        return Err(ServerError::TooLarge);
+    }
+    if length > 0 {
+        send_continue(reader, request)?;
+    }
+
+    // .take() stops exactly at the end of the body. Whatever the client sent
+    // past it is not part of this request
+    let mut body = Vec::with_capacity(length as usize);
+    match reader.take(length).read_to_end(&mut body) {
+        Ok(_) => {},
+        // Running into the read timeout is an error of its own, but here
+        // it just means the rest of the body is not coming
+        Err(ref e) if matches!(e.kind(), io::ErrorKind::TimedOut
+                               | io::ErrorKind::WouldBlock) => {},
+        Err(e) => return Err(e.into()),
+    }
+    if (body.len() as u64) < length {
+        return Err(ServerError::BadRequest(
+            "Request body is shorter than Content-Length".to_owned()));
+    }
+    Ok(body)
+}
+
+// The parts of the server which every connection shares, and which, unlike
+// the configuration, stay the same for as long as the server runs
+struct Server {
+    stats: RouteStats,
+    metrics: Metrics,
+    router: Router,
+    access_log: AccessLog,
+    // When the server started up, and the same by the wall clock
+    started: Instant,
+    started_at: SystemTime,
+    // The TLS configuration when serving HTTPS, see tls.rs
+    tls: Option<Arc<rustls::ServerConfig>>,
+    // Set with --rate-limit
+    rate_limiter: Option<RateLimiter>,
+    // Set with --cache-ttl
+    file_cache: Option<FileCache>,
+    // How many request IDs have been handed out, see request_id()
+    request_ids: AtomicU64,
+    // How many connections are open, see ConnectionSlot
+    connections: AtomicUsize,
+    // Set with --max-in-flight
+    in_flight: Option<Semaphore>,
+    // The buffers requests are read into, see handle_request()
+    buffers: BufferPool,
+}
+
+impl Server {
+    fn new(config: &ServerConfig, access_log: AccessLog,
+           tls: Option<Arc<rustls::ServerConfig>>) -> Server {
+        Server {
+            stats: RouteStats::new(),
+            metrics: Metrics::new(),
+            router: routes(config),
+            access_log,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            tls,
+            rate_limiter: config.rate_limit.map(|rate| {
+                RateLimiter::new(rate, config.rate_burst.unwrap_or(rate.ceil() as u32))
+            }),
+            file_cache: config.cache_ttl.map(FileCache::new),
+            request_ids: AtomicU64::new(0),
+            connections: AtomicUsize::new(0),
+            in_flight: config.max_in_flight.map(Semaphore::new),
+            // A worker reads one request at a time, so there is no use for
+            // more buffers than workers. A head of up to MAX_HEAD_BYTES may
+            // have had its buffer grow to twice that
+            buffers: BufferPool::new(config.threads, 2 * MAX_HEAD_BYTES as usize),
+        }
+    }
+}
+
+// Counts a connection as open for as long as it is alive. Decrementing the
+// count in Drop means it happens however handling the connection ends, a
+// panic included
+struct ConnectionSlot(Arc<Server>);
+
+impl ConnectionSlot {
+    fn take(server: &Arc<Server>) -> ConnectionSlot {
+        server.connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionSlot(Arc::clone(server))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Answers a connection we have no room for with a 503, without reading its
+// request, and closes it. This happens on the thread accepting connections,
+// or on a worker which has others waiting behind it, so the write gets a
+// short timeout: a client which does not read its response must not hold up
+// everybody else's connections. Over HTTPS, where
+// even a 503 would take a TLS handshake, the connection is just closed
+fn turn_away(mut stream: TcpStream, config: &ServerConfig, server: &Server) {
+    if server.tls.is_some() {
+        return;
+    }
+    let response = config.error_pages.response(503, "Too many connections, try again later\n")
+        .header("Retry-After", "1")
+        .header("Connection", "close");
+    let sent = stream.set_write_timeout(Some(Duration::from_secs(1)))
+        .and_then(|_| response.write_to(&mut stream, false));
+    if let Ok(bytes) = sent {
+        server.metrics.record(503, bytes);
+    }
+
+    // Closing a connection with some of the request still unread has the OS
+    // reset it, and the client may lose the 503 along with it. A connection
+    // which waited for a slot has most likely sent its request by now, so
+    // what has arrived is read and thrown away first, without waiting for
+    // more, and up to a point
+    let mut scratch = [0; 4096];
+    if stream.set_nonblocking(true).is_ok() {
+        for _ in 0..16 {
+            match stream.read(&mut scratch) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {},
+            }
+        }
+    }
+}
+
+// The ID of a request, sent back as X-Request-Id and written to the access
+// log. A client, or a proxy in front of us, which has given the request an
+// ID of its own already has it kept, so that the request can be followed
+// from one server to the next. An ID which is not a short run of printable
+// characters is not worth keeping, or putting in a log.
+//
+// Our own IDs are the time the server started, in seconds since the Unix
+// epoch, and a counter, e.g. "6553f100-00000007": unique for as long as the
+// server runs, and unlikely to repeat after a restart
+fn request_id(request: Option<&Request>, server: &Server) -> String {
+    let given = request.and_then(|request| request.header("X-Request-Id"))
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()));
+    if let Some(id) = given {
+        return id.to_owned();
+    }
+
+    let started = server.started_at.duration_since(UNIX_EPOCH).map(|since| since.as_secs())
+        .unwrap_or(0);
+    format!("{:08x}-{:08x}", started, server.request_ids.fetch_add(1, Ordering::Relaxed))
+}
+
+// The function takes ownership of "stream", and "stream" will go out of
+// scope and be deleted, closing the connection, when the function completes.
+//
+// HTTP/1.1 connections are "persistent": once a response has been sent, the
+// client may send another request over the same connection, saving the cost
+// of opening a new one. So we keep answering requests until the client says
+// it is done with the connection, closes it, or leaves it idle for longer
+// than config.idle_timeout.
+//
+// Problems with the connection itself, such as the client going away halfway
+// through, are returned to the caller: there is nobody left to answer to.
+// Everything else becomes an error response sent to the client
+//
+// The configuration and the server, on the other hand, are only borrowed:
+// they belong to main() and have to outlive every connection
+fn handle_connection(stream: TcpStream, config: &ServerConfig, server: &Server)
+                     -> Result<(), ServerError> {
+    // Reads which wait longer than this fail with a timeout instead of
+    // blocking forever. This stops a client which connects and then says
+    // nothing from keeping one of the workers busy for good; serve() swaps
+    // in config.idle_timeout while waiting for the requests after the first
+    stream.set_read_timeout(Some(config.read_timeout))?;
+
+    // .peer_addr() can fail if the client is already gone, in which case we
+    // report the unspecified address 0.0.0.0
+    let peer = stream.peer_addr()
+        .map(|address| address.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+    // With TLS the requests are read from, and the responses written to, the
+    // TLS stream wrapped around the connection. Once we are done, the client
+    // is told that the end of the connection is really the end, and not
+    // somebody cutting it short (the "close_notify" alert)
+    match server.tls {
+        // The TLS stream takes the connection for its own, so the timeouts
+        // are set through a second handle to the same socket
+        Some(ref tls) => {
+            let socket = stream.try_clone()?;
+            let mut stream = tls::accept(tls, stream)?;
+            serve(&mut stream, &socket, peer, config, server)?;
+            stream.conn.send_close_notify();
+            stream.flush()?;
+            Ok(())
+        },
+        None => serve(&stream, &stream, peer, config, server),
+    }
+}
+
+// Answers the requests coming over a connection, plain or TLS, until it is
+// time to close it. Read and Write are all it takes: &TcpStream implements
+// both, and so does &mut TlsStream.
+//
+// Reading from a stream directly asks the OS for more data every time, and a
+// single read may return anything from one byte to several requests' worth.
+// A BufReader reads in large chunks and hands them out bit by bit. The
+// stream inside it can still be written to with .get_mut().
+//
+// The same reader is used for every request on the connection: whatever it
+// has buffered past the end of one request is the start of the next.
+//
+// Between requests the connection is idle, and gets config.idle_timeout to
+// start the next one, .fill_buf() waiting for its first bytes. Once they are
+// in, the rest of the request is read within config.read_timeout as usual.
+// A client which lets the idle timeout run out, or hangs up in the meantime,
+// has the connection closed without a word: it was not in the middle of
+// anything
+fn serve<S: Read + Write>(stream: S, socket: &TcpStream, peer: IpAddr,
+                          config: &ServerConfig, server: &Server)
+                          -> Result<(), ServerError> {
+    let mut reader = BufReader::new(stream);
+    while handle_request(&mut reader, peer, config, server)? {
+        socket.set_read_timeout(Some(config.idle_timeout))?;
+        match reader.fill_buf() {
+            Ok([]) => return Ok(()),
+            Ok(_) => {},
+            Err(e) => return match ServerError::from(e) {
+                ServerError::Timeout => Ok(()),
+                e => Err(e),
+            },
+        }
+        socket.set_read_timeout(Some(config.read_timeout))?;
+    }
+    Ok(())
+}
+
+// Reads one request from the connection and answers it. Returns whether the
+// connection should be kept open for another request
+fn handle_request<S: Read + Write>(reader: &mut BufReader<S>, peer: IpAddr,
+                                   config: &ServerConfig, server: &Server)
+                                   -> Result<bool, ServerError> {
+    // "?" hands any error back to our caller. A client which sends nothing,
+    // or nothing more, before hanging up or going quiet gets nothing in
+    // return: the connection is simply closed. One which went quiet halfway
+    // through a request is told that it took too long, with a 408 Request
+    // Timeout
+    //
+    // The buffer comes from the server's pool, and goes back to it when the
+    // request is done
+    let mut head = server.buffers.take();
+    let mut timed_out = false;
+    if let Err(e) = read_head(reader, &mut head, config.max_request_line) {
+        match ServerError::from(e) {
+            ServerError::Timeout => timed_out = true,
+            e => return Err(e),
+        }
+    }
+    if head.is_empty() {
+        return Ok(false);
+    }
+
+    // For the access log; Instant is a monotonic clock, fit for measuring how
+    // long something took, while SystemTime is the wall-clock time. The clock
+    // starts once the request has arrived, so the time a connection spends
+    // idle is not counted
+    let received_at = SystemTime::now();
+    let timer = Instant::now();
+
+    // ::from_utf8_lossy() takes a chunk of bytes representing utf-8 encoded
+    // unicode text and produces a string, replacing invalid utf-8 sequences
+    // with the unicode replacement character �
+    if config.log_level >= LogLevel::Debug {
+        println!("= Read {} bytes.", head.len());
+        println!("= Request:\n{}\n", String::from_utf8_lossy(&head));
+    }
+
+    // A head which filled up the whole limit without reaching the empty line
+    // was cut short by read_head(), and so was a request line which filled
+    // up its own. The "\r"s read_head() adds may take a head past the limit
+    let mut parsed = if head.len() as u64 == config.max_request_line && !head.contains(&b'\n') {
+        Err(ParseError::RequestLineTooLong)
+    } else if head.len() as u64 >= MAX_HEAD_BYTES && !head.ends_with(b"\r\n\r\n") {
+        Err(ParseError::HeadersTooLong)
+    } else {
+        Request::parse(&head)
+    };
+
+    // RFC 7230 makes the Host header mandatory for HTTP/1.1, so a request
+    // without one is rejected before we even look at what it asks for. Older
+    // HTTP/1.0 clients are allowed to leave it out. Everything else is up to
+    // the router, which picks the handler for the request; see routes().
+    //
+    // The whole thing is wrapped in a closure and run by catch_unwind(): if
+    // anything in there panics, e.g. because of a bug in a route, we get back
+    // an Err instead of the panic tearing down the whole server, and can tell
+    // the client about it with a 500. AssertUnwindSafe is our promise to the
+    // compiler that nothing we borrow is left in a broken state by a panic.
+    //
+    // Routes which can fail return a Result; a failure, like a request we
+    // could not parse or a missing file, comes out of the closure as an
+    // Err(ServerError)
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        // Clients which are not let in get told so before anything else, not
+        // even whether their request made sense. This is about who is at the
+        // other end of the connection: X-Forwarded-For is anybody's to send
+        if !config.access.allows(peer) {
+            return Err(ServerError::Forbidden);
+        }
+        if timed_out {
+            return Err(ServerError::Timeout);
+        }
+        // .clone() because the closure only borrows "parsed", and the error
+        // needs to be moved into the ServerError
+        let request = match parsed {
+            Ok(ref mut request) => request,
+            Err(ref e) => return Err(ServerError::from(e.clone())),
+        };
+        // Before the body is read: a client over the limit does not get to
+        // make us do even that. Behind a proxy, the client is whoever the
+        // proxy says it is, not the proxy itself
+        if let Some(ref limiter) = server.rate_limiter {
+            limiter.check(client_ip(peer, Some(request), config))
+                .map_err(ServerError::TooManyRequests)?;
+        }
+        // Before the body too, so that a client expecting a 100 Continue
+        // hears about the missing header instead
+        if request.version == "HTTP/1.1" && request.header("Host").is_none() {
+            return Err(ServerError::BadRequest(
+                "HTTP/1.1 requests must have a Host header".to_owned()));
+        }
+        request.body = read_body(reader, request, config)?;
+        let context = Context {
+            config,
+            stats: &server.stats,
+            metrics: &server.metrics,
+            file_cache: server.file_cache.as_ref(),
+            started: server.started,
+            client_ip: client_ip(peer, Some(&*request), config),
+        };
+        server.router.dispatch(request, &context)
+    }));
+
+    // The payload of a panic can be of any type, hence Box<dyn Any>. The
+    // message of a panic!() is either a &'static str or a String, depending on
+    // whether it had any formatting arguments, so we try both with
+    // .downcast_ref(), which returns None when the type does not match
     //
-    // It automatically panics if the formatting trait implementation returns an
-    // error
-    let response = format!("{}{}", status_line, content);
-    println!("= Response:\n{}\n", response);
-
-    // .as_bytes returns a non-mutable reference to a byte slice containing the
-    // byte representation of the String slice
-    stream.write(response.as_bytes()).unwrap();
+    // After an error we cannot tell whether all of the request was read, so
+    // what follows on the connection may well be the rest of it rather than
+    // a new request. The only safe thing to do is to close the connection
+    let (response, keep_alive) = match outcome {
+        Ok(Ok(response)) => {
+            let keep_alive = parsed.as_ref().map(wants_keep_alive).unwrap_or(false);
+            (response, keep_alive)
+        },
+        Ok(Err(error)) => (error_response(&error, config), false),
+        Err(payload) => {
+            let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                message.to_string()
+            } else if let Some(message) = payload.downcast_ref::<String>() {
+                message.clone()
+            } else {
+                "unknown panic".to_owned()
+            };
+
+            // Panic messages may mention file paths or other internals, so
+            // they are only shown when the debug flag is set
+            let body = if config.debug {
+                format!("Internal server error\n\nHandler panicked: {}\n", message)
+            } else {
+                "Internal server error\n".to_owned()
+            };
+            (config.error_pages.response(500, &body), false)
+        },
+    };
+
+    // Any response may be compressed, errors included, as long as the client
+    // said it can take it. HTTP/1.0 has no chunks, so a chunked body goes to
+    // HTTP/1.0 clients without them, and the connection is closed to end it
+    let response = match parsed {
+        Ok(ref request) if request.version == "HTTP/1.0" => {
+            compress::gzip(request, response.unchunked())
+        },
+        Ok(ref request) => compress::gzip(request, response),
+        Err(_) => response,
+    };
+
+    let request_id = request_id(parsed.as_ref().ok(), server);
+    let response = response.header("X-Request-Id", &request_id);
+
+    // The Connection header tells the client what we decided. Keep-Alive is
+    // only informative: how long we are going to wait for its next request.
+    // A connection switching to another protocol is neither kept for HTTP
+    // nor closed just yet. One whose body ends with it cannot be kept
+    let (mut response, keep_alive) = if response.is_upgrade() {
+        (response.header("Connection", "Upgrade"), false)
+    } else if keep_alive && !response.is_until_close() {
+        (response.header("Connection", "keep-alive")
+            .header("Keep-Alive", &format!("timeout={}", config.idle_timeout.as_secs())),
+         keep_alive)
+    } else {
+        (response.header("Connection", "close"), false)
+    };
+
+    // The answer to a HEAD request is the one to a GET without the body: the
+    // headers still say what the body would have been, Content-Length
+    // included, which is why the response was put together in full
+    let head_only = parsed.as_ref().map(|request| request.method == Method::Head)
+        .unwrap_or(false);
+
+    if config.log_level >= LogLevel::Debug {
+        // Binary bodies such as the favicon would only print as garbage, and
+        // a chunked one, or one from a file, is not there yet
+        let body: &[u8] = if head_only { &[] } else { response.body_bytes() };
+        match std::str::from_utf8(body) {
+            _ if response.is_chunked() && !head_only => {
+                println!("= Response:\n{}<chunked body>\n", response.head())
+            },
+            _ if response.is_file() && !head_only => {
+                println!("= Response:\n{}<body from a file>\n", response.head())
+            },
+            Ok(text) => println!("= Response:\n{}{}\n", response.head(), text),
+            Err(_) => println!("= Response:\n{}<{} bytes of binary data>\n",
+                               response.head(), body.len()),
+        }
+    }
+
+    // .write_to() consumes the response, so that a chunked body's producer
+    // can be called. The stream is the one the reader reads from
+    let upgrade = response.take_upgrade();
+    let status = response.status();
+    let stream = reader.get_mut();
+    let bytes = response.write_to(stream, head_only)?;
     // Flush the stream as it is buffered. Flushing means: "output all the data
-    // (in this case text) which you have accmmulated from me using .write
+    // (in this case text) which you have accmmulated from me using .write_all
     // on you".
     // If we did not do this, the data would be flushed at another point in time
-    // and not right after the call to the above .write()
-    stream.flush().unwrap();
+    // and not right after the call to the above .write_all()
+    stream.flush()?;
+    server.metrics.record(status, bytes);
+
+    if config.log_level >= LogLevel::Info {
+        // A request we could not parse has no method or path to speak of
+        let request = parsed.as_ref().ok();
+        let (method, path, version) = match request {
+            Some(request) => (request.method.as_str(), request.target.as_str(),
+                              request.version.as_str()),
+            None => ("-", "-", "-"),
+        };
+        let entry = Entry {
+            time: received_at,
+            ip: client_ip(peer, request, config),
+            method,
+            path,
+            version,
+            status,
+            bytes,
+            duration: timer.elapsed(),
+            referer: request.and_then(|request| request.header("Referer")),
+            user_agent: request.and_then(|request| request.header("User-Agent")),
+            request_id: &request_id,
+        };
+        server.access_log.write(&entry, config.log_format);
+    }
+
+    // The connection now belongs to the other protocol, until it is done
+    // with it. It still has the read timeout, so a client which goes quiet
+    // for that long is disconnected, as it would be over HTTP
+    if let Some(upgrade) = upgrade {
+        upgrade(&mut Upgraded(reader))?;
+    }
+
+    Ok(keep_alive)
+}
+
+// The connection as handed over after a 101 Switching Protocols. Reading goes
+// through the BufReader, which may have read some of what the client sent
+// after the request already, and writing straight to the stream
+struct Upgraded<'a, S: 'a>(&'a mut BufReader<S>);
+
+impl<'a, S: Read + Write> Read for Upgraded<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<'a, S: Read + Write> Write for Upgraded<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+
+// Whether the client would like to send more requests on the connection.
+// HTTP/1.1 connections stay open unless the client says "Connection: close";
+// HTTP/1.0 ones are closed unless it asks for "Connection: keep-alive". The
+// header is a comma-separated list of options, matched without regard to case
+fn wants_keep_alive(request: &Request) -> bool {
+    let has_option = |option: &str| {
+        request.header("Connection")
+            .map(|value| value.split(',').any(|o| o.trim().eq_ignore_ascii_case(option)))
+            .unwrap_or(false)
+    };
+
+    if request.version == "HTTP/1.1" {
+        !has_option("close")
+    } else {
+        has_option("keep-alive")
+    }
+}
+
+// TcpListener::bind() creates, binds and starts listening on a socket all in
+// one go, always asking the OS for a backlog of 128 pending connections. To
+// choose the backlog ourselves we go through the same steps by hand with the
+// socket2 crate, and then turn the result into a plain std TcpListener
+fn bind(config: &ServerConfig) -> io::Result<TcpListener> {
+    // The address may be a host name, which can resolve to more than one
+    // address; we simply take the first one
+    let address = config.address.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Address resolved to nothing"))?;
+
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    // Like std does, allow binding to an address which still has connections
+    // lingering in the TIME_WAIT state, so that restarting the server works
+    // straight away
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    // The OS API takes a signed int; anything that does not fit is clamped.
+    // Most platforms also silently cap the value to a system-wide maximum
+    // (e.g. net.core.somaxconn on Linux)
+    socket.listen(config.backlog.min(i32::MAX as u32) as i32)?;
+
+    // .into() uses the From<Socket> implementation for TcpListener
+    Ok(socket.into())
+}
+
+// Whether the error means the client closed the connection on us: writing to
+// a socket the other end has closed fails with BrokenPipe, or with
+// ConnectionReset if the client's OS answered with a reset. Rust ignores the
+// SIGPIPE signal which would otherwise kill the process in that situation,
+// so it reaches us as a plain error
+fn is_disconnect(error: &io::Error) -> bool {
+    // matches!() is a shorthand for a match which returns true for the listed
+    // patterns and false for anything else
+    matches!(error.kind(),
+             io::ErrorKind::BrokenPipe
+             | io::ErrorKind::ConnectionReset
+             | io::ErrorKind::ConnectionAborted)
+}
+
+// How often the accept loop wakes up, when there are no connections, to
+// check whether a signal asked for a reload or a shutdown
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+// How long to wait before accepting again after running out of file
+// descriptors
+const FD_BACKOFF: Duration = Duration::from_millis(100);
+
+// Decides what the accept loop does after a failed accept: None to carry on
+// straight away, or how long to sleep first.
+//
+// Every connection uses up one file descriptor, and the OS limits how many a
+// process (EMFILE) or the whole system (ENFILE) may have open at once. Once
+// the limit is reached accept() fails immediately, and keeps failing until
+// some connection is closed: retrying right away would only spin the CPU.
+// Other accept errors, like a client hanging up before we got to it
+// (ECONNABORTED), only concern a single connection, so the next accept can
+// go ahead.
+//
+// .raw_os_error() is the error code the OS reported, if the error came from
+// the OS at all. std has no ErrorKind for these two, so we compare the codes
+// directly
+fn accept_backoff(error: &io::Error) -> Option<Duration> {
+    #[cfg(unix)]
+    {
+        if let Some(libc::EMFILE) | Some(libc::ENFILE) = error.raw_os_error() {
+            return Some(FD_BACKOFF);
+        }
+    }
+    // Keeps the compiler from warning about an unused argument elsewhere
+    let _ = error;
+    None
 }
 
 fn main() {
+    // The first argument is the path of the executable itself, which
+    // .skip(1) leaves out. The command line configuration is kept as it is,
+    // for every reload to apply the file to afresh
+    let args = ServerConfig::from_args(env::args().skip(1))
+        .and_then(|base| base.with_file().map(|config| (base, config)));
+    let (base, config) = match args {
+        Ok(configs) => configs,
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!("Usage: networking [--address ADDR] [--backlog N] \
+                       [--threads N] [--max-connections N] [--max-in-flight N [--slot-timeout SECS]] [--read-timeout SECS] [--idle-timeout SECS] [--max-body-bytes N] [--max-request-line N] [--root DIR] [--config FILE] [--trust-proxy] \
+                       [--log-format text|combined|json] [--access-log FILE] \
+                       [--cors-origin ORIGIN]... [--cors-methods LIST] \
+                       [--cors-headers LIST] [--cors-credentials] [--autoindex] [--allow-put] [--no-healthz] \
+                       [--tls-cert FILE --tls-key FILE] \
+                       [--rate-limit PER_SEC [--rate-burst N]] \
+                       [--allow CIDR]... [--deny CIDR]... [--cache-ttl SECS] \
+                       [--basic-auth USER:PASSWORD]... [--auth-realm NAME]");
+            process::exit(2);
+        },
+    };
+
     // Calling unwrap() causes the returned std::io::Result enum to be pattern
     // matched and
     // - in case of error, panic
@@ -96,36 +1612,1408 @@ fn main() {
     // - in case of success, return the value contained in the Ok variant, which
     //   in this case is a TcpListener instance
     //
-    // It is possible to just pass a string because ::bind() accepts any type
-    // which implements the ToSocketAddrs trait, and String slices do
-    // Slices are a
+    // The address in the configuration is a String; bind() resolves it with
+    // .to_socket_addrs(), which is implemented by any type implementing the
+    // ToSocketAddrs trait, and String slices do. Slices are a
     // convenient way of referring to portions of, in this case, Strings.
     // String literals are treated as String slices, that is "str"s and can
     // be thought of as a slice pointing to some section of the .text portion
     // of the binary
-    let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+    let listener = bind(&config).unwrap();
+    let pool = ThreadPool::new(config.threads);
+
+    let access_log = match config.access_log {
+        Some(ref path) => AccessLog::open(path).unwrap_or_else(|e| {
+            eprintln!("Cannot open the access log {}: {}", path.display(), e);
+            process::exit(1);
+        }),
+        None => AccessLog::stdout(),
+    };
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load(cert, key).unwrap_or_else(|message| {
+            eprintln!("{}", message);
+            process::exit(1);
+        })),
+        _ => None,
+    };
+
+    // The server is shared by all the worker threads, so it is kept in an
+    // Arc too. Nothing in it is ever replaced: the routes never change while
+    // the server runs, and the stats and the access log take care of their
+    // own locking
+    let server = Arc::new(Server::new(&config, access_log, tls));
+
+    // The configuration is kept in an Arc, a reference-counted pointer. A
+    // reload builds a whole new ServerConfig and swaps the Arc in one go, so
+    // a connection always sees either the old or the new configuration and
+    // never a mix of the two. Whoever still holds a clone of the old Arc keeps
+    // it alive until they are done with it
+    let mut config = Arc::new(config);
+
+    // The signal handler only flips this flag; the actual reload happens in
+    // the accept loop. Very little is allowed inside a signal handler (no
+    // allocations, no locks), which is why signal-hook does it this way
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGHUP,
+                                Arc::clone(&reload_requested)).unwrap();
+
+    // Ctrl-C (SIGINT) and SIGTERM, which is what service managers and `kill`
+    // send, ask for a graceful shutdown the same way. The conditional
+    // shutdown is registered first, so that it only sees the flag set from
+    // the second signal on: someone pressing Ctrl-C again because the
+    // shutdown takes too long gets the server to exit right away
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    for &signal in &[signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(
+            signal, 1, Arc::clone(&shutdown_requested)).unwrap();
+        signal_hook::flag::register(signal, Arc::clone(&shutdown_requested)).unwrap();
+    }
+
+    // A blocking accept() would only notice the flags once the next
+    // connection came in. In non-blocking mode accept() returns straight
+    // away, with a WouldBlock error when nobody is waiting, which lets the
+    // loop have a look at the flags every ACCEPT_POLL
+    listener.set_nonblocking(true).unwrap();
 
-    println!("Bound TCP listener socket at {} on port {}.\nListening...",
+    println!("Bound TCP listener socket at {} on port {} (backlog {}, {} threads).\n\
+              Listening...",
              listener.local_addr().unwrap().ip(),
-             listener.local_addr().unwrap().port());
-
-    // .incoming() returns an iterator of type std::net::Incoming which
-    // implements the required interface for the trait, .next(&mut self).
-    // Calling .next() (implicitly called by the for loop) on the iterator
-    // accepts another connection
-    for stream in listener.incoming() {
-        // Retrieve the stream from the iterator's result, which is (the result)
-        // of type enum Option<T>. As usual, .unwrap() will panic if the variant
-        // type is None
+             listener.local_addr().unwrap().port(),
+             config.backlog, config.threads);
+
+    loop {
+        if shutdown_requested.load(Ordering::SeqCst) {
+            break;
+        }
+
+        // .swap() reads the flag and clears it at the same time, so a SIGHUP
+        // arriving right now is not lost
+        if reload_requested.swap(false, Ordering::SeqCst) {
+            match base.with_file() {
+                Ok(reloaded) => {
+                    config = Arc::new(reloaded);
+                    println!("Configuration reloaded.");
+                },
+                Err(message) => {
+                    eprintln!("Keeping the old configuration: {}", message);
+                },
+            }
+        }
+
+        // .accept() returns an io::Result of the new connection and the
+        // address of the client. A failed accept is not a reason to stop
+        // serving everybody else, so it is logged and the loop moves on,
+        // after a pause if accept_backoff() says so
         //
         // stream will be of type TcpStream; this type represents a connection
         // between the host and the client and can be used to write to/read from
-        let stream = stream.unwrap();
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            },
+            Err(e) => {
+                match accept_backoff(&e) {
+                    Some(pause) => {
+                        eprintln!("Accept failed: {}; out of file descriptors, \
+                                   retrying in {}ms", e, pause.as_millis());
+                        thread::sleep(pause);
+                    },
+                    None => eprintln!("Accept failed: {}", e),
+                }
+                continue;
+            },
+        };
+
+        // On some platforms (not Linux) the connection inherits the
+        // listener's non-blocking mode. The workers expect reads and writes
+        // to wait for the client, as usual
+        if let Err(e) = stream.set_nonblocking(false) {
+            eprintln!("Dropping connection: {}", e);
+            continue;
+        }
+
+        // Only this thread ever adds to the count, so it cannot go past the
+        // limit between the check and ConnectionSlot::take()
+        let full = config.max_connections
+            .map(|max| server.connections.load(Ordering::SeqCst) >= max)
+            .unwrap_or(false);
+        if full {
+            if config.log_level >= LogLevel::Info {
+                println!("=== Turning a connection away, too many are open\n");
+            }
+            turn_away(stream, &config, &server);
+            continue;
+        }
+
+        if config.log_level >= LogLevel::Info {
+            println!("=== Connection established!\n");
+        }
+
+        // The connection is handled on one of the pool's threads, and the
+        // loop goes straight back to accepting the next one. "move" makes the
+        // closure take ownership of what it uses: the stream, and clones of
+        // the Arcs
+        let slot = ConnectionSlot::take(&server);
+        let config = Arc::clone(&config);
+        let server = Arc::clone(&server);
+        pool.execute(move || {
+            // Dropped, and the connection no longer counted, at the end
+            let _slot = slot;
+            // Held for as long as the connection is handled. A connection
+            // which does not get one in time is turned away, as one past
+            // --max-connections is
+            let _permit = match server.in_flight {
+                Some(ref in_flight) => match in_flight.acquire(config.slot_timeout) {
+                    Some(permit) => Some(permit),
+                    None => {
+                        if config.log_level >= LogLevel::Info {
+                            println!("=== Turning a connection away, it waited too long\n");
+                        }
+                        turn_away(stream, &config, &server);
+                        return;
+                    },
+                },
+                None => None,
+            };
+            match handle_connection(stream, &config, &server) {
+                Ok(()) => {},
+                // The client hung up before we were done with it. Nothing
+                // went wrong on our side, and the connection is gone anyway
+                Err(ServerError::Io(ref e)) if is_disconnect(e) => {
+                    if config.log_level >= LogLevel::Info {
+                        println!("Client disconnected: {}", e);
+                    }
+                },
+                Err(e) => eprintln!("Connection failed: {}", e),
+            }
+
+            if config.log_level >= LogLevel::Info {
+                println!("=== Closing connection.\n");
+            }
+        });
+    }
+
+    // No new connections from here on: the listening socket is closed, and
+    // clients trying to connect are refused. Dropping the pool waits for the
+    // connections being handled to finish (see ThreadPool's Drop); idle
+    // keep-alive connections are closed within config.idle_timeout
+    drop(listener);
+    println!("Shutting down, waiting for open connections to finish...");
+    drop(pool);
+    println!("Bye.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::*;
+
+    fn head_of(bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut reader = BufReader::new(bytes);
+        let mut head = Vec::new();
+        read_head(&mut reader, &mut head, 4096).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        (head, rest)
+    }
+
+    #[test]
+    fn read_head_leaves_the_body_in_the_reader() {
+        let (head, rest) = head_of(b"POST / HTTP/1.1\r\nHost: x\r\n\r\nbody");
+        assert_eq!(head, b"POST / HTTP/1.1\r\nHost: x\r\n\r\n".to_vec());
+        assert_eq!(rest, b"body".to_vec());
+    }
+
+    #[test]
+    fn read_head_turns_lone_lfs_into_crlfs() {
+        let (head, rest) = head_of(b"GET / HTTP/1.1\nHost: x\r\n\nnext");
+        assert_eq!(head, b"GET / HTTP/1.1\r\nHost: x\r\n\r\n".to_vec());
+        assert_eq!(rest, b"next".to_vec());
+    }
+
+    #[test]
+    fn reads_a_request_arriving_one_byte_at_a_time() {
+        let request = RequestBuilder::post("/fib")
+            .header("Content-Type", "application/json")
+            .body(r#"{"n": 10}"#);
+        let stream = MockStream::new(request.build_bytes()).one_byte_at_a_time();
+        let replies = Reply::parse_all(&answer(&config(), stream));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].body, br#"{"n":10,"value":55}"#.to_vec());
+    }
+
+    #[test]
+    fn answers_a_request_stalling_halfway_with_a_408() {
+        let stream = MockStream::new("GET /healthz HTTP/1.1\r\nHo").one_byte_at_a_time()
+            .then_stall();
+        let replies = Reply::parse_all(&answer(&config(), stream));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].status, 408);
+    }
+
+    #[test]
+    fn answers_a_body_stalling_halfway_with_a_400() {
+        let mut request = RequestBuilder::post("/fib")
+            .header("Content-Type", "application/json")
+            .body(r#"{"n": 10}"#)
+            .build_bytes();
+        request.truncate(request.len() - 3);
+        let stream = MockStream::new(request).one_byte_at_a_time().then_stall();
+        let replies = Reply::parse_all(&answer(&config(), stream));
+        assert_eq!(replies[0].status, 400);
+    }
+
+    #[test]
+    fn says_nothing_to_a_client_which_sends_nothing() {
+        assert!(answer(&config(), MockStream::new("")).is_empty());
+        assert!(answer(&config(), MockStream::new("").then_stall()).is_empty());
+    }
+
+    #[test]
+    fn answers_a_request_with_lone_lfs() {
+        let replies = exchange(&config(), "GET /healthz HTTP/1.1\nHost: x\n\n");
+        assert_eq!(replies[0].status, 200);
+    }
+
+    #[test]
+    fn reads_headers_longer_than_512_bytes_and_the_body_after_them() {
+        let request = RequestBuilder::post("/fib")
+            .header("X-Padding", &"x".repeat(2000))
+            .header("Content-Type", "application/json")
+            .body(r#"{"n": 10}"#);
+        let replies = exchange(&config(), request.build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].body, br#"{"n":10,"value":55}"#.to_vec());
+    }
+
+    #[test]
+    fn refuses_a_head_past_the_limit() {
+        let request = RequestBuilder::get("/healthz").header("X-Padding", &"x".repeat(9000));
+        let replies = exchange(&config(), request.build_bytes());
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].status, 400);
+    }
+
+    #[test]
+    fn refuses_an_http_1_1_request_without_host() {
+        let request = RequestBuilder::get("/healthz").without_header("Host");
+        let replies = exchange(&config(), request.build_bytes());
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].status, 400);
+        assert_eq!(replies[0].header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn answers_an_http_1_1_request_with_host() {
+        let replies = exchange(&config(), RequestBuilder::get("/healthz").build_bytes());
+        assert_eq!(replies[0].status, 200);
+    }
+
+    #[test]
+    fn answers_an_http_1_0_request_without_host() {
+        let request = RequestBuilder::get("/healthz").version("HTTP/1.0").without_header("Host");
+        let replies = exchange(&config(), request.build_bytes());
+        assert_eq!(replies[0].status, 200);
+    }
+
+    #[test]
+    fn counts_hits_by_route_not_by_path() {
+        let input = [RequestBuilder::get("/fib?n=10").build_bytes(),
+                     RequestBuilder::get("/fib?n=20").build_bytes(),
+                     RequestBuilder::get("/fib/3").build_bytes(),
+                     RequestBuilder::get("/debug/stats").build_bytes()].concat();
+        let replies = exchange(&config(), input);
+        assert_eq!(replies[3].body, b"/debug/stats 1\n/fib 2\n/fib/:n 1\n".to_vec());
+    }
+
+    #[test]
+    fn sends_a_date_with_every_response() {
+        let input = [RequestBuilder::get("/healthz").build_bytes(),
+                     RequestBuilder::get("/no/such/file").build_bytes(),
+                     b"garbage\r\n\r\n".to_vec()].concat();
+        let replies = exchange(&config(), input);
+        assert_eq!(replies.len(), 3);
+        for reply in &replies {
+            let date = reply.header("Date").expect("Response without a Date");
+            assert!(parse_http_date(date).is_some(), "Not an HTTP date: {}", date);
+        }
+    }
+
+    #[test]
+    fn serves_a_range_uncompressed_to_a_client_accepting_gzip() {
+        let root = TempDir::new();
+        let contents = "0123456789".repeat(200);
+        root.file("plain.txt", &contents);
+        root.file("packed.txt", &contents);
+        root.file("packed.txt.gz", b"\x1f\x8b not really gzip");
+        let mut config = config();
+        config.doc_root = root.path().to_owned();
+
+        for path in &["/plain.txt", "/packed.txt"] {
+            let request = RequestBuilder::get(path)
+                .header("Accept-Encoding", "gzip")
+                .header("Range", "bytes=10-19");
+            let replies = exchange(&config, request.build_bytes());
+            assert_eq!(replies[0].status, 206);
+            assert_eq!(replies[0].header("Content-Encoding"), None);
+            assert_eq!(replies[0].header("Content-Range"), Some("bytes 10-19/2000"));
+            assert_eq!(replies[0].body, b"0123456789".to_vec());
+        }
+    }
+
+    #[test]
+    fn a_precompressed_file_varies_on_both_encoding_and_origin() {
+        let root = TempDir::new();
+        root.file("app.js", "console.log(1);\n");
+        root.file("app.js.gz", b"\x1f\x8b not really gzip");
+        let mut config = config();
+        config.doc_root = root.path().to_owned();
+        config.cors.allowed_origins = vec!["https://example.com".to_owned()];
+
+        let request = RequestBuilder::get("/app.js")
+            .header("Accept-Encoding", "gzip")
+            .header("Origin", "https://example.com");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].header("Content-Encoding"), Some("gzip"));
+        assert_eq!(replies[0].header("Access-Control-Allow-Origin"), Some("https://example.com"));
+        assert_eq!(replies[0].header("Vary"), Some("Accept-Encoding, Origin"));
+    }
+
+    #[test]
+    fn health_answers_with_the_status_uptime_and_version() {
+        let replies = exchange(&config(), RequestBuilder::get("/health").build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert!(replies[0].header("Content-Type").unwrap().starts_with("application/json"));
+        let health: serde_json::Value = serde_json::from_slice(&replies[0].body).unwrap();
+        assert_eq!(health["status"], "ok");
+        assert_eq!(health["version"], VERSION);
+        assert!(health["uptime_secs"].is_u64());
+    }
+
+    #[test]
+    fn serves_the_built_in_favicon_unless_the_document_root_has_one() {
+        let root = TempDir::new();
+        let mut config = config();
+        config.doc_root = root.path().to_owned();
+        let replies = exchange(&config, RequestBuilder::get("/favicon.ico").build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].header("Content-Type"), Some(ICON));
+        assert_eq!(replies[0].body, DEFAULT_FAVICON.to_vec());
+
+        root.file("favicon.ico", b"not the default");
+        let replies = exchange(&config, RequestBuilder::get("/favicon.ico").build_bytes());
+        assert_eq!(replies[0].header("Content-Type"), Some(ICON));
+        assert_eq!(replies[0].body, b"not the default".to_vec());
+    }
+
+    fn panics(_: &Request, _: &Context) -> Result<Response, ServerError> {
+        panic!("Something broke in {}", "the handler")
+    }
+
+    fn fails(_: &Request, _: &Context) -> Result<Response, ServerError> {
+        Err(io::Error::other("disk on fire").into())
+    }
+
+    fn failing_server(config: &ServerConfig) -> Server {
+        let mut server = server(config);
+        server.router.add_route(Method::Get, "/panic", panics);
+        server.router.add_route(Method::Get, "/fail", fails);
+        server
+    }
+
+    #[test]
+    fn hides_the_cause_of_a_500_unless_in_debug_mode() {
+        let input = [RequestBuilder::get("/panic").build_bytes(),
+                     RequestBuilder::get("/fail").build_bytes()];
+        let config = config();
+        let server = failing_server(&config);
+        for request in &input {
+            let stream = MockStream::new(request.clone());
+            let replies = Reply::parse_all(&answer_on(&server, &config, stream));
+            assert_eq!(replies[0].status, 500);
+            assert_eq!(replies[0].body, b"Internal server error\n".to_vec());
+            assert_eq!(replies[0].header("Connection"), Some("close"));
+        }
+    }
+
+    #[test]
+    fn shows_the_cause_of_a_500_in_debug_mode() {
+        let config = ServerConfig { debug: true, ..config() };
+        let server = failing_server(&config);
+
+        let stream = MockStream::new(RequestBuilder::get("/panic").build_bytes());
+        let replies = Reply::parse_all(&answer_on(&server, &config, stream));
+        assert_eq!(replies[0].status, 500);
+        assert_eq!(String::from_utf8_lossy(&replies[0].body),
+                   "Internal server error\n\nHandler panicked: Something broke in the handler\n");
+
+        let stream = MockStream::new(RequestBuilder::get("/fail").build_bytes());
+        let replies = Reply::parse_all(&answer_on(&server, &config, stream));
+        assert_eq!(replies[0].status, 500);
+        assert_eq!(String::from_utf8_lossy(&replies[0].body),
+                   "Internal server error\n\nI/O error: disk on fire\n");
+    }
+
+    fn put(target: &str) -> RequestBuilder {
+        RequestBuilder::new(Method::Put, target)
+    }
+
+    #[test]
+    fn stores_a_put_upload_under_the_upload_dir() {
+        let uploads = TempDir::new();
+        let config = ServerConfig { upload_dir: uploads.path().join("in"), ..config() };
+        let request = put("/upload/notes.txt").header("Content-Type", "text/plain; charset=utf-8")
+            .body("some notes");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 201);
+        assert_eq!(replies[0].body,
+                   b"Stored 10 bytes of text/plain (utf-8) as notes.txt\n".to_vec());
+        assert_eq!(fs::read(uploads.path().join("in/notes.txt")).unwrap(), b"some notes");
+    }
+
+    #[test]
+    fn refuses_a_put_upload_without_a_length() {
+        let uploads = TempDir::new();
+        let config = ServerConfig { upload_dir: uploads.path().to_owned(), ..config() };
+        let replies = exchange(&config, put("/upload/notes.txt").build_bytes());
+        assert_eq!(replies[0].status, 411);
+        assert!(!uploads.path().join("notes.txt").exists());
+    }
+
+    #[test]
+    fn refuses_a_put_upload_with_a_name_pointing_elsewhere() {
+        let uploads = TempDir::new();
+        let config = ServerConfig { upload_dir: uploads.path().to_owned(), ..config() };
+        for target in &["/upload/", "/upload/..", "/upload/a%2Fb", "/upload/a%5Cb"] {
+            let replies = exchange(&config, put(target).body("x").build_bytes());
+            assert_eq!(replies[0].status, 400, "{}", target);
+        }
+    }
+
+    #[test]
+    fn refuses_a_put_upload_past_the_body_limit_with_a_413() {
+        let uploads = TempDir::new();
+        let config = ServerConfig {
+            upload_dir: uploads.path().to_owned(),
+            max_body_bytes: 4,
+            ..config()
+        };
+        let replies = exchange(&config, put("/upload/big").body("12345").build_bytes());
+        assert_eq!(replies[0].status, 413);
+        assert!(!uploads.path().join("big").exists());
+    }
+
+    #[test]
+    fn stores_a_put_under_the_document_root_with_allow_put() {
+        let root = TempDir::new();
+        root.file("docs/old.txt", "old");
+        let config = ServerConfig { doc_root: root.path().to_owned(), allow_put: true, ..config() };
+
+        let replies = exchange(&config, put("/docs/new.txt").body("new").build_bytes());
+        assert_eq!(replies[0].status, 201);
+        assert_eq!(replies[0].header("Location"), Some("/docs/new.txt"));
+        assert_eq!(fs::read(root.path().join("docs/new.txt")).unwrap(), b"new");
+
+        let replies = exchange(&config, put("/docs/old.txt").body("replaced").build_bytes());
+        assert_eq!(replies[0].status, 204);
+        assert!(replies[0].body.is_empty());
+        assert_eq!(fs::read(root.path().join("docs/old.txt")).unwrap(), b"replaced");
+    }
+
+    #[test]
+    fn refuses_a_put_under_the_document_root_it_cannot_store() {
+        let root = TempDir::new();
+        root.file("docs/index.html", "<p>Docs</p>");
+        let config = ServerConfig { doc_root: root.path().to_owned(), allow_put: true, ..config() };
+
+        let status = |request: RequestBuilder| exchange(&config, request.build_bytes())[0].status;
+        assert_eq!(status(put("/missing/new.txt").body("x")), 404);
+        assert_eq!(status(put("/docs").body("x")), 403);
+        assert_eq!(status(put("/../new.txt").body("x")), 403);
+        assert_eq!(status(put("/docs/new.txt")), 411);
+        assert!(!root.path().join("docs/new.txt").exists());
+    }
+
+    #[test]
+    fn ignores_a_put_under_the_document_root_without_allow_put() {
+        let root = TempDir::new();
+        let config = ServerConfig { doc_root: root.path().to_owned(), ..config() };
+        let replies = exchange(&config, put("/new.txt").body("x").build_bytes());
+        assert_eq!(replies[0].status, 404);
+        assert!(!root.path().join("new.txt").exists());
+    }
+
+    #[test]
+    fn sends_a_204_from_a_handler_without_a_body() {
+        let root = TempDir::new();
+        root.file("old.txt", "old");
+        let config = ServerConfig { doc_root: root.path().to_owned(), allow_put: true, ..config() };
+        let input = [put("/old.txt").body("new").build_bytes(),
+                     RequestBuilder::get("/healthz").build_bytes()].concat();
+        let replies = exchange(&config, input);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].status, 204);
+        assert_eq!(replies[0].header("Content-Length"), None);
+        assert_eq!(replies[1].status, 200);
+    }
+
+    #[test]
+    fn answers_only_the_first_request_of_a_connection_told_to_close() {
+        let input = [RequestBuilder::get("/healthz").header("Connection", "Close").build_bytes(),
+                     RequestBuilder::get("/healthz").build_bytes()].concat();
+        let replies = exchange(&config(), input);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn finds_close_among_the_connection_options() {
+        let request = |connection: &str| {
+            Request::parse(&RequestBuilder::get("/").header("Connection", connection)
+                           .build_bytes()).unwrap()
+        };
+        assert!(!wants_keep_alive(&request("TE, close")));
+        assert!(wants_keep_alive(&request("TE")));
+        assert!(wants_keep_alive(&request("closed")));
+    }
+
+    #[test]
+    fn keeps_an_http_1_1_connection_open_for_pipelined_requests() {
+        let input = [RequestBuilder::get("/fib?n=10").build_bytes(),
+                     RequestBuilder::post("/fib").header("Content-Type", "application/json")
+                         .body(r#"{"n": 11}"#).build_bytes(),
+                     RequestBuilder::get("/fib/12").build_bytes()].concat();
+        let config = ServerConfig { idle_timeout: Duration::from_secs(7), ..config() };
+        let replies = exchange(&config, input);
+        assert_eq!(replies.len(), 3);
+        for reply in &replies {
+            assert_eq!(reply.status, 200);
+            assert_eq!(reply.header("Connection"), Some("keep-alive"));
+            assert_eq!(reply.header("Keep-Alive"), Some("timeout=7"));
+        }
+        assert_eq!(replies[1].body, br#"{"n":11,"value":89}"#.to_vec());
+    }
+
+    #[test]
+    fn keeps_an_http_1_0_connection_open_only_when_asked_to() {
+        let request = || RequestBuilder::get("/healthz").version("HTTP/1.0");
+        let input = [request().header("Connection", "keep-alive").build_bytes(),
+                     request().build_bytes(),
+                     request().build_bytes()].concat();
+        let replies = exchange(&config(), input);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].header("Connection"), Some("keep-alive"));
+        assert_eq!(replies[1].header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn closes_the_connection_after_an_error() {
+        let input = [b"garbage\r\n\r\n".to_vec(),
+                     RequestBuilder::get("/healthz").build_bytes()].concat();
+        let replies = exchange(&config(), input);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].status, 400);
+        assert_eq!(replies[0].header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn whoami_answers_with_the_peer_address() {
+        let request = RequestBuilder::get("/whoami").header("X-Forwarded-For", "203.0.113.7");
+        let replies = exchange(&config(), request.build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].body, b"127.0.0.1\n".to_vec());
+    }
+
+    #[test]
+    fn whoami_believes_x_forwarded_for_only_with_trust_proxy() {
+        let config = ServerConfig { trust_proxy: true, ..config() };
+        let request = RequestBuilder::get("/whoami")
+            .header("X-Forwarded-For", " 2001:db8::1 , 10.0.0.1");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].body, b"2001:db8::1\n".to_vec());
+
+        let request = RequestBuilder::get("/whoami").header("X-Forwarded-For", "not an address");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].body, b"127.0.0.1\n".to_vec());
+    }
+
+    #[test]
+    fn answers_fib_up_to_the_largest_n_and_400_past_it() {
+        let replies = exchange(&config(), RequestBuilder::get("/fib?n=93").build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].body, b"12200160415121876738\n".to_vec());
+
+        for target in &["/fib?n=94", "/fib/94"] {
+            let replies = exchange(&config(), RequestBuilder::get(target).build_bytes());
+            assert_eq!(replies[0].status, 400);
+            assert_eq!(replies[0].body, b"n can be at most 93\n".to_vec());
+        }
+        for target in &["/fib", "/fib?n=-1", "/fib/ten"] {
+            let replies = exchange(&config(), RequestBuilder::get(target).build_bytes());
+            assert_eq!(replies[0].status, 400, "{}", target);
+        }
+    }
+
+    #[test]
+    fn parses_the_media_type_and_charset_of_a_content_type() {
+        let parsed = parse_content_type;
+        assert_eq!(parsed("application/json"), ("application/json".to_owned(), None));
+        assert_eq!(parsed("Text/HTML; Charset=\"UTF-8\""),
+                   ("text/html".to_owned(), Some("UTF-8".to_owned())));
+        assert_eq!(parsed("text/plain; format=flowed; junk; charset = utf-8 "),
+                   ("text/plain".to_owned(), Some("utf-8".to_owned())));
+        assert_eq!(parsed(""), (String::new(), None));
+    }
+
+    #[test]
+    fn finds_any_parameter_of_a_content_type() {
+        let header = "multipart/form-data; boundary=\"XyZ\"; charset=utf-8";
+        assert_eq!(content_type_parameter(header, "boundary"), Some("XyZ".to_owned()));
+        assert_eq!(content_type_parameter(header, "BOUNDARY"), Some("XyZ".to_owned()));
+        assert_eq!(content_type_parameter(header, "name"), None);
+        assert_eq!(content_type_parameter("boundary=XyZ", "boundary"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backs_off_only_when_out_of_file_descriptors() {
+        for code in &[libc::EMFILE, libc::ENFILE] {
+            assert_eq!(accept_backoff(&io::Error::from_raw_os_error(*code)), Some(FD_BACKOFF));
+        }
+        assert_eq!(accept_backoff(&io::Error::from_raw_os_error(libc::ECONNABORTED)), None);
+        assert_eq!(accept_backoff(&io::Error::other("not from the OS")), None);
+    }
+
+    #[test]
+    fn turns_a_failing_handler_into_a_response_and_closes() {
+        let config = config();
+        let server = failing_server(&config);
+        let stream = MockStream::new(RequestBuilder::get("/fail").build_bytes());
+        let mut reader = BufReader::new(stream);
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(matches!(handle_request(&mut reader, peer, &config, &server), Ok(false)));
+        let replies = Reply::parse_all(&reader.into_inner().output);
+        assert_eq!(replies[0].status, 500);
+    }
+
+    #[test]
+    fn reports_a_client_which_hung_up_as_a_disconnect() {
+        let config = config();
+        let server = server(&config);
+        let stream = MockStream::new(RequestBuilder::get("/healthz").build_bytes()).hung_up();
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        match handle_request(&mut BufReader::new(stream), peer, &config, &server) {
+            Err(ServerError::Io(ref e)) => assert!(is_disconnect(e), "{}", e),
+            other => panic!("Expected an I/O error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn answers_a_cors_preflight_from_an_allowed_origin() {
+        let mut config = config();
+        config.cors.allowed_origins = vec!["https://example.com".to_owned()];
+        let request = RequestBuilder::new(Method::Options, "/fib")
+            .header("Origin", "https://example.com")
+            .header("Access-Control-Request-Method", "POST");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 204);
+        assert_eq!(replies[0].header("Allow"), Some("GET, HEAD, POST"));
+        assert_eq!(replies[0].header("Access-Control-Allow-Origin"), Some("https://example.com"));
+        assert!(replies[0].header("Access-Control-Allow-Methods").unwrap().contains("POST"));
+
+        let request = request.header("Origin", "https://elsewhere.example");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 204);
+        assert_eq!(replies[0].header("Access-Control-Allow-Origin"), None);
+        assert_eq!(replies[0].header("Access-Control-Allow-Methods"), None);
+    }
+
+    #[test]
+    fn sends_the_sequence_in_chunks() {
+        let replies = exchange(&config(), RequestBuilder::get("/fib/seq?n=6").build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(replies[0].body, b"0\n1\n1\n2\n3\n5\n8\n".to_vec());
 
-        println!("=== Connection established!\n");
+        let replies = exchange(&config(), RequestBuilder::get("/fib/seq?n=94").build_bytes());
+        assert_eq!(replies[0].status, 400);
+    }
+
+    #[test]
+    fn sends_the_sequence_to_an_http_1_0_client_without_chunks() {
+        let request = RequestBuilder::get("/fib/seq?n=3").version("HTTP/1.0")
+            .header("Connection", "keep-alive");
+        let replies = exchange(&config(), request.build_bytes());
+        assert_eq!(replies[0].header("Transfer-Encoding"), None);
+        assert_eq!(replies[0].header("Connection"), Some("close"));
+        assert_eq!(replies[0].body, b"0\n1\n1\n2\n".to_vec());
+    }
+
+    #[test]
+    fn resolves_paths_inside_the_document_root() {
+        let root = TempDir::new();
+        let file = root.file("docs/a.txt", "a").canonicalize().unwrap();
+        let resolved = |path| resolve_path(root.path(), path).ok().and_then(|path| path);
+        assert_eq!(resolved("/docs/a.txt"), Some(file.clone()));
+        assert_eq!(resolved("/docs/./b/../a.txt"), Some(file.clone()));
+        assert_eq!(resolved("//docs//a.txt"), Some(file));
+        assert_eq!(resolved("/docs/missing.txt"), None);
+        assert_eq!(resolved("/"), Some(root.path().canonicalize().unwrap()));
+    }
+
+    #[test]
+    fn refuses_paths_climbing_out_of_the_document_root() {
+        let root = TempDir::new();
+        let outside = root.file("secret.txt", "secret");
+        let docs = root.path().join("docs");
+        fs::create_dir_all(&docs).unwrap();
+        for path in &["/..", "/../secret.txt", "/a/../../secret.txt", "/../no/such/file"] {
+            assert!(matches!(resolve_path(&docs, path), Err(ServerError::Forbidden)), "{}", path);
+        }
+        assert!(matches!(resolve_path(&root.path().join("missing"), "/"), Ok(None)));
+
+        let config = ServerConfig { doc_root: docs, ..config() };
+        let replies = exchange(&config, RequestBuilder::get("/../secret.txt").build_bytes());
+        assert_eq!(replies[0].status, 403);
+        assert_eq!(fs::read(outside).unwrap(), b"secret");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_links_pointing_out_of_the_document_root() {
+        let root = TempDir::new();
+        let outside = root.file("secret.txt", "secret");
+        let docs = root.path().join("docs");
+        fs::create_dir_all(&docs).unwrap();
+        std::os::unix::fs::symlink(&outside, docs.join("link.txt")).unwrap();
+        assert!(matches!(resolve_path(&docs, "/link.txt"), Err(ServerError::Forbidden)));
+    }
+
+    #[test]
+    fn serves_a_file_without_an_extension_with_a_sniffed_type() {
+        let root = TempDir::new();
+        root.file("README", "Read me\n");
+        root.file("blob", b"\0\x01\x02");
+        let config = ServerConfig { doc_root: root.path().to_owned(), ..config() };
+        let replies = exchange(&config, RequestBuilder::get("/README").build_bytes());
+        assert_eq!(replies[0].header("Content-Type"), Some("text/plain; charset=utf-8"));
+        let replies = exchange(&config, RequestBuilder::get("/blob").build_bytes());
+        assert_eq!(replies[0].header("Content-Type"), Some("application/octet-stream"));
+    }
+
+    // Reads the head of `input` and then its body, the way handle_request()
+    // does, and gives back the body and whatever is left after it
+    fn body_of(input: &[u8], config: &ServerConfig) -> (Result<Vec<u8>, ServerError>, Vec<u8>) {
+        let mut reader = BufReader::new(MockStream::new(input));
+        let mut head = Vec::new();
+        read_head(&mut reader, &mut head, MAX_HEAD_BYTES).unwrap();
+        let request = Request::parse(&head).unwrap();
+        let body = read_body(&mut reader, &request, config);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        (body, rest)
+    }
+
+    #[test]
+    fn reads_as_much_body_as_content_length_says() {
+        let input = [RequestBuilder::post("/").body("hello").build_bytes(),
+                     b"GET /next".to_vec()].concat();
+        let (body, rest) = body_of(&input, &config());
+        assert_eq!(body.unwrap(), b"hello".to_vec());
+        assert_eq!(rest, b"GET /next".to_vec());
+
+        let (body, _) = body_of(&RequestBuilder::post("/").build_bytes(), &config());
+        assert_eq!(body.unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn refuses_an_invalid_or_broken_content_length() {
+        for length in &["five", "-1", "1, 2"] {
+            let request = RequestBuilder::post("/").header("Content-Length", length).body("hello");
+            let (body, _) = body_of(&request.build_bytes(), &config());
+            assert!(matches!(body, Err(ServerError::BadRequest(_))), "{}", length);
+        }
+
+        let request = RequestBuilder::post("/").header("Content-Length", "10").body("hello");
+        match body_of(&request.build_bytes(), &config()).0 {
+            Err(ServerError::BadRequest(message)) => {
+                assert_eq!(message, "Request body is shorter than Content-Length")
+            },
+            other => panic!("Expected a bad request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn answers_a_post_with_a_short_body_with_a_400() {
+        let request = RequestBuilder::post("/fib").header("Content-Type", "application/json")
+            .header("Content-Length", "100").body(r#"{"n": 10}"#);
+        let replies = exchange(&config(), request.build_bytes());
+        assert_eq!(replies[0].status, 400);
+        assert_eq!(replies[0].header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn refuses_a_body_past_the_limit_before_reading_it() {
+        let config = ServerConfig { max_body_bytes: 4, ..config() };
+        let (body, rest) = body_of(&RequestBuilder::post("/").body("hello").build_bytes(), &config);
+        assert!(matches!(body, Err(ServerError::TooLarge)));
+        assert_eq!(rest, b"hello".to_vec());
+
+        let (body, _) = body_of(&RequestBuilder::post("/").body("1234").build_bytes(), &config);
+        assert_eq!(body.unwrap(), b"1234".to_vec());
+    }
+
+    #[test]
+    fn refuses_a_chunked_body_growing_past_the_limit() {
+        let config = ServerConfig { max_body_bytes: 4, ..config() };
+        let request = RequestBuilder::post("/").header("Transfer-Encoding", "chunked")
+            .body("3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n");
+        let (body, _) = body_of(&request.build_bytes(), &config);
+        assert!(matches!(body, Err(ServerError::TooLarge)));
+    }
+
+    #[test]
+    fn answers_a_body_past_the_limit_with_a_413_and_closes() {
+        let config = ServerConfig { max_body_bytes: 4, ..config() };
+        let input = [RequestBuilder::post("/fib").header("Content-Type", "application/json")
+                         .body(r#"{"n": 10}"#).build_bytes(),
+                     RequestBuilder::get("/healthz").build_bytes()].concat();
+        let replies = exchange(&config, input);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].status, 413);
+        assert_eq!(replies[0].header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn reads_a_chunked_body() {
+        let request = RequestBuilder::post("/").header("Transfer-Encoding", "Chunked")
+            .body("3\r\nabc\r\n2\r\nde\r\n0\r\n\r\nnext");
+        let (body, rest) = body_of(&request.build_bytes(), &config());
+        assert_eq!(body.unwrap(), b"abcde".to_vec());
+        assert_eq!(rest, b"next".to_vec());
+    }
+
+    #[test]
+    fn refuses_transfer_encoding_with_content_length_or_other_than_chunked() {
+        let request = RequestBuilder::post("/").header("Transfer-Encoding", "chunked")
+            .header("Content-Length", "5").body("0\r\n\r\n");
+        let (body, _) = body_of(&request.build_bytes(), &config());
+        assert!(matches!(body, Err(ServerError::BadRequest(_))));
+
+        let request = RequestBuilder::post("/").header("Transfer-Encoding", "gzip").body("x");
+        let (body, _) = body_of(&request.build_bytes(), &config());
+        assert!(matches!(body, Err(ServerError::NotImplemented(_))));
+    }
+
+    #[test]
+    fn answers_a_chunked_post() {
+        let request = RequestBuilder::post("/fib").header("Content-Type", "application/json")
+            .header("Transfer-Encoding", "chunked")
+            .body("4\r\n{\"n\"\r\n5\r\n: 10}\r\n0\r\n\r\n");
+        let replies = exchange(&config(), request.build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].body, br#"{"n":10,"value":55}"#.to_vec());
+
+        let request = RequestBuilder::post("/fib").header("Transfer-Encoding", "gzip").body("x");
+        assert_eq!(exchange(&config(), request.build_bytes())[0].status, 501);
+    }
+
+    fn form(filename: &str) -> RequestBuilder {
+        let body = format!("--b\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHoliday\r\n\
+                            --b\r\nContent-Disposition: form-data; name=\"photo\"; \
+                            filename=\"{}\"\r\nContent-Type: image/png\r\n\r\nPNG\r\n--b--\r\n",
+                           filename);
+        RequestBuilder::post("/upload")
+            .header("Content-Type", "multipart/form-data; boundary=b")
+            .body(body)
+    }
+
+    #[test]
+    fn stores_the_files_of_a_form() {
+        let uploads = TempDir::new();
+        let config = ServerConfig { upload_dir: uploads.path().to_owned(), ..config() };
+        let replies = exchange(&config, form("beach.png").build_bytes());
+        assert_eq!(replies[0].status, 201);
+        assert_eq!(replies[0].body,
+                   b"Stored 3 bytes of image/png from field photo as beach.png\n".to_vec());
+        assert_eq!(fs::read(uploads.path().join("beach.png")).unwrap(), b"PNG");
+        assert!(!uploads.path().join("title").exists());
+    }
+
+    #[test]
+    fn refuses_a_form_it_cannot_store() {
+        let uploads = TempDir::new();
+        let config = ServerConfig { upload_dir: uploads.path().to_owned(), ..config() };
+        let status = |request: RequestBuilder| exchange(&config, request.build_bytes())[0].status;
+        assert_eq!(status(form("../beach.png")), 400);
+        assert_eq!(status(form("beach.png").header("Content-Type", "multipart/form-data")), 400);
+        assert_eq!(status(form("beach.png").header("Content-Type", "text/plain")), 400);
+        assert_eq!(status(form("beach.png").header("Content-Type",
+                                                   "multipart/form-data; boundary=other")), 400);
+        assert_eq!(fs::read_dir(uploads.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn answers_a_post_which_is_not_json_with_a_415_or_a_400() {
+        let status = |content_type: &str, body: &str| {
+            let request = RequestBuilder::post("/fib").header("Content-Type", content_type)
+                .body(body);
+            exchange(&config(), request.build_bytes())[0].status
+        };
+        assert_eq!(status("text/plain", "10"), 415);
+        assert_eq!(status("application/json", "{ten}"), 400);
+        assert_eq!(status("application/json", r#"{"m": 10}"#), 400);
+        assert_eq!(status("application/json", r#"{"n": 94}"#), 400);
+        let replies = exchange(&config(), RequestBuilder::post("/fib").body("10").build_bytes());
+        assert_eq!(replies[0].status, 415);
+        assert_eq!(replies[0].body, b"Missing Content-Type\n".to_vec());
+    }
+
+    // A document root with a single page in it, and the configuration
+    // serving it
+    fn site() -> (TempDir, ServerConfig) {
+        let root = TempDir::new();
+        root.file("page.txt", "0123456789".repeat(200));
+        let config = ServerConfig { doc_root: root.path().to_owned(), ..config() };
+        (root, config)
+    }
+
+    #[test]
+    fn matches_etags_strong_weak_or_any() {
+        let etag = "\"5f-1a\"";
+        assert!(etag_matches("\"5f-1a\"", etag));
+        assert!(etag_matches("\"00-00\", W/\"5f-1a\"", etag));
+        assert!(etag_matches(" * ", etag));
+        assert!(!etag_matches("\"5f-1b\"", etag));
+        assert!(!etag_matches("5f-1a", etag));
+    }
+
+    #[test]
+    fn answers_a_matching_if_none_match_with_a_304() {
+        let (_root, config) = site();
+        let replies = exchange(&config, RequestBuilder::get("/page.txt").build_bytes());
+        assert_eq!(replies[0].status, 200);
+        let etag = replies[0].header("ETag").unwrap().to_owned();
+
+        let request = RequestBuilder::get("/page.txt").header("If-None-Match", &etag);
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 304);
+        assert_eq!(replies[0].header("ETag"), Some(etag.as_str()));
+        assert!(replies[0].body.is_empty());
+
+        let request = RequestBuilder::get("/page.txt").header("If-None-Match", "\"other\"");
+        assert_eq!(exchange(&config, request.build_bytes())[0].status, 200);
+    }
+
+    #[test]
+    fn compares_if_modified_since_to_the_second() {
+        let modified = UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        assert!(not_modified_since(modified, "Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(not_modified_since(modified, "Sunday, 06-Nov-94 08:49:38 GMT"));
+        assert!(!not_modified_since(modified, "Sun Nov  6 08:49:36 1994"));
+        assert!(!not_modified_since(modified, "yesterday"));
+    }
+
+    #[test]
+    fn answers_if_modified_since_with_a_304_unless_if_none_match_says_otherwise() {
+        let (_root, config) = site();
+        let replies = exchange(&config, RequestBuilder::get("/page.txt").build_bytes());
+        let last_modified = replies[0].header("Last-Modified").unwrap().to_owned();
+        assert!(parse_http_date(&last_modified).is_some());
+
+        let request = RequestBuilder::get("/page.txt").header("If-Modified-Since", &last_modified);
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 304);
+        assert!(replies[0].body.is_empty());
+
+        let request = request.header("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(exchange(&config, request.build_bytes())[0].status, 200);
+
+        // The ETag wins when both are sent
+        let request = RequestBuilder::get("/page.txt")
+            .header("If-Modified-Since", &last_modified)
+            .header("If-None-Match", "\"other\"");
+        assert_eq!(exchange(&config, request.build_bytes())[0].status, 200);
+    }
+
+    #[test]
+    fn answers_a_range_with_a_206_or_a_416() {
+        let (_root, config) = site();
+        let replies = exchange(&config, RequestBuilder::get("/page.txt").build_bytes());
+        assert_eq!(replies[0].header("Accept-Ranges"), Some("bytes"));
+
+        let request = RequestBuilder::get("/page.txt").header("Range", "bytes=-5");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 206);
+        assert_eq!(replies[0].header("Content-Range"), Some("bytes 1995-1999/2000"));
+        assert_eq!(replies[0].body, b"56789".to_vec());
+
+        let request = RequestBuilder::get("/page.txt").header("Range", "bytes=2000-");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 416);
+        assert_eq!(replies[0].header("Content-Range"), Some("bytes */2000"));
+
+        let request = RequestBuilder::get("/page.txt").header("Range", "bytes=9-5");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].body.len(), 2000);
+    }
+
+    #[test]
+    fn answers_several_ranges_with_a_multipart_body() {
+        let (_root, config) = site();
+        let request = RequestBuilder::get("/page.txt").header("Range", "bytes=0-2,-3");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 206);
+        let content_type = replies[0].header("Content-Type").unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        assert_eq!(replies[0].header("Content-Range"), None);
+
+        let expected = format!("\r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                                Content-Range: bytes 0-2/2000\r\n\r\n012\
+                                \r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                                Content-Range: bytes 1997-1999/2000\r\n\r\n789\
+                                \r\n--{0}--\r\n", boundary);
+        assert_eq!(String::from_utf8_lossy(&replies[0].body), expected);
+    }
+
+    #[test]
+    fn answers_head_with_the_headers_of_a_get_and_no_body() {
+        let (_root, config) = site();
+        let head = RequestBuilder::new(Method::Head, "/page.txt");
+        let input = [head.build_bytes(), RequestBuilder::get("/healthz").build_bytes()].concat();
+        let bytes = answer(&config, MockStream::new(input));
+        let text = String::from_utf8_lossy(&bytes);
+        let (head, rest) = text.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\nContent-Length: 2000\r\n"), "{}", head);
+        // Straight on to the response to the next request
+        assert!(rest.starts_with("HTTP/1.1 200 OK\r\n"), "{}", rest);
+
+        let bytes = answer(&config, MockStream::new(
+            RequestBuilder::new(Method::Head, "/fib?n=10").build_bytes()));
+        assert!(String::from_utf8_lossy(&bytes).ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn redirects_old_to_new() {
+        let replies = exchange(&config(), RequestBuilder::get("/old").build_bytes());
+        assert_eq!(replies[0].status, 301);
+        assert_eq!(replies[0].header("Location"), Some("/new"));
+    }
+
+    #[test]
+    fn answers_a_malformed_request_with_a_400_saying_why() {
+        let cases: &[(&str, &str)] = &[
+            ("GET /\r\n\r\n", "Malformed request line: GET /\n"),
+            ("GET / HTTP/one\r\nHost: x\r\n\r\n", "Invalid HTTP version: HTTP/one\n"),
+            ("GET index.html HTTP/1.1\r\nHost: x\r\n\r\n", "Invalid request target: index.html\n"),
+            ("GET / HTTP/1.1\r\nHost x\r\n\r\n", "Malformed header: Host x\n"),
+            ("GET /%zz HTTP/1.1\r\nHost: x\r\n\r\n", "Invalid percent-encoding: /%zz\n"),
+        ];
+        for &(input, message) in cases {
+            let replies = exchange(&config(), input);
+            assert_eq!(replies.len(), 1, "{:?}", input);
+            assert_eq!(replies[0].status, 400, "{:?}", input);
+            assert_eq!(String::from_utf8_lossy(&replies[0].body), message);
+            assert_eq!(replies[0].header("Connection"), Some("close"));
+        }
+    }
+
+    #[test]
+    fn answers_an_unknown_method_with_a_501() {
+        let replies = exchange(&config(), "BREW /pot HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(replies[0].status, 501);
+        assert_eq!(replies[0].body, b"Method not implemented: BREW\n".to_vec());
+    }
+
+    #[test]
+    fn lists_a_directory_only_with_autoindex() {
+        let root = TempDir::new();
+        root.file("docs/a.txt", "a");
+        let config = ServerConfig { doc_root: root.path().to_owned(), ..config() };
+        let replies = exchange(&config, RequestBuilder::get("/docs/").build_bytes());
+        assert_eq!(replies[0].status, 404);
+
+        let config = ServerConfig { autoindex: true, ..config };
+        let replies = exchange(&config, RequestBuilder::get("/docs?x=1").build_bytes());
+        assert_eq!(replies[0].status, 301);
+        assert_eq!(replies[0].header("Location"), Some("/docs/?x=1"));
+        let replies = exchange(&config, RequestBuilder::get("/docs/").build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].header("Content-Type"), Some(HTML));
+        assert!(String::from_utf8_lossy(&replies[0].body).contains("href=\"a.txt\""));
+
+        // An index.html takes precedence
+        root.file("docs/index.html", "<p>Docs</p>");
+        let replies = exchange(&config, RequestBuilder::get("/docs/").build_bytes());
+        assert_eq!(replies[0].body, b"<p>Docs</p>".to_vec());
+    }
+
+    #[test]
+    fn answers_errors_with_the_pages_set_for_them() {
+        let root = TempDir::new();
+        let mut config = ServerConfig { doc_root: root.path().join("site"), ..config() };
+        root.file("site/404.html", "<p>The root's own</p>");
+        let replies = exchange(&config, RequestBuilder::get("/missing").build_bytes());
+        assert_eq!(replies[0].body, b"<p>The root's own</p>".to_vec());
+
+        config.error_pages.set(404, root.file("errors/404.html", "<p>Not here</p>"));
+        config.error_pages.set(400, root.file("errors/400.txt", "Try again\n"));
+        config.error_pages.set(405, root.file("errors/405.html", "<p>Not like that</p>"));
+        let input = [RequestBuilder::get("/missing").build_bytes(),
+                     RequestBuilder::new(Method::Delete, "/fib").build_bytes()].concat();
+        let replies = exchange(&config, input);
+        assert_eq!(replies[0].status, 404);
+        assert_eq!(replies[0].body, b"<p>Not here</p>".to_vec());
+        assert_eq!(replies[1].status, 405);
+        assert_eq!(replies[1].body, b"<p>Not like that</p>".to_vec());
+        assert_eq!(replies[1].header("Allow"), Some("GET, HEAD, POST"));
+
+        let replies = exchange(&config, "garbage\r\n\r\n");
+        assert_eq!(replies[0].status, 400);
+        assert_eq!(replies[0].header("Content-Type"), Some(TEXT));
+        assert_eq!(replies[0].body, b"Try again\n".to_vec());
+    }
+
+    #[test]
+    fn gzips_a_large_text_response_for_a_client_accepting_it() {
+        let (_root, config) = site();
+        let request = RequestBuilder::get("/page.txt").header("Accept-Encoding", "br, gzip;q=0.8");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].header("Content-Encoding"), Some("gzip"));
+        assert_eq!(replies[0].header("Vary"), Some("Accept-Encoding"));
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(&replies[0].body[..]).read_to_string(&mut text).unwrap();
+        assert_eq!(text, "0123456789".repeat(200));
+
+        let replies = exchange(&config, RequestBuilder::get("/page.txt").build_bytes());
+        assert_eq!(replies[0].header("Content-Encoding"), None);
+        assert_eq!(replies[0].body.len(), 2000);
+    }
+
+    #[test]
+    fn healthz_answers_unless_turned_off() {
+        let (root, mut config) = site();
+        let replies = exchange(&config, RequestBuilder::get("/healthz").build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].body, b"{\"status\":\"ok\"}".to_vec());
+
+        // Leaving /healthz to the site, which may not have one
+        config.healthz = false;
+        let replies = exchange(&config, RequestBuilder::get("/healthz").build_bytes());
+        assert_eq!(replies[0].status, 404);
+        root.file("healthz", "Our own\n");
+        let replies = exchange(&config, RequestBuilder::get("/healthz").build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].body, b"Our own\n".to_vec());
+    }
+
+    #[test]
+    fn metrics_count_the_responses_sent_before() {
+        let input = [RequestBuilder::get("/healthz").build_bytes(),
+                     RequestBuilder::get("/missing").build_bytes(),
+                     RequestBuilder::get("/metrics").build_bytes()].concat();
+        let replies = exchange(&config(), input);
+        assert_eq!(replies[2].status, 200);
+        assert_eq!(replies[2].header("Content-Type"),
+                   Some("text/plain; version=0.0.4; charset=utf-8"));
+        let text = String::from_utf8(replies[2].body.clone()).unwrap();
+        assert!(text.contains("http_requests_total 2\n"), "{}", text);
+        assert!(text.contains("http_responses_total{code=\"200\"} 1\n"), "{}", text);
+        assert!(text.contains("http_responses_total{code=\"404\"} 1\n"), "{}", text);
+    }
+
+    #[test]
+    fn answers_a_client_over_the_rate_limit_with_a_429() {
+        let config = ServerConfig { rate_limit: Some(0.5), rate_burst: Some(2), ..config() };
+        let input = [RequestBuilder::get("/healthz").build_bytes(),
+                     RequestBuilder::get("/healthz").build_bytes(),
+                     RequestBuilder::get("/healthz").build_bytes()].concat();
+        let replies = exchange(&config, input);
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[1].status, 200);
+        assert_eq!(replies[2].status, 429);
+        assert_eq!(replies[2].header("Retry-After"), Some("2"));
+    }
+
+    #[test]
+    fn answers_a_client_not_let_in_with_a_403() {
+        let mut config = config();
+        config.access.allow.push("10.0.0.0/8".parse().unwrap());
+        let replies = exchange(&config, RequestBuilder::get("/healthz").build_bytes());
+        assert_eq!(replies[0].status, 403);
+
+        config.access.allow.push("127.0.0.1".parse().unwrap());
+        let replies = exchange(&config, RequestBuilder::get("/healthz").build_bytes());
+        assert_eq!(replies[0].status, 200);
+        config.access.deny.push("127.0.0.0/8".parse().unwrap());
+        let replies = exchange(&config, RequestBuilder::get("/healthz").build_bytes());
+        assert_eq!(replies[0].status, 403);
+    }
+
+    fn statuses(replies: &[Reply]) -> Vec<u16> {
+        replies.iter().map(|reply| reply.status).collect()
+    }
+
+    #[test]
+    fn says_continue_before_reading_a_body_the_client_holds_back() {
+        let request = RequestBuilder::post("/fib").header("Expect", "100-Continue")
+            .header("Content-Type", "application/json").body("{\"n\": 10}");
+        let replies = exchange(&config(), request.build_bytes());
+        assert_eq!(statuses(&replies), [100, 200]);
+
+        let chunked = RequestBuilder::post("/fib").header("Expect", "100-continue")
+            .header("Content-Type", "application/json").header("Transfer-Encoding", "chunked")
+            .body("9\r\n{\"n\": 10}\r\n0\r\n\r\n");
+        assert_eq!(statuses(&exchange(&config(), chunked.build_bytes())), [100, 200]);
+
+        // HTTP/1.0 has no interim responses
+        let replies = exchange(&config(), request.version("HTTP/1.0").build_bytes());
+        assert_eq!(statuses(&replies), [200]);
+    }
+
+    #[test]
+    fn refuses_a_body_over_the_limit_without_saying_continue() {
+        let config = ServerConfig { max_body_bytes: 4, ..config() };
+        let request = RequestBuilder::post("/fib").header("Expect", "100-continue")
+            .header("Content-Type", "application/json").header("Content-Length", "9");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(statuses(&replies), [413]);
+    }
+
+    #[test]
+    fn echoes_websocket_messages_after_the_handshake() {
+        let request = RequestBuilder::get("/ws/echo").header("Upgrade", "websocket")
+            .header("Connection", "Upgrade").header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Version", "13");
+        // "hi" and a close frame with code 1000, masked with 0s, as the
+        // client's frames must be
+        let input = [request.build_bytes(), vec![0x81, 0x82, 0, 0, 0, 0, b'h', b'i'],
+                     vec![0x88, 0x82, 0, 0, 0, 0, 0x03, 0xE8]].concat();
+        let output = answer(&config(), MockStream::new(input));
+
+        let end = output.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&output[..end]);
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        assert!(head.contains("\r\nUpgrade: websocket\r\n"), "{}", head);
+        assert!(head.contains("\r\nConnection: Upgrade\r\n"), "{}", head);
+        assert!(head.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{}", head);
+        assert_eq!(output[end..], [0x81, 2, b'h', b'i', 0x88, 2, 0x03, 0xE8]);
+    }
+
+    #[test]
+    fn streams_ticks_as_server_sent_events() {
+        let replies = exchange(&config(), RequestBuilder::get("/events/ticks?count=1").build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].header("Content-Type"), Some("text/event-stream"));
+        assert_eq!(replies[0].header("Cache-Control"), Some("no-cache"));
+        assert_eq!(replies[0].header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(replies[0].body, b"event: tick\nid: 1\ndata: 1\n\n".to_vec());
+
+        let replies = exchange(&config(), RequestBuilder::get("/events/ticks?count=3601").build_bytes());
+        assert_eq!(replies[0].status, 400);
+    }
+
+    #[test]
+    fn serves_each_virtual_host_from_its_own_root() {
+        let (_root, mut config) = site();
+        let example = TempDir::new();
+        example.file("page.txt", "Example\n");
+        config.virtual_hosts.insert("example.com".to_owned(), example.path().to_owned());
+
+        let request = RequestBuilder::get("/page.txt").header("Host", "Example.com:8080");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].body, b"Example\n".to_vec());
+        let replies = exchange(&config, RequestBuilder::get("/page.txt").build_bytes());
+        assert_eq!(replies[0].body.len(), 2000);
+    }
+
+    #[test]
+    fn streams_a_large_file_as_it_is() {
+        let (root, config) = site();
+        let contents: Vec<u8> = (0..STREAM_BYTES + 10).map(|i| b'a' + (i % 26) as u8).collect();
+        root.file("large.txt", &contents);
+
+        let request = RequestBuilder::get("/large.txt").header("Accept-Encoding", "gzip");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 200);
+        assert_eq!(replies[0].header("Content-Length"), Some(&*contents.len().to_string()));
+        // Not read into memory, so not compressed either
+        assert_eq!(replies[0].header("Content-Encoding"), None);
+        assert!(replies[0].body == contents);
+
+        let request = RequestBuilder::get("/large.txt").header("Range", "bytes=-5");
+        let replies = exchange(&config, request.build_bytes());
+        assert_eq!(replies[0].status, 206);
+        assert_eq!(replies[0].body, contents[contents.len() - 5..].to_vec());
+    }
+
+    #[test]
+    fn keeps_a_request_id_given_and_makes_one_up_otherwise() {
+        let request = |id: Option<&str>| {
+            let request = RequestBuilder::get("/healthz");
+            match id {
+                Some(id) => request.header("X-Request-Id", id),
+                None => request,
+            }.build_bytes()
+        };
+        let input = [request(Some(" abc-123 ")), request(None), request(None),
+                     request(Some("has spaces")), request(Some(&"x".repeat(129)))].concat();
+        let replies = exchange(&config(), input);
+        let ids: Vec<&str> = replies.iter().map(|reply| reply.header("X-Request-Id").unwrap()).collect();
+        assert_eq!(ids[0], "abc-123");
+
+        // The same start time, and a counter going up
+        let (started, count) = ids[1].split_once('-').unwrap();
+        assert_eq!(started.len(), 8);
+        assert_eq!(ids[2], format!("{}-{:08x}", started, u64::from_str_radix(count, 16).unwrap() + 1));
+        for id in &ids[3..] {
+            assert!(id.starts_with(started), "{}", id);
+        }
+    }
+
+    #[test]
+    fn asks_for_a_password_when_users_are_set() {
+        let config = ServerConfig {
+            basic_auth: vec![("alice".to_owned(), "secret".to_owned())],
+            auth_realm: "Say \"please\"".to_owned(),
+            ..config()
+        };
+        let replies = exchange(&config, RequestBuilder::get("/healthz").build_bytes());
+        assert_eq!(replies[0].status, 401);
+        assert_eq!(replies[0].header("WWW-Authenticate"),
+                   Some("Basic realm=\"Say \\\"please\\\"\", charset=\"UTF-8\""));
+
+        let request = RequestBuilder::get("/healthz")
+            .header("Authorization", "Basic YWxpY2U6c2VjcmV0");
+        assert_eq!(exchange(&config, request.build_bytes())[0].status, 200);
+        // Preflights never come with credentials
+        let preflight = RequestBuilder::new(Method::Options, "/healthz");
+        assert_ne!(exchange(&config, preflight.build_bytes())[0].status, 401);
+    }
 
-        handle_connection(stream);
+    #[test]
+    fn answers_a_request_line_over_the_limit_with_a_414() {
+        let config = ServerConfig { max_request_line: 64, ..config() };
+        // "GET " and " HTTP/1.1\r\n" take 15 of the 64 bytes
+        let fits = format!("/{}", "a".repeat(48));
+        let replies = exchange(&config, RequestBuilder::get(&fits).build_bytes());
+        assert_eq!(replies[0].status, 404);
 
-        println!("=== Closing connection.\n");
+        let input = [RequestBuilder::get(&format!("{}a", fits)).build_bytes(),
+                     RequestBuilder::get("/healthz").build_bytes()].concat();
+        let replies = exchange(&config, input);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].status, 414);
+        assert_eq!(replies[0].header("Connection"), Some("close"));
     }
 }
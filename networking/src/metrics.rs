@@ -0,0 +1,95 @@
+// Counters for monitoring systems, shown at /metrics in the text format
+// Prometheus scrapes:
+//   # HELP http_requests_total Requests answered.
+//   # TYPE http_requests_total counter
+//   http_requests_total 42
+//   http_responses_total{code="200"} 40
+//   http_responses_total{code="404"} 2
+// Counters only ever go up; Prometheus works out rates from the difference
+// between two scrapes, and notices when a restart takes them back to 0.
+//
+// Unlike RouteStats, which counts hits per route, these count what actually
+// went out: every response, whatever the route, including the errors
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Plain numbers are updated with atomics, which need no lock: an increment
+// made by one worker thread cannot be lost to another one making its own at
+// the same time. The counts per status code live in a map which can grow,
+// and that takes a Mutex. A BTreeMap keeps the codes sorted for rendering
+#[derive(Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    statuses: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    // Counts a response which has been sent, with a body `bytes` long
+    //
+    // Ordering::Relaxed is enough: every counter stands on its own, and
+    // nothing else is read or written on the strength of its value
+    pub fn record(&self, status: u16, bytes: usize) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        let mut statuses = self.statuses.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *statuses.entry(status).or_insert(0) += 1;
+    }
+
+    // Renders the counters in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        output.push_str("# HELP http_requests_total Requests answered.\n");
+        output.push_str("# TYPE http_requests_total counter\n");
+        output.push_str(&format!("http_requests_total {}\n",
+                                 self.requests.load(Ordering::Relaxed)));
+
+        output.push_str("# HELP http_responses_total Responses sent, by status code.\n");
+        output.push_str("# TYPE http_responses_total counter\n");
+        let statuses = self.statuses.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (status, count) in statuses.iter() {
+            output.push_str(&format!("http_responses_total{{code=\"{}\"}} {}\n", status, count));
+        }
+
+        output.push_str("# HELP http_response_bytes_total Bytes of response bodies sent.\n");
+        output.push_str("# TYPE http_response_bytes_total counter\n");
+        output.push_str(&format!("http_response_bytes_total {}\n",
+                                 self.bytes.load(Ordering::Relaxed)));
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn renders_nothing_but_zeros_to_start_with() {
+        assert_eq!(Metrics::new().render(),
+                   "# HELP http_requests_total Requests answered.\n\
+                    # TYPE http_requests_total counter\n\
+                    http_requests_total 0\n\
+                    # HELP http_responses_total Responses sent, by status code.\n\
+                    # TYPE http_responses_total counter\n\
+                    # HELP http_response_bytes_total Bytes of response bodies sent.\n\
+                    # TYPE http_response_bytes_total counter\n\
+                    http_response_bytes_total 0\n");
+    }
+
+    #[test]
+    fn counts_responses_by_status_in_order() {
+        let metrics = Metrics::new();
+        metrics.record(404, 10);
+        metrics.record(200, 100);
+        metrics.record(200, 50);
+        let output = metrics.render();
+        assert!(output.contains("http_requests_total 3\n"), "{}", output);
+        assert!(output.contains("http_responses_total{code=\"200\"} 2\n\
+                                 http_responses_total{code=\"404\"} 1\n"), "{}", output);
+        assert!(output.contains("http_response_bytes_total 160\n"), "{}", output);
+    }
+}
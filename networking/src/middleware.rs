@@ -0,0 +1,28 @@
+// Middleware: code which runs around every request the router dispatches,
+// whatever its route. That is the place for what concerns all of them alike,
+// like adding the CORS headers, rather than every handler doing it on its
+// own.
+//
+// A middleware gets the request, and `next`, which passes the request on to
+// whatever comes after it: the next middleware, or the route's handler once
+// there are none left. It can change the response `next` comes back with,
+// or answer the request itself without calling `next` at all, e.g. to turn
+// away a client which is not allowed in.
+//
+// Middleware is added with Router::add_middleware(), and runs in the order it
+// was added: the first one added is the first to see the request, and the
+// last to see the response
+use error::ServerError;
+use request::Request;
+use response::Response;
+use router::Context;
+
+// Hands the request on to the rest of the chain
+pub type Next<'a> = &'a dyn Fn(&Request) -> Result<Response, ServerError>;
+
+// Send + Sync because the router, and the middleware in it, is shared by
+// all of the worker threads
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &Request, context: &Context, next: Next)
+              -> Result<Response, ServerError>;
+}
@@ -0,0 +1,147 @@
+// Works out the Content-Type of a file from its extension. The client relies
+// on it to know what to do with the bytes: a browser renders text/html,
+// applies text/css, runs text/javascript and so on.
+//
+// Text types say that they are UTF-8, which is what we expect files on disk
+// to be written in.
+//
+// A file whose extension says nothing, like a README or an image saved
+// without one, can still be recognised by its first bytes; see sniff_file()
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+// The type of anything we do not recognise: "some bytes". Browsers offer to
+// download it rather than guess
+pub const UNKNOWN: &str = "application/octet-stream";
+
+// How much of a file sniff_file() looks at, the same as browsers do
+const SNIFF_BYTES: u64 = 512;
+
+// Formats which start with the same few bytes in every file, "magic
+// numbers", and the types they give away
+const SIGNATURES: [(&[u8], &str); 4] = [
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xFF\xD8\xFF", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+];
+
+// E.g. "index.html" gives "text/html; charset=utf-8". Extensions are compared
+// without regard to case, so "LOGO.PNG" is an image/png too
+pub fn content_type_for(path: &str) -> &'static str {
+    // .extension() returns what follows the last "." of the file name, if
+    // there is one; .to_str() fails only on names which are not valid UTF-8
+    let extension = match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some(extension) => extension.to_ascii_lowercase(),
+        None => return UNKNOWN,
+    };
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        _ => UNKNOWN,
+    }
+}
+
+// Works out the Content-Type of a file from its first bytes, for a file
+// whose extension is of no help:
+// - images of the formats in SIGNATURES, by their magic numbers
+// - HTML, by the "<!DOCTYPE html" or "<html" it starts with
+// - text, if it is UTF-8 without control characters in it
+// and UNKNOWN for anything else
+pub fn sniff_file(path: &Path) -> io::Result<&'static str> {
+    let mut start = Vec::new();
+    File::open(path)?.take(SNIFF_BYTES).read_to_end(&mut start)?;
+    Ok(sniff(&start))
+}
+
+fn sniff(bytes: &[u8]) -> &'static str {
+    if let Some(&(_, content_type)) = SIGNATURES.iter()
+        .find(|&&(signature, _)| bytes.starts_with(signature)) {
+        return content_type;
+    }
+
+    // Tags are case-insensitive, and may come after some blank lines
+    let starts_with = |prefix: &[u8]| {
+        let text = bytes.trim_ascii_start();
+        text.len() >= prefix.len() && text[..prefix.len()].eq_ignore_ascii_case(prefix)
+    };
+    if starts_with(b"<!doctype html") || starts_with(b"<html") {
+        return "text/html; charset=utf-8";
+    }
+
+    // Only the first SNIFF_BYTES bytes were read, which may end halfway
+    // through a character; .error_len() is None for exactly that. Tabs,
+    // line breaks and form feeds are the control characters text has
+    let utf8 = match ::std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    let control = bytes.iter()
+        .any(|&byte| (byte < 0x20 && !b"\t\n\r\x0C".contains(&byte)) || byte == 0x7F);
+    if utf8 && !control {
+        return "text/plain; charset=utf-8";
+    }
+    UNKNOWN
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::TempDir;
+
+    #[test]
+    fn knows_types_by_extension() {
+        assert_eq!(content_type_for("index.html"), "text/html; charset=utf-8");
+        assert_eq!(content_type_for("/static/app.min.js"), "text/javascript; charset=utf-8");
+        assert_eq!(content_type_for("LOGO.PNG"), "image/png");
+        assert_eq!(content_type_for("favicon.ico"), "image/x-icon");
+    }
+
+    #[test]
+    fn does_not_guess_from_an_unknown_extension() {
+        assert_eq!(content_type_for("archive.tar.zst"), UNKNOWN);
+        assert_eq!(content_type_for("README"), UNKNOWN);
+        assert_eq!(content_type_for(".bashrc"), UNKNOWN);
+    }
+
+    #[test]
+    fn sniffs_images_html_and_text() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(sniff(b"\xFF\xD8\xFF\xE0"), "image/jpeg");
+        assert_eq!(sniff(b"GIF89a\x01\0"), "image/gif");
+        assert_eq!(sniff(b"\n\n  <!DOCTYPE HTML>\n<p>Hi"), "text/html; charset=utf-8");
+        assert_eq!(sniff(b"<html lang=\"en\">"), "text/html; charset=utf-8");
+        assert_eq!(sniff("Read me\n\tcafé\r\n".as_bytes()), "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn does_not_take_binary_for_text() {
+        assert_eq!(sniff(b"text\0with a NUL"), UNKNOWN);
+        assert_eq!(sniff(b"\x7FELF\x02\x01"), UNKNOWN);
+        assert_eq!(sniff(b"\xC3\x28 is not UTF-8"), UNKNOWN);
+    }
+
+    #[test]
+    fn sniffs_only_the_start_of_a_file() {
+        let dir = TempDir::new();
+        // The 512th byte is the first half of an "é"
+        let mut text = "x".repeat(511).into_bytes();
+        text.extend_from_slice("é and a NUL: \0".as_bytes());
+        let path = dir.file("README", &text);
+        assert_eq!(sniff_file(&path).unwrap(), "text/plain; charset=utf-8");
+        assert!(sniff_file(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+// Bodies of type multipart/form-data, which is what a browser sends for an
+// HTML form with a file in it (RFC 7578). The body holds one part per form
+// field, separated by a "boundary" which the client picks and announces in
+// the Content-Type header:
+//   Content-Type: multipart/form-data; boundary=XyZ
+//
+// and the body then looks like this, every line ending in "\r\n":
+//   --XyZ
+//   Content-Disposition: form-data; name="title"
+//
+//   Holiday
+//   --XyZ
+//   Content-Disposition: form-data; name="photo"; filename="beach.png"
+//   Content-Type: image/png
+//
+//   <the bytes of the file>
+//   --XyZ--
+//
+// Each part has headers of its own, then an empty line, then its data. The
+// "\r\n" in front of a boundary belongs to the boundary, not to the data, and
+// the final boundary has an extra "--" at the end
+use error::ParseError;
+
+// A field without a Content-Type of its own is plain text (RFC 7578, section
+// 4.4)
+const DEFAULT_CONTENT_TYPE: &str = "text/plain";
+
+#[derive(Debug)]
+pub struct Part {
+    // The name of the form field
+    pub name: String,
+    // The name of the file on the client's side, for file fields only. It is
+    // whatever the client said, so it should not be trusted as a path
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+// Splits `body` into its parts. Whatever comes before the first boundary or
+// after the last one (the "preamble" and "epilogue") is ignored, as RFC 2046
+// says. A body which never gets to its final boundary was cut short, and is
+// an error rather than a form with fewer fields than it should have
+pub fn parse_multipart(body: &[u8], boundary: &str) -> Result<Vec<Part>, ParseError> {
+    let invalid = |reason: &str| ParseError::InvalidMultipart(reason.to_owned());
+
+    if boundary.is_empty() {
+        return Err(invalid("Empty boundary"));
+    }
+    let delimiter = format!("--{}", boundary).into_bytes();
+    // Every boundary but the first one follows the data of a part, and the
+    // "\r\n" in between is part of it
+    let next_delimiter = [&b"\r\n"[..], &delimiter].concat();
+
+    // The first boundary is either at the very start of the body or at the
+    // start of a line of the preamble
+    let mut position = if body.starts_with(&delimiter) {
+        delimiter.len()
+    } else {
+        find(body, &next_delimiter).ok_or_else(|| invalid("Missing boundary"))?
+            + next_delimiter.len()
+    };
+
+    let mut parts = Vec::new();
+    loop {
+        let rest = &body[position..];
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !rest.starts_with(b"\r\n") {
+            return Err(invalid("Boundary is not followed by CRLF"));
+        }
+
+        let rest = &rest[2..];
+        let end = find(rest, &next_delimiter)
+            .ok_or_else(|| invalid("Missing final boundary"))?;
+        parts.push(parse_part(&rest[..end])?);
+        position += 2 + end + next_delimiter.len();
+    }
+}
+
+// A single part: its headers, an empty line and its data. The data may be
+// anything, including more "\r\n\r\n", so only the first one counts
+fn parse_part(part: &[u8]) -> Result<Part, ParseError> {
+    // A part may have no headers at all, in which case the empty line comes
+    // first
+    let (head, data) = if part.starts_with(b"\r\n") {
+        (&b""[..], &part[2..])
+    } else {
+        match find(part, b"\r\n\r\n") {
+            Some(end) => (&part[..end], &part[end + 4..]),
+            None => {
+                return Err(ParseError::InvalidMultipart(
+                    "Part headers are not followed by an empty line".to_owned()))
+            },
+        }
+    };
+
+    let mut name = None;
+    let mut filename = None;
+    let mut content_type = None;
+    // Without headers, there are no lines to look at rather than an empty one
+    for line in String::from_utf8_lossy(head).split("\r\n").filter(|line| !line.is_empty()) {
+        let (header, value) = line.split_once(':')
+            .ok_or_else(|| ParseError::MalformedHeader(line.to_owned()))?;
+        let header = header.trim();
+
+        if header.eq_ignore_ascii_case("Content-Disposition") {
+            // form-data; name="photo"; filename="beach.png"
+            for parameter in value.split(';').skip(1) {
+                if let Some((key, value)) = parameter.split_once('=') {
+                    let value = value.trim().trim_matches('"').to_owned();
+                    match key.trim().to_ascii_lowercase().as_str() {
+                        "name" => name = Some(value),
+                        "filename" => filename = Some(value),
+                        _ => {},
+                    }
+                }
+            }
+        } else if header.eq_ignore_ascii_case("Content-Type") {
+            content_type = Some(value.trim().to_owned());
+        }
+    }
+
+    Ok(Part {
+        name: name.ok_or_else(|| ParseError::InvalidMultipart(
+            "Part without a name".to_owned()))?,
+        filename,
+        content_type: content_type.unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_owned()),
+        data: data.to_vec(),
+    })
+}
+
+// Where `needle` first appears in `haystack`. .windows() goes through every
+// slice of needle's length, one byte further each time
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example at the top of the file, with a preamble and an epilogue
+    const FORM: &[u8] = b"This is the preamble\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        Holiday\r\n\
+        --XyZ\r\n\
+        content-disposition: form-data; name=\"photo\"; filename=\"beach.png\"\r\n\
+        Content-Type: image/png\r\n\r\n\
+        \x89PNG\r\n\r\n--Xy\r\n\
+        --XyZ--\r\nThis is the epilogue";
+
+    #[test]
+    fn splits_a_form_into_its_parts() {
+        let parts = parse_multipart(FORM, "XyZ").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name, "title");
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].content_type, "text/plain");
+        assert_eq!(parts[0].data, b"Holiday".to_vec());
+        assert_eq!(parts[1].name, "photo");
+        assert_eq!(parts[1].filename.as_deref(), Some("beach.png"));
+        assert_eq!(parts[1].content_type, "image/png");
+        assert_eq!(parts[1].data, b"\x89PNG\r\n\r\n--Xy".to_vec());
+    }
+
+    #[test]
+    fn parses_a_form_starting_with_its_boundary() {
+        let parts = parse_multipart(b"--b\r\nContent-Disposition: form-data; name=a\r\n\r\n\
+                                      1\r\n--b--", "b").unwrap();
+        assert_eq!(parts[0].name, "a");
+        assert_eq!(parts[0].data, b"1".to_vec());
+        assert!(parse_multipart(b"--b--", "b").unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_a_broken_form() {
+        let reason = |body: &[u8], boundary| match parse_multipart(body, boundary) {
+            Err(ParseError::InvalidMultipart(reason)) => reason,
+            other => panic!("Expected an invalid multipart body, got {:?}", other),
+        };
+        assert_eq!(reason(FORM, ""), "Empty boundary");
+        assert_eq!(reason(FORM, "other"), "Missing boundary");
+        assert_eq!(reason(&FORM[..FORM.len() - 30], "XyZ"), "Missing final boundary");
+        assert_eq!(reason(b"--b junk\r\n--b--", "b"), "Boundary is not followed by CRLF");
+        assert_eq!(reason(b"--b\r\n\r\nno headers\r\n--b--", "b"), "Part without a name");
+        assert_eq!(reason(b"--b\r\nContent-Disposition: form-data\r\n--b--", "b"),
+                   "Part headers are not followed by an empty line");
+    }
+}
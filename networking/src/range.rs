@@ -0,0 +1,161 @@
+// Range requests, which ask for a piece of a file rather than all of it.
+// Media players use them to skip ahead, and download managers to resume a
+// download which broke off halfway:
+//   Range: bytes=0-1023     the first 1024 bytes
+//   Range: bytes=1024-      everything from byte 1024 on
+//   Range: bytes=-500       the last 500 bytes
+// Positions count from 0 and both ends are included (RFC 7233).
+//
+// The answer is a 206 Partial Content with just those bytes, and a
+// Content-Range header saying which ones they are out of how many:
+//   Content-Range: bytes 0-1023/146515
+//
+// Several ranges may be asked for at once, "bytes=0-99,200-299", which are
+// sent as the parts of a multipart/byteranges body, each with a
+// Content-Range of its own.
+
+// The most ranges one request may ask for. Every one of them costs a file
+// to open, and a header listing thousands of ranges, all of them the whole
+// file, would make a small request a very large response
+const MAX_RANGES: usize = 16;
+
+// What a Range header asks of a file of a given length
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    // The bytes from the first position to the second one, both included,
+    // already cut down to the length of the file
+    Satisfiable(u64, u64),
+    // More than one of those, in the order they were asked for
+    Multiple(Vec<(u64, u64)>),
+    // Ranges which all start past the end of the file: a 416 Range Not
+    // Satisfiable
+    Unsatisfiable,
+}
+
+// Works out which bytes of a file `length` bytes long the header asks for.
+//
+// A header we do not understand is no reason to turn the request down: RFC
+// 7233 says to ignore it and send the whole file, which is what None means.
+// That includes a header with a single range in it we do not understand, and
+// one asking for more than MAX_RANGES ranges. Of the ones we do understand,
+// those which start past the end of the file are left out; only when that
+// leaves none is the request unsatisfiable
+pub fn parse_range(header: &str, length: u64) -> Option<ByteRange> {
+    let (unit, ranges) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let ranges: Vec<&str> = ranges.split(',').map(str::trim).collect();
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+
+    let mut satisfiable = Vec::new();
+    for range in ranges {
+        if let Some(range) = parse_one(range, length)? {
+            satisfiable.push(range);
+        }
+    }
+    Some(match satisfiable.len() {
+        0 => ByteRange::Unsatisfiable,
+        1 => ByteRange::Satisfiable(satisfiable[0].0, satisfiable[0].1),
+        _ => ByteRange::Multiple(satisfiable),
+    })
+}
+
+// One range of the header: None if we do not understand it, Some(None) if it
+// starts past the end of the file
+fn parse_one(range: &str, length: u64) -> Option<Option<(u64, u64)>> {
+    let (first, last) = range.split_once('-')?;
+
+    // Only plain digits: .parse() would let a "+" sign through too
+    let number = |text: &str| -> Option<u64> {
+        if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        text.parse().ok()
+    };
+
+    match (first, last) {
+        // The last n bytes. Asking for more bytes than the file has gets the
+        // whole file, but an empty file has no last bytes to give
+        ("", suffix) => {
+            let suffix = number(suffix)?;
+            if suffix == 0 || length == 0 {
+                return Some(None);
+            }
+            Some(Some((length.saturating_sub(suffix), length - 1)))
+        },
+        (first, last) => {
+            let first = number(first)?;
+            let last = if last.is_empty() { None } else { Some(number(last)?) };
+            // A range which ends before it starts is invalid rather than
+            // unsatisfiable, and so ignored
+            if last.map(|last| last < first).unwrap_or(false) {
+                return None;
+            }
+            if first >= length {
+                return Some(None);
+            }
+            // The end may be past the end of the file, which just means
+            // "up to the end"
+            let last = last.map(|last| last.min(length - 1)).unwrap_or(length - 1);
+            Some(Some((first, last)))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use self::ByteRange::*;
+
+    #[test]
+    fn parses_a_single_range() {
+        assert_eq!(parse_range("bytes=0-1023", 146515), Some(Satisfiable(0, 1023)));
+        assert_eq!(parse_range("bytes=1024-", 146515), Some(Satisfiable(1024, 146514)));
+        assert_eq!(parse_range("bytes=-500", 146515), Some(Satisfiable(146015, 146514)));
+        assert_eq!(parse_range("Bytes = 5-5", 10), Some(Satisfiable(5, 5)));
+    }
+
+    #[test]
+    fn cuts_a_range_down_to_the_file() {
+        assert_eq!(parse_range("bytes=5-100", 10), Some(Satisfiable(5, 9)));
+        assert_eq!(parse_range("bytes=-100", 10), Some(Satisfiable(0, 9)));
+    }
+
+    #[test]
+    fn finds_a_range_past_the_end_unsatisfiable() {
+        assert_eq!(parse_range("bytes=10-", 10), Some(Unsatisfiable));
+        assert_eq!(parse_range("bytes=-0", 10), Some(Unsatisfiable));
+        assert_eq!(parse_range("bytes=-5", 0), Some(Unsatisfiable));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Unsatisfiable));
+    }
+
+    #[test]
+    fn ignores_a_header_it_does_not_understand() {
+        for header in &["items=0-5", "bytes", "bytes=5", "bytes=9-5", "bytes=+1-5",
+                        "bytes=a-b", "bytes=-", "bytes=0x1-2"] {
+            assert_eq!(parse_range(header, 10), None, "{}", header);
+        }
+    }
+
+    #[test]
+    fn parses_several_ranges_leaving_out_those_past_the_end() {
+        assert_eq!(parse_range("bytes=0-99, 200-299", 1000),
+                   Some(Multiple(vec![(0, 99), (200, 299)])));
+        assert_eq!(parse_range("bytes=-1,0-0", 1000), Some(Multiple(vec![(999, 999), (0, 0)])));
+        assert_eq!(parse_range("bytes=0-99,5000-", 1000), Some(Satisfiable(0, 99)));
+        assert_eq!(parse_range("bytes=5000-,6000-", 1000), Some(Unsatisfiable));
+        // One range we do not understand spoils the header
+        assert_eq!(parse_range("bytes=0-99,oops", 1000), None);
+    }
+
+    #[test]
+    fn ignores_too_many_ranges() {
+        let ranges = vec!["0-0"; MAX_RANGES].join(",");
+        assert!(parse_range(&format!("bytes={}", ranges), 10).is_some());
+        assert_eq!(parse_range(&format!("bytes={},0-0", ranges), 10), None);
+    }
+}
@@ -0,0 +1,135 @@
+// Limits how many requests a single client can make, so that one of them
+// hammering the server cannot crowd out everybody else:
+//   cargo run -- --rate-limit 10 --rate-burst 20
+// lets every client make 10 requests a second, with up to 20 in a row
+// after being quiet for a while. A client over the limit gets a 429 Too
+// Many Requests, and a Retry-After header saying when to try again.
+//
+// Every client has a "token bucket". It holds up to `burst` tokens, every
+// request takes one out, and it fills back up at `rate` tokens a second. A
+// request finding the bucket empty is refused
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// The most clients we keep a bucket for. Once a new client would take us
+// past it, the ones whose buckets have filled up again are forgotten: they
+// would get a full bucket as new clients anyway. If that is not enough, as
+// when many clients turn up at once, the ones which have been quiet for the
+// longest are forgotten too, until KEEP_AFTER_PRUNE are left. Such a client
+// starts over with a full bucket, but memory stays bounded however many
+// addresses the requests come from, and the pruning, which goes through
+// every bucket, only happens once in a while
+const MAX_CLIENTS: usize = 10_000;
+const KEEP_AFTER_PRUNE: usize = MAX_CLIENTS / 10 * 9;
+
+struct Bucket {
+    tokens: f64,
+    // When the tokens were last counted
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    // Tokens per second
+    rate: f64,
+    burst: f64,
+    // Shared by all of the worker threads, like the counters in stats.rs
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> RateLimiter {
+        RateLimiter {
+            rate,
+            burst: f64::from(burst),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes a token out of the client's bucket. When there is none, the
+    // error is how many seconds to wait for the next one, rounded up
+    pub fn check(&self, ip: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&ip) {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&ip) {
+            // .select_nth_unstable() puts the element which would be at that
+            // index if the slice were sorted there, without sorting all of
+            // it: here, the time of the newest bucket to go
+            let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+            let oldest_kept = updated.len() - KEEP_AFTER_PRUNE;
+            let cutoff = *updated.select_nth_unstable(oldest_kept - 1).1;
+            buckets.retain(|_, bucket| bucket.updated > cutoff);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: self.burst, updated: now });
+        // The bucket is only topped up when a request comes in, with
+        // whatever accumulated since the last one
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil() as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n))
+    }
+
+    #[test]
+    fn lets_a_burst_through_then_says_how_long_to_wait() {
+        let limiter = RateLimiter::new(0.5, 3);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(ip(1)), Ok(()));
+        }
+        // A token every 2 seconds
+        assert_eq!(limiter.check(ip(1)), Err(2));
+        // Every client has a bucket of its own
+        assert_eq!(limiter.check(ip(2)), Ok(()));
+    }
+
+    #[test]
+    fn fills_the_bucket_back_up_over_time() {
+        let limiter = RateLimiter::new(50.0, 1);
+        assert_eq!(limiter.check(ip(1)), Ok(()));
+        assert_eq!(limiter.check(ip(1)), Err(1));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(limiter.check(ip(1)), Ok(()));
+    }
+
+    #[test]
+    fn forgets_the_quietest_clients_past_the_cap() {
+        let limiter = RateLimiter::new(0.001, 1);
+        for n in 0..MAX_CLIENTS as u32 {
+            assert_eq!(limiter.check(ip(n)), Ok(()));
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_CLIENTS);
+
+        assert_eq!(limiter.check(ip(MAX_CLIENTS as u32)), Ok(()));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), KEEP_AFTER_PRUNE + 1);
+        // The first client was forgotten and starts over with a full
+        // bucket, the last one it kept still has an empty one
+        assert_eq!(limiter.check(ip(0)), Ok(()));
+        assert!(limiter.check(ip(MAX_CLIENTS as u32 - 1)).is_err());
+    }
+}
@@ -0,0 +1,461 @@
+// The request line and headers sent by the client, parsed into something the
+// rest of the server can look at without dealing with raw bytes.
+//
+// An HTTP request starts with a request line such as
+//   GET / HTTP/1.1
+// followed by one header per line, each line terminated by "\r\n". The
+// headers end at the first empty line; a body, if any, comes after that
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use error::{ParseError, ServerError};
+
+// The verb at the start of the request line. Deriving PartialEq lets us
+// compare methods with ==; Eq and Hash let them be used as HashMap keys.
+// None of the variants hold any data, so Copy costs nothing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Head,
+    Options,
+    Patch,
+}
+
+impl Method {
+    // The method as it appears on the request line
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Head => "HEAD",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+// FromStr is the trait behind str's .parse(): with it, "GET".parse::<Method>()
+// works just like "42".parse::<u32>() does. Methods are case-sensitive, so
+// "get" is not a method we know. Neither is anything we do not implement,
+// like CONNECT or TRACE: the client gets a 501 Not Implemented for those
+impl FromStr for Method {
+    type Err = ParseError;
+
+    fn from_str(name: &str) -> Result<Method, ParseError> {
+        match name {
+            "GET" => Ok(Method::Get),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "HEAD" => Ok(Method::Head),
+            "OPTIONS" => Ok(Method::Options),
+            "PATCH" => Ok(Method::Patch),
+            _ => Err(ParseError::UnknownMethod(name.to_owned())),
+        }
+    }
+}
+
+// Display is what "{}" uses, so methods can be printed as they are written
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// Unlike the buffer it is parsed from, a Request owns all of its data
+// (String rather than &str), so it can be kept around and handed to other
+// functions without worrying about lifetimes
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    // The target exactly as sent, query string included, e.g.
+    // "/hello%20world.html?x=1"
+    pub target: String,
+    // The path part of the target, decoded: "/hello world.html". This is
+    // what picks a route
+    pub path: String,
+    // The query string, the part of the target after the "?", as decoded
+    // name/value pairs: "?q=rust&page=2" gives q => "rust", page => "2".
+    // A name given more than once keeps the last of its values
+    pub query: HashMap<String, String>,
+    // E.g. "HTTP/1.1"
+    pub version: String,
+    // Header names are case-insensitive, so they are stored in lowercase.
+    // Use .header() rather than indexing the map directly
+    pub headers: HashMap<String, String>,
+    // The bytes following the headers, as many as Content-Length said. This
+    // is not part of what .parse() looks at: the body is read separately,
+    // once we know from the headers how long it is, and stays empty for a
+    // request without one
+    pub body: Vec<u8>,
+    // The segments of the path captured by the route which matched, e.g. id
+    // => "42" for /users/42 and the route /users/:id. Filled in by
+    // Router::dispatch(), and empty for routes without captures
+    pub params: HashMap<String, String>,
+}
+
+impl Request {
+    // Parses the request line and headers at the start of `bytes`. Whatever
+    // follows the empty line ending the headers is left alone, and the body
+    // of the result is empty.
+    //
+    // If the client stopped sending halfway through, the last header may
+    // have been cut in half. Only the lines which are complete are looked at:
+    // up to the empty line if we have it, otherwise up to the last "\r\n"
+    pub fn parse(bytes: &[u8]) -> Result<Request, ParseError> {
+        // ::from_utf8_lossy() replaces invalid utf-8 sequences with the
+        // unicode replacement character � instead of failing
+        let text = String::from_utf8_lossy(bytes);
+
+        let head = match text.find("\r\n\r\n") {
+            Some(end) => &text[..end],
+            None => match text.rfind("\r\n") {
+                Some(end) => &text[..end],
+                // Without a single "\r\n" we do not even have the whole
+                // request line
+                None if text.trim().is_empty() => {
+                    return Err(ParseError::MissingRequestLine)
+                },
+                None => return Err(ParseError::MissingCrlf),
+            },
+        };
+
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or("");
+        if request_line.trim().is_empty() {
+            return Err(ParseError::MissingRequestLine);
+        }
+
+        // Slice patterns let us check the number of pieces and name them at
+        // the same time
+        let parts: Vec<&str> = request_line.split_whitespace().collect();
+        let (method, path, version) = match parts[..] {
+            [method, path, version] => (method, path, version),
+            _ => return Err(ParseError::MalformedRequestLine(request_line.to_owned())),
+        };
+        if !is_valid_version(version) {
+            return Err(ParseError::InvalidVersion(version.to_owned()));
+        }
+        // The target is a path, or "*" for an OPTIONS request about the
+        // server as a whole. (HTTP also allows whole URLs, but only proxies
+        // get sent those)
+        if !path.starts_with('/') && path != "*" {
+            return Err(ParseError::InvalidTarget(path.to_owned()));
+        }
+
+        // Every "Name: value" line becomes an entry of the map. .ok_or_else()
+        // turns the Option returned by .split_once() into a Result, so that
+        // "?" can bail out on the first line without a colon.
+        //
+        // A header sent more than once is the same as a single one with all
+        // the values separated by commas (RFC 7230, section 3.2.2), so that
+        // is how repeated headers are stored
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for line in lines {
+            let (name, value) = line.split_once(':')
+                .ok_or_else(|| ParseError::MalformedHeader(line.to_owned()))?;
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+            headers.entry(name)
+                .and_modify(|existing| {
+                    existing.push_str(", ");
+                    existing.push_str(value);
+                })
+                .or_insert_with(|| value.to_owned());
+        }
+
+        // The query string, everything after the first "?", is not part of
+        // the path
+        let (raw_path, raw_query) = match path.split_once('?') {
+            Some((raw_path, raw_query)) => (raw_path, raw_query),
+            None => (path, ""),
+        };
+        let decoded = percent_decode(raw_path)?;
+        let query = parse_query(raw_query)?;
+
+        // The method is checked last: a request which is broken in other
+        // ways as well is a bad request first and foremost
+        Ok(Request {
+            method: method.parse()?,
+            target: path.to_owned(),
+            path: decoded,
+            query,
+            version: version.to_owned(),
+            headers,
+            body: Vec::new(),
+            params: HashMap::new(),
+        })
+    }
+
+    // Looks up a header by name, ignoring case. .map(String::as_str) turns
+    // the Option<&String> into the more convenient Option<&str>
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    // Parses the body as JSON into any type serde knows how to build, e.g.
+    //   let point: Point = request.json()?;
+    // DeserializeOwned means that the result owns its data rather than
+    // borrowing it from the body.
+    //
+    // Only bodies which say they are JSON are parsed: anything else gets a
+    // 415 Unsupported Media Type, and JSON which does not fit T a 400
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, ServerError> {
+        let content_type = self.header("Content-Type").unwrap_or("");
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        if !media_type.eq_ignore_ascii_case("application/json") {
+            return Err(ServerError::UnsupportedMediaType(content_type.to_owned()));
+        }
+        serde_json::from_slice(&self.body).map_err(ServerError::InvalidJson)
+    }
+}
+
+// The other way around from percent_decode(): escapes every byte of `text`
+// but letters, digits and "-._~", the characters which never need one
+// (RFC 3986, section 2.3). Meant for a single segment of a path, so "/" is
+// escaped too
+pub fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for &byte in text.as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            },
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// The version is "HTTP/" followed by a major and a minor version of a single
+// digit each (RFC 7230, section 2.6), e.g. "HTTP/1.1"
+fn is_valid_version(version: &str) -> bool {
+    match version.strip_prefix("HTTP/").map(str::as_bytes) {
+        Some(&[major, b'.', minor]) => major.is_ascii_digit() && minor.is_ascii_digit(),
+        _ => false,
+    }
+}
+
+// URLs can only contain a limited set of characters, so anything else (a
+// space, a "?" which is not the start of the query, non-ASCII text...) is
+// sent "percent-encoded": every byte becomes a "%" followed by its value in
+// two hex digits. A space is %20, and "é", two bytes in UTF-8, is %C3%A9.
+//
+// This turns the escapes back into bytes, and checks that the bytes make
+// valid UTF-8. A "%" which is not followed by two hex digits is an error
+pub fn percent_decode(encoded: &str) -> Result<String, ParseError> {
+    let invalid = || ParseError::InvalidPercentEncoding(encoded.to_owned());
+
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            // .get() returns None rather than panicking when the string ends
+            // too early, as in "abc%2"
+            let high = bytes.get(i + 1).and_then(|&b| hex_value(b)).ok_or_else(invalid)?;
+            let low = bytes.get(i + 2).and_then(|&b| hex_value(b)).ok_or_else(invalid)?;
+            decoded.push(high * 16 + low);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+// Splits a query string into its name=value pairs, which are separated by
+// "&". Both names and values are percent-decoded; a "+" stands for a space,
+// as that is how HTML forms encode them (a real "+" comes as %2B).
+//
+// A pair without a "=" is a name with an empty value, so "?debug" gives
+// debug => "". A name given more than once keeps the last of its values,
+// since .insert() replaces whatever the map held already
+fn parse_query(query: &str) -> Result<HashMap<String, String>, ParseError> {
+    let decode = |text: &str| percent_decode(&text.replace('+', " "));
+
+    let mut pairs = HashMap::new();
+    // Empty pairs, as in "a=1&&b=2" or a trailing "&", are skipped
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        pairs.insert(decode(name)?, decode(value)?);
+    }
+    Ok(pairs)
+}
+
+// The value of a single hex digit, in either case
+fn hex_value(digit: u8) -> Option<u8> {
+    // .to_digit(16) does the work for chars; a u8 is turned into one with
+    // "as char"
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(head: &str) -> ParseError {
+        Request::parse(head.as_bytes()).unwrap_err()
+    }
+
+    #[test]
+    fn parses_the_request_line_and_headers() {
+        let request = Request::parse(b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\
+                                      User-Agent:curl/8.4.0 \r\n\r\nbody").unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.target, "/index.html");
+        assert_eq!(request.path, "/index.html");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.header("HOST"), Some("example.com"));
+        assert_eq!(request.header("user-agent"), Some("curl/8.4.0"));
+        assert_eq!(request.headers.len(), 2);
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn joins_a_header_sent_twice() {
+        let request = Request::parse(b"GET / HTTP/1.1\r\nAccept: text/html\r\n\
+                                      accept: */*\r\n\r\n").unwrap();
+        assert_eq!(request.header("Accept"), Some("text/html, */*"));
+    }
+
+    #[test]
+    fn parses_what_arrived_of_a_head_cut_short() {
+        let request = Request::parse(b"GET / HTTP/1.1\r\nHost: x\r\nUser-Ag").unwrap();
+        assert_eq!(request.header("Host"), Some("x"));
+        assert_eq!(request.header("User-Agent"), None);
+    }
+
+    #[test]
+    fn refuses_a_broken_request() {
+        assert_eq!(error(""), ParseError::MissingRequestLine);
+        assert_eq!(error("\r\n\r\n"), ParseError::MissingRequestLine);
+        assert_eq!(error("GET / HTTP/1.1"), ParseError::MissingCrlf);
+        assert_eq!(error("GET /\r\n\r\n"),
+                   ParseError::MalformedRequestLine("GET /".to_owned()));
+        assert_eq!(error("GET / HTTP/1.1 extra\r\n\r\n"),
+                   ParseError::MalformedRequestLine("GET / HTTP/1.1 extra".to_owned()));
+        assert_eq!(error("GET / HTTP/11\r\n\r\n"), ParseError::InvalidVersion("HTTP/11".to_owned()));
+        assert_eq!(error("GET / FTP/1.1\r\n\r\n"), ParseError::InvalidVersion("FTP/1.1".to_owned()));
+        assert_eq!(error("GET index.html HTTP/1.1\r\n\r\n"),
+                   ParseError::InvalidTarget("index.html".to_owned()));
+        assert_eq!(error("GET / HTTP/1.1\r\nNo colon\r\n\r\n"),
+                   ParseError::MalformedHeader("No colon".to_owned()));
+    }
+
+    #[test]
+    fn accepts_an_asterisk_as_the_target() {
+        let request = Request::parse(b"OPTIONS * HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(request.path, "*");
+    }
+
+    #[test]
+    fn parses_and_prints_every_method() {
+        for name in &["GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH"] {
+            let method: Method = name.parse().unwrap();
+            assert_eq!(method.as_str(), *name);
+            assert_eq!(method.to_string(), *name);
+        }
+    }
+
+    #[test]
+    fn does_not_know_other_methods() {
+        assert_eq!("get".parse::<Method>(), Err(ParseError::UnknownMethod("get".to_owned())));
+        assert_eq!("CONNECT".parse::<Method>(),
+                   Err(ParseError::UnknownMethod("CONNECT".to_owned())));
+        // Only once the request is otherwise fine
+        assert_eq!(error("TRACE / HTTP/1.1\r\n\r\n"), ParseError::UnknownMethod("TRACE".to_owned()));
+        assert_eq!(error("TRACE / HTTP/9\r\n\r\n"), ParseError::InvalidVersion("HTTP/9".to_owned()));
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("/hello%20world.html"), Ok("/hello world.html".to_owned()));
+        assert_eq!(percent_decode("/caf%C3%a9"), Ok("/café".to_owned()));
+        assert_eq!(percent_decode("/a+b%2B"), Ok("/a+b+".to_owned()));
+        assert_eq!(percent_decode("/plain"), Ok("/plain".to_owned()));
+    }
+
+    #[test]
+    fn refuses_broken_percent_escapes() {
+        for encoded in &["/abc%2", "/abc%", "/%zz", "/%C3"] {
+            assert_eq!(percent_decode(encoded),
+                       Err(ParseError::InvalidPercentEncoding(encoded.to_string())));
+        }
+        assert_eq!(error("GET /%zz HTTP/1.1\r\n\r\n"),
+                   ParseError::InvalidPercentEncoding("/%zz".to_owned()));
+    }
+
+    #[test]
+    fn keeps_the_target_as_sent_and_decodes_the_path() {
+        let request = Request::parse(b"GET /hello%20world.html?x=1 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.target, "/hello%20world.html?x=1");
+        assert_eq!(request.path, "/hello world.html");
+    }
+
+    fn query(target: &str) -> HashMap<String, String> {
+        let head = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        Request::parse(head.as_bytes()).unwrap().query
+    }
+
+    #[test]
+    fn parses_the_query_string() {
+        let query = query("/search?q=rust+lang&page=2&debug&&tag=a%26b&");
+        assert_eq!(query.len(), 4);
+        assert_eq!(query["q"], "rust lang");
+        assert_eq!(query["page"], "2");
+        assert_eq!(query["debug"], "");
+        assert_eq!(query["tag"], "a&b");
+    }
+
+    #[test]
+    fn keeps_the_last_value_of_a_name_given_twice() {
+        assert_eq!(query("/?n=1&n=2")["n"], "2");
+        assert!(query("/").is_empty());
+        assert!(query("/?").is_empty());
+    }
+
+    #[test]
+    fn refuses_a_broken_escape_in_the_query() {
+        assert_eq!(error("GET /?q=%G1 HTTP/1.1\r\n\r\n"),
+                   ParseError::InvalidPercentEncoding("%G1".to_owned()));
+    }
+
+    fn with_body(content_type: Option<&str>, body: &str) -> Request {
+        let head = match content_type {
+            Some(content_type) => format!("POST / HTTP/1.1\r\nContent-Type: {}\r\n\r\n",
+                                          content_type),
+            None => "POST / HTTP/1.1\r\n\r\n".to_owned(),
+        };
+        let mut request = Request::parse(head.as_bytes()).unwrap();
+        request.body = body.as_bytes().to_vec();
+        request
+    }
+
+    #[test]
+    fn parses_a_json_body() {
+        let request = with_body(Some("Application/JSON; charset=utf-8"), r#"{"n": 10}"#);
+        let value: HashMap<String, u32> = request.json().unwrap();
+        assert_eq!(value["n"], 10);
+    }
+
+    #[test]
+    fn refuses_a_body_which_is_not_json() {
+        let result: Result<u32, _> = with_body(Some("text/plain"), "10").json();
+        assert!(matches!(result, Err(ServerError::UnsupportedMediaType(ref t)) if t == "text/plain"));
+        let result: Result<u32, _> = with_body(None, "10").json();
+        assert!(matches!(result, Err(ServerError::UnsupportedMediaType(ref t)) if t.is_empty()));
+        let result: Result<u32, _> = with_body(Some("application/json"), "{ten}").json();
+        assert!(matches!(result, Err(ServerError::InvalidJson(_))));
+    }
+}
@@ -0,0 +1,559 @@
+// What the server sends back: a status line, headers and a body, e.g.
+//   HTTP/1.1 200 OK
+//   Content-Type: text/plain; charset=utf-8
+//   Content-Length: 3
+//   Date: Sun, 06 Nov 1994 08:49:37 GMT
+//
+//   55
+//
+// Responses are put together with a chain of calls, each one taking the
+// response by value and handing it back (the "builder" pattern):
+//   Response::new(200).header("Content-Type", TEXT).body("55\n")
+// and .write_to() sends the result over the connection.
+//
+// A body which is only worked out bit by bit, and whose length we do not know
+// up front, can be sent as it is produced instead, in "chunks"; see
+// .chunked()
+//
+// A body which is already sitting in a file, and may be too large to read
+// into memory, can be copied over from it instead; see .file()
+//
+// A response can also hand the connection over to another protocol once it
+// has been sent, see .upgrade()
+use std::fmt;
+use std::io::{self, BufWriter, Read, Write};
+use std::time::SystemTime;
+
+use serde::Serialize;
+use serde_json;
+
+use date::http_date;
+use error::ServerError;
+
+// What is sent when nobody said what the body is: "some bytes"
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+const JSON: &str = "application/json";
+const TEXT: &str = "text/plain; charset=utf-8";
+
+// Writes out a chunked body, see .chunked(). A Box<dyn FnOnce> can hold any
+// closure which is called once, whatever it captured; &mut dyn Write is
+// whatever the closure writes the body to
+pub type Producer = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()>>;
+
+// Anything which can be both read from and written to, like a connection. A
+// trait object can only name one trait besides auto traits like Send, so
+// "dyn Read + Write" is not allowed; "dyn Stream" is the way around it. The
+// blanket impl makes every type which is both, a Stream too
+pub trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+// Takes over the connection after a 101 Switching Protocols, see .upgrade()
+pub type Upgrade = Box<dyn FnOnce(&mut dyn Stream) -> io::Result<()>>;
+
+pub struct Response {
+    status: u16,
+    // A Vec rather than a HashMap keeps the headers in the order they were
+    // set, which makes the output predictable
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // Set for a chunked body, in which case `body` stays empty
+    producer: Option<Producer>,
+    // Set by .unchunked(): the body the producer writes goes out as it is,
+    // without chunks, and ends where the connection does
+    until_close: bool,
+    // Set for a body copied from a file, along with its length. `body`
+    // stays empty then too
+    file: Option<(Box<dyn Read>, u64)>,
+    upgrade: Option<Upgrade>,
+}
+
+// Closures have no Debug of their own, so Debug is written by hand rather
+// than derived, leaving the closures out
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", &self.body)
+            .field("chunked", &self.producer.is_some())
+            .field("until_close", &self.until_close)
+            .field("file", &self.file.as_ref().map(|&(_, len)| len))
+            .field("upgrade", &self.upgrade.is_some())
+            .finish()
+    }
+}
+
+impl Response {
+    // A response with the given status code, no headers and an empty body
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            producer: None,
+            until_close: false,
+            file: None,
+            upgrade: None,
+        }
+    }
+
+    // Sets a header, replacing any earlier value of it. Header names are
+    // case-insensitive, so "content-type" replaces "Content-Type" too
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    // Sets the body. Into<Vec<u8>> is implemented for Vec<u8> itself, but
+    // also for &str, String and &[u8], so any of those can be passed in
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = body.into();
+        self.producer = None;
+        self.file = None;
+        self
+    }
+
+    // Makes the body the next `len` bytes of `file`, copied over a buffer at
+    // a time while the response is being sent, e.g.
+    //   Response::new(200).file(File::open(path)?, len)
+    // so that only that buffer is ever in memory, however large the file.
+    // Unlike a chunked body, the length is known up front, and the response
+    // has a Content-Length like any other. A file which turns out to be
+    // shorter, because it shrank after we looked at it, closes the
+    // connection, as the client is waiting for bytes which are not coming
+    pub fn file<R: Read + 'static>(mut self, file: R, len: u64) -> Response {
+        self.body = Vec::new();
+        self.producer = None;
+        self.file = Some((Box::new(file), len));
+        self
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file.is_some()
+    }
+
+    // Makes the body a chunked one, written by `producer` while the response
+    // is being sent rather than up front, e.g.
+    //   Response::new(200).chunked(|out| {
+    //       for i in 0..10 {
+    //           writeln!(out, "line {}", i)?;
+    //           out.flush()?;
+    //       }
+    //       Ok(())
+    //   })
+    // The response goes out with "Transfer-Encoding: chunked" instead of a
+    // Content-Length, and the body as a series of chunks, each one its
+    // length in hex, "\r\n", the bytes and another "\r\n". A chunk of length
+    // 0 marks the end (RFC 7230, section 4.1):
+    //   5\r\nhello\r\n0\r\n\r\n
+    // What the producer writes is buffered, and a chunk is sent whenever the
+    // buffer is full or the producer calls .flush(). An error returned by
+    // the producer closes the connection: with the head sent already, there
+    // is no other way left to tell the client
+    pub fn chunked<F>(mut self, producer: F) -> Response
+        where F: FnOnce(&mut dyn Write) -> io::Result<()> + 'static {
+        self.body = Vec::new();
+        self.file = None;
+        self.producer = Some(Box::new(producer));
+        self
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.producer.is_some()
+    }
+
+    // Has a chunked body sent without the chunks, neither Transfer-Encoding
+    // nor Content-Length saying where it ends: the client reads until the
+    // connection is closed, which has to happen once the body is sent.
+    // HTTP/1.0 clients do not know about chunks, so they get this instead.
+    // What the producer writes still goes out as it is written, which an
+    // event stream depends on; a response which is not chunked is left alone
+    pub fn unchunked(mut self) -> Response {
+        self.until_close = self.is_chunked();
+        self
+    }
+
+    // Whether the end of the body is the end of the connection, see
+    // .unchunked()
+    pub fn is_until_close(&self) -> bool {
+        self.until_close
+    }
+
+    // Has the connection taken over by `upgrade` once the response has been
+    // sent. This is for a 101 Switching Protocols: from then on the
+    // connection speaks another protocol, e.g. WebSocket, and `upgrade` is
+    // what speaks it. The connection is closed when it returns
+    pub fn upgrade<F>(mut self, upgrade: F) -> Response
+        where F: FnOnce(&mut dyn Stream) -> io::Result<()> + 'static {
+        self.upgrade = Some(Box::new(upgrade));
+        self
+    }
+
+    pub fn is_upgrade(&self) -> bool {
+        self.upgrade.is_some()
+    }
+
+    // Takes what takes over the connection out of the response, which
+    // .write_to() is then free to consume
+    pub fn take_upgrade(&mut self) -> Option<Upgrade> {
+        self.upgrade.take()
+    }
+
+    // A response with `value` as its JSON body, e.g.
+    //   Response::json(200, &Health { status: "ok", ... })
+    // Content-Length is taken care of by .head(), like for every other body.
+    //
+    // Serializing can only fail for values JSON has no way of writing, such
+    // as a map with non-string keys, which is a bug on our side: serde_json's
+    // error converts into an io::Error, and from there into a 500
+    pub fn json<T: Serialize>(status: u16, value: &T) -> Result<Response, ServerError> {
+        let body = serde_json::to_vec(value).map_err(io::Error::from)?;
+        Ok(Response::new(status).header("Content-Type", JSON).body(body))
+    }
+
+    // A response sending the client to `location` instead, e.g.
+    //   Response::redirect(301, "/new")
+    // The status says what kind of redirect it is:
+    // - 301 Moved Permanently and 308 Permanent Redirect: for good, so
+    //   clients may remember it
+    // - 302 Found and 307 Temporary Redirect: just this once
+    // - 303 See Other: the answer to this request is over there, to be
+    //   fetched with a GET
+    // 307 and 308 promise that the method and body are kept, which browsers
+    // do not always do for a 301 or a 302.
+    //
+    // Any other status, or a location which would break the header, is a bug
+    // in the calling code, and an error like for .json()
+    pub fn redirect(status: u16, location: &str) -> Result<Response, ServerError> {
+        let invalid = |message: String| {
+            ServerError::from(io::Error::new(io::ErrorKind::InvalidInput, message))
+        };
+        if ![301, 302, 303, 307, 308].contains(&status) {
+            return Err(invalid(format!("{} is not a redirect status", status)));
+        }
+        if location.is_empty() || location.contains(|c: char| c.is_control()) {
+            return Err(invalid(format!("Invalid redirect location: {:?}", location)));
+        }
+
+        // Clients follow the Location header on their own; the body is for
+        // people looking at the response
+        Ok(Response::new(status)
+           .header("Location", location)
+           .header("Content-Type", TEXT)
+           .body(format!("Redirecting to {}\n", location)))
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn body_bytes(&self) -> &[u8] {
+        &self.body
+    }
+
+    // The value of a header, if it was set
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&(existing, _)| existing.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Whether the response is allowed a body at all. 1xx, 204 No Content and
+    // 304 Not Modified never have one (RFC 7230, section 3.3)
+    fn has_body(&self) -> bool {
+        !(self.status < 200 || self.status == 204 || self.status == 304)
+    }
+
+    // The status line and headers, up to and including the empty line which
+    // ends them.
+    //
+    // Content-Length tells the client how many bytes of body to expect, and
+    // is always worked out from the body itself so that it cannot be wrong.
+    // Responses which cannot have a body get no Content-Length nor
+    // Content-Type; RFC 7230 forbids the former for a 204. Date is mandatory
+    // (RFC 7231, section 7.1.1.2), so it gets filled in if it is missing
+    pub fn head(&self) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+
+        for (name, value) in &self.headers {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        if self.has_body() {
+            if self.get_header("Content-Type").is_none() {
+                head.push_str(&format!("Content-Type: {}\r\n", DEFAULT_CONTENT_TYPE));
+            }
+            match self.file {
+                _ if self.until_close => {},
+                _ if self.is_chunked() => head.push_str("Transfer-Encoding: chunked\r\n"),
+                Some((_, len)) => head.push_str(&format!("Content-Length: {}\r\n", len)),
+                None => head.push_str(&format!("Content-Length: {}\r\n", self.body.len())),
+            }
+        }
+        if self.get_header("Date").is_none() {
+            head.push_str(&format!("Date: {}\r\n", http_date(SystemTime::now())));
+        }
+
+        head.push_str("\r\n");
+        head
+    }
+
+    // Writes the whole response to `out`, the body included unless
+    // `head_only` is set, and returns the size of the body sent: the bytes
+    // of the chunks without what frames them, for a chunked one. Whatever
+    // body a response which cannot have one was given is left out
+    pub fn write_to<W: Write>(self, out: &mut W, head_only: bool) -> io::Result<usize> {
+        out.write_all(self.head().as_bytes())?;
+        if head_only || !self.has_body() {
+            return Ok(0);
+        }
+
+        if let Some((file, len)) = self.file {
+            let copied = io::copy(&mut file.take(len), out)?;
+            if copied < len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "File ended before its length"));
+            }
+            return Ok(copied as usize);
+        }
+        match self.producer {
+            Some(producer) => {
+                let framed = !self.until_close;
+                let mut chunks = ChunkedWriter { out, framed, written: 0 };
+                {
+                    let mut buffered = BufWriter::new(&mut chunks);
+                    producer(&mut buffered)?;
+                    buffered.flush()?;
+                }
+                if framed {
+                    chunks.out.write_all(b"0\r\n\r\n")?;
+                }
+                Ok(chunks.written)
+            },
+            None => {
+                out.write_all(&self.body)?;
+                Ok(self.body.len())
+            },
+        }
+    }
+}
+
+// Turns every write into one chunk of a chunked body, or passes it on as it
+// is when not `framed`, see Response::unchunked()
+struct ChunkedWriter<'a, W: Write + 'a> {
+    out: &'a mut W,
+    framed: bool,
+    written: usize,
+}
+
+impl<'a, W: Write> Write for ChunkedWriter<'a, W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        // An empty chunk would mark the end of the body
+        if bytes.is_empty() {
+            return Ok(0);
+        }
+        if !self.framed {
+            self.out.write_all(bytes)?;
+            self.written += bytes.len();
+            return Ok(bytes.len());
+        }
+        write!(self.out, "{:x}\r\n", bytes.len())?;
+        self.out.write_all(bytes)?;
+        self.out.write_all(b"\r\n")?;
+        self.written += bytes.len();
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// The reason phrase which goes with each status code. Clients are not
+// supposed to care about it, but people reading the responses do
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "CONTINUE",
+        101 => "SWITCHING PROTOCOLS",
+        200 => "OK",
+        201 => "CREATED",
+        204 => "NO CONTENT",
+        206 => "PARTIAL CONTENT",
+        301 => "MOVED PERMANENTLY",
+        302 => "FOUND",
+        303 => "SEE OTHER",
+        304 => "NOT MODIFIED",
+        307 => "TEMPORARY REDIRECT",
+        308 => "PERMANENT REDIRECT",
+        400 => "BAD REQUEST",
+        401 => "UNAUTHORIZED",
+        403 => "FORBIDDEN",
+        404 => "NOT FOUND",
+        405 => "METHOD NOT ALLOWED",
+        408 => "REQUEST TIMEOUT",
+        411 => "LENGTH REQUIRED",
+        413 => "PAYLOAD TOO LARGE",
+        414 => "URI TOO LONG",
+        415 => "UNSUPPORTED MEDIA TYPE",
+        416 => "RANGE NOT SATISFIABLE",
+        426 => "UPGRADE REQUIRED",
+        429 => "TOO MANY REQUESTS",
+        500 => "INTERNAL SERVER ERROR",
+        501 => "NOT IMPLEMENTED",
+        503 => "SERVICE UNAVAILABLE",
+        // Anything else still gets a phrase matching its class
+        _ => match status / 100 {
+            1 => "INFORMATIONAL",
+            2 => "SUCCESS",
+            3 => "REDIRECTION",
+            4 => "CLIENT ERROR",
+            _ => "SERVER ERROR",
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What .write_to() sends, as text
+    fn sent(response: Response, head_only: bool) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out, head_only).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn sends_a_204_without_a_body_or_content_length() {
+        let response = Response::new(204)
+            .header("Date", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .body("dropped");
+        assert_eq!(sent(response, false),
+                   "HTTP/1.1 204 NO CONTENT\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n");
+    }
+
+    #[test]
+    fn sends_a_304_without_a_body_or_content_length() {
+        let sent = sent(Response::new(304).body("dropped"), false);
+        assert!(sent.starts_with("HTTP/1.1 304 NOT MODIFIED\r\n"));
+        assert!(!sent.contains("Content-Length"));
+        assert!(sent.ends_with("\r\n\r\n"));
+    }
+
+    const DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    #[test]
+    fn writes_the_status_line_headers_and_body() {
+        let response = Response::new(200)
+            .header("Content-Type", TEXT)
+            .header("Date", DATE)
+            .body("55\n");
+        assert_eq!(sent(response, false),
+                   "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                    Date: Sun, 06 Nov 1994 08:49:37 GMT\r\nContent-Length: 3\r\n\r\n55\n");
+    }
+
+    #[test]
+    fn works_out_content_length_and_defaults_the_content_type() {
+        let response = Response::new(200)
+            .header("Content-Length", "1000")
+            .header("Date", DATE)
+            .body(vec![0u8; 4]);
+        assert_eq!(sent(response, false),
+                   "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+                    Content-Type: application/octet-stream\r\nContent-Length: 4\r\n\r\n\0\0\0\0");
+    }
+
+    #[test]
+    fn replaces_a_header_set_twice_regardless_of_case() {
+        let response = Response::new(200).header("X-A", "1").header("x-a", "2");
+        assert_eq!(response.get_header("X-A"), Some("2"));
+        assert_eq!(sent(response, false).matches("-a: ").count(), 1);
+    }
+
+    #[test]
+    fn leaves_the_body_out_when_told_to() {
+        let response = Response::new(200).header("Date", DATE).body("55\n");
+        assert_eq!(sent(response, true),
+                   "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+                    Content-Type: application/octet-stream\r\nContent-Length: 3\r\n\r\n");
+    }
+
+    #[test]
+    fn has_a_reason_phrase_for_every_status() {
+        assert_eq!(reason(404), "NOT FOUND");
+        assert_eq!(reason(299), "SUCCESS");
+        assert_eq!(reason(599), "SERVER ERROR");
+    }
+
+    #[test]
+    fn sends_a_chunk_for_every_flush() {
+        let response = Response::new(200).header("Date", DATE).chunked(|out| {
+            out.write_all(b"hello")?;
+            out.flush()?;
+            out.write_all(b", ")?;
+            out.write_all(b"world")?;
+            Ok(())
+        });
+        assert!(response.is_chunked());
+        let mut out = Vec::new();
+        assert_eq!(response.write_to(&mut out, false).unwrap(), 12);
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+                    Content-Type: application/octet-stream\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn sends_an_unchunked_body_as_it_is() {
+        let response = Response::new(200).header("Date", DATE)
+            .chunked(|out| out.write_all(b"data: 1\n\n"))
+            .unchunked();
+        assert!(response.is_until_close());
+        let sent = sent(response, false);
+        assert!(!sent.contains("Transfer-Encoding") && !sent.contains("Content-Length"));
+        assert!(sent.ends_with("\r\n\r\ndata: 1\n\n"), "{}", sent);
+
+        // Only a chunked body ends with the connection
+        assert!(!Response::new(200).body("x").unchunked().is_until_close());
+    }
+
+    #[test]
+    fn fails_when_the_producer_does() {
+        let response = Response::new(200).chunked(|_| Err(io::Error::other("broken")));
+        assert!(response.write_to(&mut Vec::new(), false).is_err());
+    }
+
+    #[test]
+    fn serializes_a_json_body() {
+        let response = Response::json(201, &vec![1, 2, 3]).unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.get_header("Content-Type"), Some(JSON));
+        assert_eq!(response.body_bytes(), b"[1,2,3]");
+    }
+
+    #[test]
+    fn redirects_with_a_location() {
+        let response = Response::redirect(301, "/new").unwrap();
+        assert_eq!(response.status(), 301);
+        assert_eq!(response.get_header("Location"), Some("/new"));
+        assert_eq!(response.body_bytes(), b"Redirecting to /new\n");
+        for &status in &[302, 303, 307, 308] {
+            assert_eq!(Response::redirect(status, "https://example.com/").unwrap().status(),
+                       status);
+        }
+    }
+
+    #[test]
+    fn refuses_to_redirect_with_another_status_or_a_broken_location() {
+        assert!(Response::redirect(200, "/new").is_err());
+        assert!(Response::redirect(304, "/new").is_err());
+        assert!(Response::redirect(302, "").is_err());
+        assert!(Response::redirect(302, "/new\r\nSet-Cookie: a=b").is_err());
+    }
+}
@@ -0,0 +1,481 @@
+// Picks the function which handles a request, based on its method and path.
+//
+// Routes are registered up front with .add_route(), e.g.
+//   router.add_route(Method::Get, "/health", health);
+// and .dispatch() calls the handler of the route matching the request. A
+// pattern ending in "/*" matches every path starting with what comes before
+// the "*", e.g. "/upload/*" matches "/upload/notes.txt". A segment starting
+// with ":" matches any one segment and captures it under that name, e.g.
+// "/users/:id" matches "/users/42", giving id => "42" in request.params, but
+// not "/users/42/posts". An exact match always wins over a ":" one, which
+// wins over a "*" one; between ":" patterns, a plain segment wins over a
+// capture in the same place, and a longer "*" pattern wins over a shorter
+// one. The order in which routes are added makes no difference.
+//
+// A HEAD request asks for what a GET would get, minus the body, so it is
+// handled by the GET route of its path unless there is a HEAD route of its
+// own. Leaving out the body is up to whoever writes the response out.
+//
+// A path which has routes, but not for the method of the request, gets a 405
+// Method Not Allowed with an Allow header listing the methods it does have.
+//
+// OPTIONS requests without a route of their own are CORS preflights, see
+// cors.rs.
+//
+// Every request goes through the router's middleware on its way to the
+// handler, see middleware.rs
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use config::ServerConfig;
+use cors::CorsConfig;
+use error::ServerError;
+use file_cache::FileCache;
+use metrics::Metrics;
+use middleware::Middleware;
+use request::{Method, Request};
+use response::Response;
+use stats::RouteStats;
+
+// Everything besides the request itself which a handler may need. The
+// lifetime 'a says the context only borrows these, from handle_connection()
+pub struct Context<'a> {
+    pub config: &'a ServerConfig,
+    pub stats: &'a RouteStats,
+    pub metrics: &'a Metrics,
+    // Set with --cache-ttl; see serve_file() in main.rs
+    pub file_cache: Option<&'a FileCache>,
+    // When the server started up
+    pub started: Instant,
+    // Who sent the request; see client_ip() in main.rs
+    pub client_ip: IpAddr,
+}
+
+// A plain function pointer rather than a closure: handlers get everything
+// they need through their arguments, which keeps the router simple and lets
+// it be shared between threads as it is. Handlers which can fail return the
+// error, and let the caller turn it into a response
+pub type Handler = fn(&Request, &Context) -> Result<Response, ServerError>;
+
+pub struct Router {
+    // Exact patterns, looked up by path; every path can have a handler for
+    // each method
+    exact: HashMap<&'static str, Vec<(Method, Handler)>>,
+    // Patterns with ":" segments in them
+    captures: Vec<(&'static str, Method, Handler)>,
+    // Patterns ending in "*", kept whole: the "*" is only cut off when
+    // matching
+    prefixes: Vec<(&'static str, Method, Handler)>,
+    // Called when no route matches
+    not_found: Handler,
+    // For the preflights
+    cors: CorsConfig,
+    // In the order it runs in
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Router {
+    // Creates a router without any routes. `not_found` answers every request
+    // which none of the routes added later matches
+    pub fn new(not_found: Handler) -> Router {
+        Router {
+            exact: HashMap::new(),
+            captures: Vec::new(),
+            prefixes: Vec::new(),
+            not_found,
+            cors: CorsConfig::default(),
+            middleware: Vec::new(),
+        }
+    }
+
+    // Replaces the CORS settings, which allow no origin to begin with
+    pub fn set_cors(&mut self, cors: CorsConfig) {
+        self.cors = cors;
+    }
+
+    // Adds middleware to run, after any added before, around every request
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    // Registers `handler` for requests with the given method and a path
+    // matching `pattern`. Adding the same method and pattern twice replaces
+    // the earlier handler
+    pub fn add_route(&mut self, method: Method, pattern: &'static str, handler: Handler) {
+        if pattern.ends_with("/*") {
+            self.prefixes.retain(|&(p, ref m, _)| !(p == pattern && *m == method));
+            self.prefixes.push((pattern, method, handler));
+        } else if pattern.split('/').any(|segment| segment.starts_with(':')) {
+            self.captures.retain(|&(p, ref m, _)| !(p == pattern && *m == method));
+            self.captures.push((pattern, method, handler));
+        } else {
+            let routes = self.exact.entry(pattern).or_default();
+            routes.retain(|(m, _)| *m != method);
+            routes.push((method, handler));
+        }
+    }
+
+    // Hands the request to the middleware, and from there to the handler of
+    // the matching route. What the route captures of the path goes into
+    // request.params first, for the middleware and handler to see
+    pub fn dispatch(&self, request: &mut Request, context: &Context)
+                    -> Result<Response, ServerError> {
+        if let Some((pattern, _)) = self.find(&request.method, &request.path) {
+            request.params = captures(pattern, &request.path).unwrap_or_default();
+        }
+        self.run_middleware(0, request, context)
+    }
+
+    // Runs the middleware from `index` on. Every one of them gets, as its
+    // `next`, a closure running the ones after it in turn
+    fn run_middleware(&self, index: usize, request: &Request, context: &Context)
+                      -> Result<Response, ServerError> {
+        match self.middleware.get(index) {
+            Some(middleware) => middleware.handle(request, context, &|request| {
+                self.run_middleware(index + 1, request, context)
+            }),
+            None => self.route(request, context),
+        }
+    }
+
+    // Hands the request to the handler of the matching route, counting the
+    // hit under the route's pattern, or to the not found handler. Only the
+    // path takes part in the matching, not the query string
+    fn route(&self, request: &Request, context: &Context) -> Result<Response, ServerError> {
+        match self.find(&request.method, &request.path) {
+            Some((pattern, handler)) => {
+                context.stats.hit(pattern);
+                handler(request, context)
+            },
+            None => {
+                let allowed = self.allowed_methods(&request.path);
+                let allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+                if request.method == Method::Options {
+                    let response = self.cors.preflight(request);
+                    Ok(if allowed.is_empty() {
+                        response
+                    } else {
+                        response.header("Allow", &allow.join(", "))
+                    })
+                } else if !allowed.is_empty() {
+                    Ok(context.config.error_pages.response(405, "Method not allowed\n")
+                       .header("Allow", &allow.join(", ")))
+                } else {
+                    (self.not_found)(request, context)
+                }
+            },
+        }
+    }
+
+    // Looks for the route matching method and path, with HEAD falling back
+    // to GET
+    fn find(&self, method: &Method, path: &str) -> Option<(&'static str, Handler)> {
+        self.find_exactly(method, path).or_else(|| match *method {
+            Method::Head => self.find_exactly(&Method::Get, path),
+            _ => None,
+        })
+    }
+
+    // The methods of all the routes matching the path, in the order they
+    // were added. HEAD is allowed wherever GET is, see .find()
+    fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let exact = self.exact.get(path).into_iter()
+            .flat_map(|routes| routes.iter().map(|&(method, _)| method));
+        let captured = self.captures.iter()
+            .filter(|&&(pattern, _, _)| captures(pattern, path).is_some())
+            .map(|&(_, method, _)| method);
+        let prefixes = self.prefixes.iter()
+            .filter(|&&(pattern, _, _)| path.starts_with(&pattern[..pattern.len() - 1]))
+            .map(|&(_, method, _)| method);
+
+        let mut methods = Vec::new();
+        for method in exact.chain(captured).chain(prefixes) {
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+            if method == Method::Get && !methods.contains(&Method::Head) {
+                methods.push(Method::Head);
+            }
+        }
+        methods
+    }
+
+    // Looks for the route of exactly this method matching the path: first
+    // among the exact patterns, then the ":" patterns, then the "*" patterns,
+    // longest first
+    fn find_exactly(&self, method: &Method, path: &str) -> Option<(&'static str, Handler)> {
+        // .get_key_value() hands back the key stored in the map too, which is
+        // the pattern as a &'static str, unlike the request's path
+        if let Some((&pattern, routes)) = self.exact.get_key_value(path) {
+            if let Some(&(_, handler)) = routes.iter().find(|&(m, _)| m == method) {
+                return Some((pattern, handler));
+            }
+        }
+
+        // Vec<bool>s compare element by element, and true is greater than
+        // false, so the pattern with a plain segment where the others
+        // capture comes out on top
+        let captured = self.captures.iter()
+            .filter(|&&(pattern, ref m, _)| m == method && captures(pattern, path).is_some())
+            .max_by_key(|&&(pattern, _, _)| {
+                pattern.split('/').map(|segment| !segment.starts_with(':')).collect::<Vec<_>>()
+            });
+        if let Some(&(pattern, _, handler)) = captured {
+            return Some((pattern, handler));
+        }
+
+        self.prefixes.iter()
+            .filter(|&&(pattern, ref m, _)| {
+                m == method && path.starts_with(&pattern[..pattern.len() - 1])
+            })
+            .max_by_key(|&&(pattern, _, _)| pattern.len())
+            .map(|&(pattern, _, handler)| (pattern, handler))
+    }
+}
+
+// Matches a path against a pattern with ":" segments, segment by segment,
+// and returns what the captures captured, or None if it does not match. A
+// capture matches any segment but an empty one. The path has been decoded
+// already, so an escaped "/", as in "a%2Fb", splits segments like any other
+fn captures(pattern: &str, path: &str) -> Option<HashMap<String, String>> {
+    let patterns: Vec<&str> = pattern.split('/').collect();
+    let segments: Vec<&str> = path.split('/').collect();
+    if patterns.len() != segments.len() {
+        return None;
+    }
+
+    let mut params = HashMap::new();
+    for (pattern, segment) in patterns.iter().zip(segments) {
+        match pattern.strip_prefix(':') {
+            Some(name) if !segment.is_empty() => {
+                params.insert(name.to_owned(), segment.to_owned());
+            },
+            None if *pattern == segment => {},
+            _ => return None,
+        }
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use error::{status_for, ParseError};
+    use middleware::Next;
+    use test_support::{self, RequestBuilder};
+
+    // Handlers saying which one they are, in the body
+    fn first(_: &Request, _: &Context) -> Result<Response, ServerError> {
+        Ok(Response::new(200).body("first"))
+    }
+
+    fn second(_: &Request, _: &Context) -> Result<Response, ServerError> {
+        Ok(Response::new(200).body("second"))
+    }
+
+    fn not_found(_: &Request, _: &Context) -> Result<Response, ServerError> {
+        Ok(Response::new(404).body("not found"))
+    }
+
+    // Dispatches a request to `router`, and gives back the status and body
+    // of the response, and the response for anything else
+    fn dispatch(router: &Router, method: Method, target: &str) -> (u16, String, Response) {
+        let config = test_support::config();
+        let (stats, metrics) = (RouteStats::new(), Metrics::new());
+        let context = Context {
+            config: &config,
+            stats: &stats,
+            metrics: &metrics,
+            file_cache: None,
+            started: Instant::now(),
+            client_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        };
+        let bytes = RequestBuilder::new(method, target).build_bytes();
+        let mut request = Request::parse(&bytes).unwrap();
+        let response = router.dispatch(&mut request, &context).unwrap();
+        let body = String::from_utf8_lossy(response.body_bytes()).into_owned();
+        (response.status(), body, response)
+    }
+
+    #[test]
+    fn routes_put_delete_and_patch() {
+        let mut router = Router::new(not_found);
+        router.add_route(Method::Put, "/items", first);
+        router.add_route(Method::Delete, "/items", second);
+        router.add_route(Method::Patch, "/items/:id", first);
+        assert_eq!(dispatch(&router, Method::Put, "/items").1, "first");
+        assert_eq!(dispatch(&router, Method::Delete, "/items").1, "second");
+        assert_eq!(dispatch(&router, Method::Patch, "/items/7").1, "first");
+    }
+
+    #[test]
+    fn answers_a_get_to_a_delete_route_with_a_405() {
+        let mut router = Router::new(not_found);
+        router.add_route(Method::Delete, "/items/:id", first);
+        assert_eq!(dispatch(&router, Method::Delete, "/items/7").0, 200);
+
+        let (status, _, response) = dispatch(&router, Method::Get, "/items/7");
+        assert_eq!(status, 405);
+        assert_eq!(response.get_header("Allow"), Some("DELETE"));
+    }
+
+    #[test]
+    fn leaves_unknown_methods_to_a_501() {
+        let error = Request::parse(b"BREW /pot HTTP/1.1\r\nHost: x\r\n\r\n").unwrap_err();
+        assert_eq!(error, ParseError::UnknownMethod("BREW".to_owned()));
+        assert_eq!(status_for(&ServerError::from(error)), 501);
+    }
+
+    #[test]
+    fn routes_by_method_and_exact_path() {
+        let mut router = Router::new(not_found);
+        router.add_route(Method::Get, "/a", first);
+        router.add_route(Method::Post, "/a", second);
+        assert_eq!(dispatch(&router, Method::Get, "/a").1, "first");
+        assert_eq!(dispatch(&router, Method::Post, "/a").1, "second");
+        assert_eq!(dispatch(&router, Method::Get, "/a?x=1").1, "first");
+        assert_eq!(dispatch(&router, Method::Get, "/a/").0, 404);
+        assert_eq!(dispatch(&router, Method::Get, "/b").1, "not found");
+    }
+
+    #[test]
+    fn replaces_a_route_added_twice() {
+        let mut router = Router::new(not_found);
+        router.add_route(Method::Get, "/a", first);
+        router.add_route(Method::Get, "/a", second);
+        router.add_route(Method::Get, "/p/*", first);
+        router.add_route(Method::Get, "/p/*", second);
+        assert_eq!(dispatch(&router, Method::Get, "/a").1, "second");
+        assert_eq!(dispatch(&router, Method::Get, "/p/x").1, "second");
+    }
+
+    #[test]
+    fn matches_prefixes_longest_first_and_after_exact_paths() {
+        let mut router = Router::new(not_found);
+        router.add_route(Method::Get, "/files/*", first);
+        router.add_route(Method::Get, "/files/private/*", second);
+        router.add_route(Method::Get, "/files/index", second);
+        assert_eq!(dispatch(&router, Method::Get, "/files/a.txt").1, "first");
+        assert_eq!(dispatch(&router, Method::Get, "/files/private/b.txt").1, "second");
+        assert_eq!(dispatch(&router, Method::Get, "/files/index").1, "second");
+        assert_eq!(dispatch(&router, Method::Get, "/files/").1, "first");
+        assert_eq!(dispatch(&router, Method::Get, "/files").1, "not found");
+    }
+
+    // Sorted, as a HashMap has no order of its own
+    fn params(request: &Request, _: &Context) -> Result<Response, ServerError> {
+        let mut params: Vec<_> = request.params.iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        params.sort();
+        Ok(Response::new(200).body(params.join("&")))
+    }
+
+    #[test]
+    fn captures_segments_of_the_path() {
+        let mut router = Router::new(not_found);
+        router.add_route(Method::Get, "/users/:id", params);
+        router.add_route(Method::Get, "/users/:id/posts/:post", params);
+        assert_eq!(dispatch(&router, Method::Get, "/users/42").1, "id=42");
+        assert_eq!(dispatch(&router, Method::Get, "/users/42/posts/7").1, "id=42&post=7");
+        assert_eq!(dispatch(&router, Method::Get, "/users/a%20b").1, "id=a b");
+        assert_eq!(dispatch(&router, Method::Get, "/users/a%2Fb").0, 404);
+        assert_eq!(dispatch(&router, Method::Get, "/users/").0, 404);
+        assert_eq!(dispatch(&router, Method::Get, "/users/42/posts").0, 404);
+    }
+
+    #[test]
+    fn prefers_exact_then_plain_segments_then_prefixes() {
+        let mut router = Router::new(not_found);
+        router.add_route(Method::Get, "/users/*", second);
+        router.add_route(Method::Get, "/users/:id/:tab", params);
+        router.add_route(Method::Get, "/users/:id/posts", first);
+        router.add_route(Method::Get, "/users/me/posts", second);
+        assert_eq!(dispatch(&router, Method::Get, "/users/me/posts").1, "second");
+        assert_eq!(dispatch(&router, Method::Get, "/users/42/posts").1, "first");
+        assert_eq!(dispatch(&router, Method::Get, "/users/42/likes").1, "id=42&tab=likes");
+        assert_eq!(dispatch(&router, Method::Get, "/users/42").1, "second");
+    }
+
+    #[test]
+    fn lists_the_methods_of_a_path_in_a_405() {
+        let mut router = Router::new(not_found);
+        router.add_route(Method::Post, "/items/:id", first);
+        router.add_route(Method::Get, "/items/:id", first);
+        router.add_route(Method::Delete, "/items/*", first);
+        let (status, body, response) = dispatch(&router, Method::Put, "/items/7");
+        assert_eq!(status, 405);
+        assert_eq!(body, "Method not allowed\n");
+        assert_eq!(response.get_header("Allow"), Some("POST, GET, HEAD, DELETE"));
+
+        // Paths without any routes are not found, whatever the method
+        assert_eq!(dispatch(&router, Method::Put, "/other").0, 404);
+    }
+
+    // Adds its name to X-Trace on the way back, after whatever ran inside
+    // it, and turns away requests for /closed if told to
+    struct Trace(&'static str, bool);
+
+    impl Middleware for Trace {
+        fn handle(&self, request: &Request, _: &Context, next: Next)
+                  -> Result<Response, ServerError> {
+            if self.1 && request.path == "/closed" {
+                return Ok(Response::new(403).header("X-Trace", self.0));
+            }
+            let response = next(request)?;
+            let trace = match response.get_header("X-Trace") {
+                Some(inner) => format!("{} {}", inner, self.0),
+                None => self.0.to_owned(),
+            };
+            Ok(response.header("X-Trace", &trace))
+        }
+    }
+
+    #[test]
+    fn runs_the_middleware_in_the_order_it_was_added() {
+        let mut router = Router::new(not_found);
+        router.add_route(Method::Get, "/open", first);
+        router.add_middleware(Trace("outer", false));
+        router.add_middleware(Trace("gate", true));
+        router.add_middleware(Trace("inner", false));
+
+        let (status, body, response) = dispatch(&router, Method::Get, "/open");
+        assert_eq!((status, body.as_str()), (200, "first"));
+        assert_eq!(response.get_header("X-Trace"), Some("inner gate outer"));
+
+        // The not found handler gets there through the middleware as well
+        let (status, _, response) = dispatch(&router, Method::Get, "/missing");
+        assert_eq!(status, 404);
+        assert_eq!(response.get_header("X-Trace"), Some("inner gate outer"));
+    }
+
+    #[test]
+    fn lets_middleware_answer_without_the_handler() {
+        let mut router = Router::new(not_found);
+        router.add_route(Method::Get, "/closed", first);
+        router.add_middleware(Trace("outer", false));
+        router.add_middleware(Trace("gate", true));
+        router.add_middleware(Trace("inner", false));
+
+        let (status, _, response) = dispatch(&router, Method::Get, "/closed");
+        assert_eq!(status, 403);
+        assert_eq!(response.get_header("X-Trace"), Some("gate outer"));
+    }
+
+    #[test]
+    fn answers_options_with_the_methods_of_the_path() {
+        let mut router = Router::new(not_found);
+        router.add_route(Method::Get, "/items", first);
+        router.add_route(Method::Post, "/items", first);
+        let (status, body, response) = dispatch(&router, Method::Options, "/items");
+        assert_eq!((status, body.as_str()), (204, ""));
+        assert_eq!(response.get_header("Allow"), Some("GET, HEAD, POST"));
+
+        let (status, _, response) = dispatch(&router, Method::Options, "*");
+        assert_eq!(status, 204);
+        assert_eq!(response.get_header("Allow"), None);
+    }
+}
@@ -0,0 +1,120 @@
+// A counting semaphore: a number of slots, each of which can be held by one
+// thread at a time. A thread taking a slot while they are all held waits for
+// somebody to give one back, for up to a given time.
+//
+// This is what --max-in-flight uses to cap how many connections are being
+// handled at once. Unlike --max-connections, which turns the connections past
+// its limit away straight away, a connection past this one waits for its turn
+// and is only turned away if none comes in time.
+//
+// std has no semaphore, but it has what it takes to make one: a Mutex around
+// the number of free slots, and a Condvar, which lets a thread sleep until
+// another one tells it that the number has changed
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+pub struct Semaphore {
+    free: Mutex<usize>,
+    // Told about every slot given back
+    freed: Condvar,
+}
+
+impl Semaphore {
+    pub fn new(slots: usize) -> Semaphore {
+        Semaphore {
+            free: Mutex::new(slots),
+            freed: Condvar::new(),
+        }
+    }
+
+    // Takes a slot, waiting up to `timeout` for one to be given back if
+    // there is none free. None if the time ran out first. The slot is held
+    // until the Permit is dropped.
+    //
+    // .wait_timeout_while() goes back to sleep whenever it wakes up without
+    // a slot for us, as it may: another thread can get to a freed slot first,
+    // and condition variables are allowed to wake up for no reason at all
+    pub fn acquire(&self, timeout: Duration) -> Option<Permit<'_>> {
+        let free = self.lock();
+        let (mut free, _) = self.freed
+            .wait_timeout_while(free, timeout, |free| *free == 0)
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if *free == 0 {
+            return None;
+        }
+        *free -= 1;
+        Some(Permit(self))
+    }
+
+    // Nothing a panic could interrupt leaves the count in a broken state, so
+    // a poisoned lock is taken anyway
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.free.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// A slot taken from a Semaphore, given back when dropped, however the thread
+// holding it is done with it
+pub struct Permit<'a>(&'a Semaphore);
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        *self.0.lock() += 1;
+        // One slot, so one waiting thread can have it
+        self.0.freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn hands_out_as_many_slots_as_it_has() {
+        let semaphore = Semaphore::new(2);
+        let first = semaphore.acquire(Duration::from_millis(0));
+        let second = semaphore.acquire(Duration::from_millis(0));
+        assert!(first.is_some() && second.is_some());
+        assert!(semaphore.acquire(Duration::from_millis(0)).is_none());
+
+        drop(first);
+        assert!(semaphore.acquire(Duration::from_millis(0)).is_some());
+    }
+
+    #[test]
+    fn waits_for_a_slot_to_be_given_back() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let (taken, is_taken) = mpsc::channel();
+        let holder = {
+            let semaphore = Arc::clone(&semaphore);
+            thread::spawn(move || {
+                let _permit = semaphore.acquire(Duration::from_secs(1)).unwrap();
+                taken.send(()).unwrap();
+                thread::sleep(Duration::from_millis(200));
+            })
+        };
+        is_taken.recv().unwrap();
+
+        let started = Instant::now();
+        let permit = semaphore.acquire(Duration::from_secs(5));
+        assert!(permit.is_some());
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(100), "Did not wait: {:?}", waited);
+        assert!(waited < Duration::from_secs(5), "Waited for the timeout: {:?}", waited);
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn gives_up_once_the_timeout_runs_out() {
+        let semaphore = Semaphore::new(1);
+        let _held = semaphore.acquire(Duration::from_millis(0)).unwrap();
+
+        let started = Instant::now();
+        assert!(semaphore.acquire(Duration::from_millis(200)).is_none());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,94 @@
+// Server-Sent Events: a response which never quite ends, the server adding
+// an event to it whenever it has something new to say. Browsers read them
+// with the EventSource API. The body is text, every event a few lines
+// followed by an empty one:
+//   event: tick
+//   id: 1
+//   data: 1
+//
+// Every line of the data gets a "data: " of its own, and the client joins
+// them back together with "\n"s.
+//
+// The response is a chunked one, see Response::chunked(), with a chunk for
+// every event, so that it goes out as soon as it is written
+use std::io::{self, Write};
+use std::sync::mpsc::Receiver;
+
+use response::Response;
+
+pub struct Event {
+    // The type of event, which EventSource dispatches on; "message" if None
+    pub name: Option<String>,
+    // Sent back by the client, as Last-Event-ID, when it reconnects
+    pub id: Option<String>,
+    pub data: String,
+}
+
+impl Event {
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        // A line break in the name or ID would start a new field, so they
+        // stop at the first one
+        let line = |value: &str| value.split(['\n', '\r']).next().unwrap_or("").to_owned();
+        if let Some(ref name) = self.name {
+            writeln!(out, "event: {}", line(name))?;
+        }
+        if let Some(ref id) = self.id {
+            writeln!(out, "id: {}", line(id))?;
+        }
+        // "\r\n", "\n" and "\r" all end a line
+        for data in self.data.replace("\r\n", "\n").split(['\n', '\r']) {
+            writeln!(out, "data: {}", data)?;
+        }
+        writeln!(out)
+    }
+}
+
+// A response sending every event which comes out of `events`, until the
+// other end of the channel is dropped. A client which goes away before then
+// makes the write fail, which ends the response, and drops `events` in turn:
+// the sender finds out on its next .send()
+pub fn stream(events: Receiver<Event>) -> Response {
+    Response::new(200)
+        .header("Content-Type", "text/event-stream")
+        // Every client wants the events as they happen, not a cached copy
+        .header("Cache-Control", "no-cache")
+        .chunked(move |out| {
+            for event in events {
+                event.write_to(out)?;
+                out.flush()?;
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn written(event: Event) -> String {
+        let mut out = Vec::new();
+        event.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_the_fields_then_an_empty_line() {
+        let event = Event { name: Some("tick".to_owned()), id: Some("7".to_owned()),
+                            data: "7".to_owned() };
+        assert_eq!(written(event), "event: tick\nid: 7\ndata: 7\n\n");
+        let event = Event { name: None, id: None, data: String::new() };
+        assert_eq!(written(event), "data: \n\n");
+    }
+
+    #[test]
+    fn gives_every_line_of_the_data_a_field_of_its_own() {
+        let event = Event { name: None, id: None, data: "one\r\ntwo\nthree\rfour".to_owned() };
+        assert_eq!(written(event), "data: one\ndata: two\ndata: three\ndata: four\n\n");
+    }
+
+    #[test]
+    fn cuts_the_name_and_id_short_at_a_line_break() {
+        let event = Event { name: Some("a\ndata: forged".to_owned()), id: Some("1\r2".to_owned()),
+                            data: "x".to_owned() };
+        assert_eq!(written(event), "event: a\nid: 1\ndata: x\n\n");
+    }
+}
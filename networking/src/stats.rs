@@ -0,0 +1,66 @@
+// Keeps count of how many times each route has been requested, so that they
+// can be shown at /debug/stats.
+//
+// Counters are keyed by the route which matched, not by the raw path of the
+// request: "/fib?n=10" and "/fib?n=20" are both hits of "/fib"
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// The map lives behind a Mutex. Besides letting several threads share it
+// safely, this gives us "interior mutability": .hit() can update the counters
+// through a shared reference (&self) instead of requiring &mut self, so the
+// stats can be handed out to whoever needs them without giving up ownership
+#[derive(Default)]
+pub struct RouteStats {
+    // &'static str because routes are string literals written in the code,
+    // which live for the whole duration of the program
+    hits: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl RouteStats {
+    pub fn new() -> RouteStats {
+        RouteStats::default()
+    }
+
+    // .lock() returns an error only if another thread panicked while holding
+    // the lock. The counters are still perfectly usable then, as a panic
+    // cannot leave a u64 half-updated, so we take the guard out of the error
+    // with .into_inner() rather than panicking in turn.
+    //
+    // .entry() looks up the key and lets us insert a value if it is missing;
+    // .or_insert() returns a mutable reference to the counter either way
+    pub fn hit(&self, route: &'static str) {
+        let mut hits = self.hits.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *hits.entry(route).or_insert(0) += 1;
+    }
+
+    // Renders the counters as plain text, one "route count" pair per line.
+    // HashMaps do not keep their keys in any particular order, so we sort
+    // them to make the output stable
+    pub fn render(&self) -> String {
+        let hits = self.hits.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut routes: Vec<_> = hits.iter().collect();
+        routes.sort();
+
+        let mut output = String::new();
+        for (route, count) in routes {
+            output.push_str(&format!("{} {}\n", route, count));
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_hits_by_route_in_order() {
+        let stats = RouteStats::new();
+        assert_eq!(stats.render(), "");
+        stats.hit("/fib");
+        stats.hit("/");
+        stats.hit("/fib");
+        assert_eq!(stats.render(), "/ 1\n/fib 2\n");
+    }
+}
@@ -0,0 +1,340 @@
+// Helpers for the tests of the other modules: building the raw bytes of a
+// request without gluing strings together by hand, a stand-in for the
+// connection to send them over, and a way to have the server answer them
+// without any sockets involved, e.g.
+//   let replies = exchange(&config, RequestBuilder::get("/health").build_bytes());
+//   assert_eq!(replies[0].status, 200);
+//
+// Only compiled for "cargo test", see the #[cfg(test)] in main.rs
+use std::cmp;
+use std::env;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use access_log::AccessLog;
+use chunked::read_chunked;
+use config::{LogLevel, ServerConfig};
+use request::Method;
+use {handle_request, Server};
+
+// A request, put together one part at a time. It speaks HTTP/1.1 and comes
+// with a "Host: localhost" unless told otherwise, and a body gets its
+// Content-Length by itself
+pub struct RequestBuilder {
+    method: Method,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+}
+
+impl RequestBuilder {
+    pub fn new(method: Method, target: &str) -> RequestBuilder {
+        RequestBuilder {
+            method,
+            target: target.to_owned(),
+            version: "HTTP/1.1".to_owned(),
+            headers: vec![("Host".to_owned(), "localhost".to_owned())],
+            body: None,
+        }
+    }
+
+    pub fn get(target: &str) -> RequestBuilder {
+        RequestBuilder::new(Method::Get, target)
+    }
+
+    pub fn post(target: &str) -> RequestBuilder {
+        RequestBuilder::new(Method::Post, target)
+    }
+
+    pub fn version(mut self, version: &str) -> RequestBuilder {
+        self.version = version.to_owned();
+        self
+    }
+
+    // Sets a header, replacing one of the same name given before
+    pub fn header(mut self, name: &str, value: &str) -> RequestBuilder {
+        self = self.without_header(name);
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn without_header(mut self, name: &str) -> RequestBuilder {
+        self.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> RequestBuilder {
+        self.body = Some(body.into());
+        self
+    }
+
+    // The request as it goes over the wire. A body sent with
+    // Transfer-Encoding is taken to be encoded already, and gets no
+    // Content-Length
+    pub fn build_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("{} {} {}\r\n", self.method, self.target, self.version)
+            .into_bytes();
+        for (name, value) in &self.headers {
+            bytes.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        let has = |name: &str| self.headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name));
+        if let Some(ref body) = self.body {
+            if !has("Content-Length") && !has("Transfer-Encoding") {
+                bytes.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+            }
+        }
+        bytes.extend_from_slice(b"\r\n");
+        if let Some(ref body) = self.body {
+            bytes.extend_from_slice(body);
+        }
+        bytes
+    }
+}
+
+// A connection which reads what it was given, at most `chunk` bytes at a
+// time, and keeps what is written to it. Once the input runs out, reads
+// either report the end of it, as if the client had closed its end, or fail
+// with WouldBlock, as a socket whose read timeout ran out does
+pub struct MockStream {
+    input: Vec<u8>,
+    position: usize,
+    chunk: usize,
+    stall: bool,
+    hung_up: bool,
+    pub output: Vec<u8>,
+}
+
+impl MockStream {
+    pub fn new<B: Into<Vec<u8>>>(input: B) -> MockStream {
+        MockStream {
+            input: input.into(),
+            position: 0,
+            chunk: usize::MAX,
+            stall: false,
+            hung_up: false,
+            output: Vec::new(),
+        }
+    }
+
+    // Every read returns a single byte, the least a read may return
+    pub fn one_byte_at_a_time(mut self) -> MockStream {
+        self.chunk = 1;
+        self
+    }
+
+    pub fn then_stall(mut self) -> MockStream {
+        self.stall = true;
+        self
+    }
+
+    // Writes fail with BrokenPipe, as they do once the client has closed
+    // the connection
+    pub fn hung_up(mut self) -> MockStream {
+        self.hung_up = true;
+        self
+    }
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = &self.input[self.position..];
+        if left.is_empty() && self.stall {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let count = cmp::min(cmp::min(self.chunk, buf.len()), left.len());
+        buf[..count].copy_from_slice(&left[..count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.hung_up {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The configuration the tests start from: the defaults, without the request
+// and response dumps
+pub fn config() -> ServerConfig {
+    ServerConfig {
+        log_level: LogLevel::Error,
+        ..ServerConfig::default()
+    }
+}
+
+// Has a server with the given configuration answer whatever requests come
+// over `stream`, for as long as it keeps the connection open, and gives
+// back everything it wrote
+pub fn answer(config: &ServerConfig, stream: MockStream) -> Vec<u8> {
+    answer_on(&server(config), config, stream)
+}
+
+// A server the way main() sets one up, for tests which need to change it
+// before it answers, e.g. to add a route of their own
+pub fn server(config: &ServerConfig) -> Server {
+    Server::new(config, AccessLog::stdout(), None)
+}
+
+// The same as answer(), with a server of the test's making
+pub fn answer_on(server: &Server, config: &ServerConfig, stream: MockStream) -> Vec<u8> {
+    let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let mut reader = BufReader::new(stream);
+    while let Ok(true) = handle_request(&mut reader, peer, config, server) {}
+    reader.into_inner().output
+}
+
+// The same, for requests which arrive all at once, with the responses
+// picked apart
+pub fn exchange<B: Into<Vec<u8>>>(config: &ServerConfig, input: B) -> Vec<Reply> {
+    Reply::parse_all(&answer(config, MockStream::new(input)))
+}
+
+// A response as the client sees it, its body with any chunks put together
+#[derive(Debug)]
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    // The value of a header, looked up regardless of case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Every response in `bytes`, one after the other. Their bodies are as
+    // long as Content-Length says, in chunks, or whatever is left
+    pub fn parse_all(mut bytes: &[u8]) -> Vec<Reply> {
+        let mut replies = Vec::new();
+        while !bytes.is_empty() {
+            let end = find(bytes, b"\r\n\r\n").expect("Response without an empty line");
+            let head = String::from_utf8_lossy(&bytes[..end]).into_owned();
+            bytes = &bytes[end + 4..];
+
+            let mut lines = head.split("\r\n");
+            let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+            let headers: Vec<(String, String)> = lines
+                .map(|line| {
+                    let (name, value) = line.split_once(':').unwrap();
+                    (name.to_owned(), value.trim().to_owned())
+                })
+                .collect();
+            let mut reply = Reply { status, headers, body: Vec::new() };
+
+            let length = reply.header("Content-Length").map(|length| length.parse().unwrap());
+            if reply.header("Transfer-Encoding") == Some("chunked") {
+                reply.body = read_chunked(&mut bytes, u64::MAX).unwrap();
+            } else if let Some(length) = length {
+                reply.body = bytes[..length].to_vec();
+                bytes = &bytes[length..];
+            } else if status >= 200 && status != 204 && status != 304 {
+                reply.body = bytes.to_vec();
+                bytes = &[];
+            }
+            replies.push(reply);
+        }
+        replies
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// A directory of its own for a test to put files in, removed again with
+// everything in it once the test is done
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        // Tests run in parallel, so every directory needs a name of its own
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!("networking-test-{}-{}", process::id(),
+                                                COUNT.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    // Writes a file into the directory, creating the ones it is in
+    pub fn file<B: AsRef<[u8]>>(&self, name: &str, contents: B) -> PathBuf {
+        let path = self.0.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use request::Request;
+
+    #[test]
+    fn builds_a_request_which_parses_back() {
+        let bytes = RequestBuilder::get("/foo?x=1").header("X", "Y").body("hello").build_bytes();
+        assert_eq!(bytes, b"GET /foo?x=1 HTTP/1.1\r\nHost: localhost\r\nX: Y\r\n\
+                             Content-Length: 5\r\n\r\nhello".to_vec());
+
+        let request = Request::parse(&bytes).unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path, "/foo");
+        assert_eq!(request.query.get("x").map(String::as_str), Some("1"));
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.header("x"), Some("Y"));
+        assert_eq!(request.header("Content-Length"), Some("5"));
+    }
+
+    #[test]
+    fn replaces_headers_and_can_leave_out_host() {
+        let bytes = RequestBuilder::post("/").version("HTTP/1.0").header("host", "example.com")
+            .header("A", "1").without_header("a").build_bytes();
+        assert_eq!(bytes, b"POST / HTTP/1.0\r\nhost: example.com\r\n\r\n".to_vec());
+    }
+
+    #[test]
+    fn reads_one_byte_at_a_time() {
+        let mut stream = MockStream::new("abc").one_byte_at_a_time();
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn has_the_server_answer() {
+        let replies = exchange(&config(), RequestBuilder::get("/healthz").build_bytes());
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].status, 200);
+    }
+}
@@ -0,0 +1,178 @@
+// A fixed number of worker threads taking turns at running jobs, so that one
+// slow client only holds up one worker instead of the whole server.
+//
+// The pool and its workers are connected by a channel (std::sync::mpsc,
+// "multiple producer, single consumer"): execute() sends jobs down the
+// channel, and whichever worker is free picks up the next one. Creating the
+// threads once up front is cheaper than spawning a new one per connection,
+// and puts a limit on how many connections are handled at the same time
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// A job is any closure which can be called once (FnOnce), moved to another
+// thread (Send) and does not borrow anything short-lived ('static). Closures
+// all have different types, so they are boxed to give them a common one
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    // An Option so that Drop can take the sender out and drop it before
+    // waiting for the workers; see below
+    sender: Option<mpsc::Sender<Job>>,
+}
+
+impl ThreadPool {
+    // Creates a pool of `size` worker threads.
+    //
+    // Panics if size is 0: a pool without workers would accept jobs and
+    // never run any of them
+    pub fn new(size: usize) -> ThreadPool {
+        assert!(size > 0, "A thread pool needs at least one thread");
+
+        let (sender, receiver) = mpsc::channel();
+
+        // There is only a single receiving end, but every worker needs to
+        // pull from it. Arc lets them share ownership of it, Mutex makes sure
+        // only one of them takes a job at a time
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|id| Worker::new(id, Arc::clone(&receiver)))
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    // Hands the closure to the next free worker. If they are all busy, the
+    // job waits in the channel until one of them is done
+    pub fn execute<F>(&self, f: F)
+        where F: FnOnce() + Send + 'static {
+        // The sender is only missing while the pool is being dropped, and
+        // nobody can call execute() then. send() fails only once all the
+        // receivers are gone, i.e. every worker has died
+        self.sender.as_ref()
+            .unwrap()
+            .send(Box::new(f))
+            .expect("All the worker threads have stopped");
+    }
+}
+
+// Dropping the pool waits for the jobs already handed out to finish
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Closing the channel first is what lets the workers stop: once the
+        // sender is gone, recv() returns an error as soon as the queued jobs
+        // have run out, and every worker leaves its loop
+        drop(self.sender.take());
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                // join() returns an error only if the thread panicked, and
+                // there is nothing left to clean up for it in that case
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+struct Worker {
+    // Taken out by ThreadPool's Drop in order to join the thread
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+        let thread = thread::Builder::new()
+            .name(format!("worker-{}", id))
+            .spawn(move || loop {
+                // The lock guard is a temporary of this statement, so the
+                // lock is released before the job runs and the other workers
+                // can pick up jobs in the meantime. A poisoned lock is still
+                // usable: the receiver itself cannot be left half-updated
+                let job = receiver.lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .recv();
+
+                match job {
+                    // A panicking job would otherwise take its worker down
+                    // with it, and the pool would slowly run out of them
+                    Ok(job) => {
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            eprintln!("A job panicked in worker {}", id);
+                        }
+                    },
+                    // The channel is closed: the pool is shutting down
+                    Err(_) => break,
+                }
+            })
+            .expect("Failed to spawn a worker thread");
+
+        Worker {
+            thread: Some(thread),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn runs_every_job_before_being_dropped() {
+        let done = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = ThreadPool::new(3);
+            for _ in 0..20 {
+                let done = Arc::clone(&done);
+                pool.execute(move || {
+                    thread::sleep(Duration::from_millis(5));
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        }
+        assert_eq!(done.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn runs_jobs_at_the_same_time() {
+        let pool = ThreadPool::new(2);
+        let (started, has_started) = mpsc::channel();
+        let (go, wait) = mpsc::channel::<()>();
+        let wait = Arc::new(Mutex::new(wait));
+        for _ in 0..2 {
+            let (started, wait) = (started.clone(), Arc::clone(&wait));
+            pool.execute(move || {
+                started.send(()).unwrap();
+                let _ = wait.lock().unwrap().recv_timeout(Duration::from_secs(5));
+            });
+        }
+        // Both jobs start without either of them finishing first
+        for _ in 0..2 {
+            has_started.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        drop(go);
+    }
+
+    #[test]
+    fn keeps_its_workers_when_a_job_panics() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("Job failed on purpose"));
+        let (done, is_done) = mpsc::channel();
+        pool.execute(move || done.send(()).unwrap());
+        is_done.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one thread")]
+    fn refuses_to_have_no_threads() {
+        ThreadPool::new(0);
+    }
+}
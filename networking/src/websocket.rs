@@ -0,0 +1,344 @@
+// WebSocket (RFC 6455): a connection which starts out as an HTTP request and
+// then carries messages both ways for as long as it stays open. The client
+// asks for it with
+//   GET /ws/echo HTTP/1.1
+//   Upgrade: websocket
+//   Connection: Upgrade
+//   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
+//   Sec-WebSocket-Version: 13
+// and we agree with
+//   HTTP/1.1 101 SWITCHING PROTOCOLS
+//   Upgrade: websocket
+//   Connection: Upgrade
+//   Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=
+// after which both sides send "frames" instead of HTTP. The accept value
+// proves that we understood the request, rather than being some server
+// which answers anything with a 101; see accept_key().
+//
+// Only text messages are supported. A binary one, or anything breaking the
+// rules, has us close the connection with a code saying why
+use std::io;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha1::{Digest, Sha1};
+
+use error::ServerError;
+use request::{Method, Request};
+use response::{Response, Stream};
+
+// Which the accept value is worked out with. Fixed by the RFC, for every
+// server and client
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Messages are kept in memory like request bodies are, so there is a limit
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+// The kinds of frame, in the low 4 bits of a frame's first byte
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// The codes a close frame gives as the reason for closing
+const PROTOCOL_ERROR: u16 = 1002;
+const UNSUPPORTED_DATA: u16 = 1003;
+const INVALID_DATA: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+// One frame, as read off the connection
+struct Frame {
+    // Whether this is the last frame of its message
+    fin: bool,
+    opcode: u8,
+    // Unmasked already
+    payload: Vec<u8>,
+}
+
+// The Sec-WebSocket-Accept to answer a Sec-WebSocket-Key with: the SHA-1
+// hash of the key followed by the GUID, in base64
+pub fn accept_key(key: &str) -> String {
+    let hash = Sha1::digest(format!("{}{}", key.trim(), GUID).as_bytes());
+    BASE64.encode(hash)
+}
+
+// Checks that `request` asks for a WebSocket, and answers it with the 101
+// which makes it one. `session` then gets to send and receive the messages.
+//
+// A request for another version of the protocol gets a 426 Upgrade Required
+// saying which version we speak; anything else which is not a proper
+// WebSocket request is a bad request
+pub fn handshake<F>(request: &Request, session: F) -> Result<Response, ServerError>
+    where F: FnOnce(&mut WebSocket) -> io::Result<()> + 'static {
+    let bad_request = |message: &str| Err(ServerError::BadRequest(message.to_owned()));
+    let has_token = |name: &str, token: &str| {
+        request.header(name)
+            .map(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    };
+
+    if request.method != Method::Get || request.version == "HTTP/1.0" {
+        return bad_request("WebSocket requests must be HTTP/1.1 GETs");
+    }
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return bad_request("Expected Upgrade: websocket and Connection: Upgrade");
+    }
+    if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Ok(Response::new(426)
+                  .header("Sec-WebSocket-Version", "13")
+                  .header("Content-Type", "text/plain; charset=utf-8")
+                  .body("Only WebSocket version 13 is supported\n"));
+    }
+    // The key is 16 random bytes, in base64
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if BASE64.decode(key.trim()).map(|key| key.len() == 16).unwrap_or(false) => key,
+        _ => return bad_request("Missing or invalid Sec-WebSocket-Key"),
+    };
+
+    Ok(Response::new(101)
+       .header("Upgrade", "websocket")
+       .header("Sec-WebSocket-Accept", &accept_key(key))
+       .upgrade(move |stream| session(&mut WebSocket { stream })))
+}
+
+// The WebSocket side of the connection, handed to the session
+pub struct WebSocket<'a> {
+    stream: &'a mut dyn Stream,
+}
+
+impl<'a> WebSocket<'a> {
+    // Waits for the next text message, answering pings along the way. None
+    // means the connection is over: the client closed it, or broke the rules
+    // and had it closed
+    pub fn read_text(&mut self) -> io::Result<Option<String>> {
+        // A message may come in several frames, the first one saying what
+        // kind of message it is and the others being continuations of it
+        let mut message: Option<Vec<u8>> = None;
+        loop {
+            let Frame { fin, opcode, payload } = match self.read_frame()? {
+                Ok(frame) => frame,
+                Err(code) => return self.fail(code),
+            };
+            match opcode {
+                TEXT if message.is_none() => message = Some(payload),
+                CONTINUATION if message.is_some() => {
+                    let text = message.as_mut().unwrap();
+                    if text.len() + payload.len() > MAX_MESSAGE_BYTES {
+                        return self.fail(MESSAGE_TOO_BIG);
+                    }
+                    text.extend_from_slice(&payload);
+                },
+                BINARY if message.is_none() => return self.fail(UNSUPPORTED_DATA),
+                // Control frames may come in the middle of a message
+                PING => {
+                    self.write_frame(PONG, &payload)?;
+                    continue;
+                },
+                PONG => continue,
+                // Saying goodbye back is all it takes; the code the client
+                // gave, if any, is its first two bytes
+                CLOSE => {
+                    let code = payload.get(..2).unwrap_or(&[]).to_vec();
+                    self.write_frame(CLOSE, &code)?;
+                    return Ok(None);
+                },
+                _ => return self.fail(PROTOCOL_ERROR),
+            }
+
+            if fin {
+                return match String::from_utf8(message.take().unwrap_or_default()) {
+                    Ok(text) => Ok(Some(text)),
+                    Err(_) => self.fail(INVALID_DATA),
+                };
+            }
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(TEXT, text.as_bytes())
+    }
+
+    // Closes the connection with `code` saying why
+    fn fail(&mut self, code: u16) -> io::Result<Option<String>> {
+        self.write_frame(CLOSE, &code.to_be_bytes())?;
+        Ok(None)
+    }
+
+    // Reads a frame:
+    //   1 byte: FIN (this is the last frame of the message) and the opcode
+    //   1 byte: MASK and the length, or 126 or 127 if it follows in
+    //           2 or 8 bytes
+    //   4 bytes: the masking key, which every frame from the client has
+    //   the payload, each byte XORed with a byte of the key in turn
+    // A frame which breaks the rules is Ok(Err(code)), the code to close
+    // the connection with
+    fn read_frame(&mut self) -> io::Result<Result<Frame, u16>> {
+        let mut header = [0; 2];
+        self.stream.read_exact(&mut header)?;
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0F;
+        let masked = header[1] & 0x80 != 0;
+        // The other three bits of the first byte are for extensions, and we
+        // agreed to none
+        if header[0] & 0x70 != 0 || !masked {
+            return Ok(Err(PROTOCOL_ERROR));
+        }
+
+        let length = match header[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                self.stream.read_exact(&mut length)?;
+                u64::from(u16::from_be_bytes(length))
+            },
+            127 => {
+                let mut length = [0; 8];
+                self.stream.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            },
+            length => u64::from(length),
+        };
+        // Control frames are never fragmented, and short
+        if opcode >= CLOSE && (!fin || length > 125) {
+            return Ok(Err(PROTOCOL_ERROR));
+        }
+        if length > MAX_MESSAGE_BYTES as u64 {
+            return Ok(Err(MESSAGE_TOO_BIG));
+        }
+
+        let mut mask = [0; 4];
+        self.stream.read_exact(&mut mask)?;
+        let mut payload = vec![0; length as usize];
+        self.stream.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Ok(Frame { fin, opcode, payload }))
+    }
+
+    // Writes a frame, the whole message in one. Frames from the server are
+    // not masked
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        if payload.len() < 126 {
+            frame.push(payload.len() as u8);
+        } else if payload.len() <= usize::from(u16::MAX) {
+            frame.push(126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        } else {
+            frame.push(127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{MockStream, RequestBuilder};
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    fn upgrade_request() -> RequestBuilder {
+        RequestBuilder::get("/ws/echo").header("Upgrade", "websocket")
+            .header("Connection", "keep-alive, Upgrade").header("Sec-WebSocket-Key", KEY)
+            .header("Sec-WebSocket-Version", "13")
+    }
+
+    fn answer(request: RequestBuilder) -> Result<Response, ServerError> {
+        let request = Request::parse(&request.build_bytes()).unwrap();
+        handshake(&request, |_| Ok(()))
+    }
+
+    fn bad_request(request: RequestBuilder) -> String {
+        match answer(request) {
+            Err(ServerError::BadRequest(message)) => message,
+            other => panic!("Expected a bad request, got {:?}", other.map(|r| r.status())),
+        }
+    }
+
+    // A frame the way a client sends it, masked
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    // What read_text() makes of `input`, and what it wrote back
+    fn read_text(input: Vec<u8>) -> (Option<String>, Vec<u8>) {
+        let mut stream = MockStream::new(input);
+        let text = WebSocket { stream: &mut stream }.read_text().unwrap();
+        (text, stream.output)
+    }
+
+    #[test]
+    fn works_out_the_accept_key_of_the_rfc_example() {
+        assert_eq!(accept_key(KEY), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn switches_protocols_for_a_proper_request() {
+        let response = answer(upgrade_request()).unwrap();
+        assert_eq!(response.status(), 101);
+        assert!(response.is_upgrade());
+    }
+
+    #[test]
+    fn refuses_what_is_not_a_websocket_request() {
+        assert_eq!(bad_request(upgrade_request().without_header("Upgrade")),
+                   "Expected Upgrade: websocket and Connection: Upgrade");
+        assert_eq!(bad_request(upgrade_request().version("HTTP/1.0")),
+                   "WebSocket requests must be HTTP/1.1 GETs");
+        assert_eq!(bad_request(upgrade_request().header("Sec-WebSocket-Key", "c2hvcnQ=")),
+                   "Missing or invalid Sec-WebSocket-Key");
+
+        let response = answer(upgrade_request().header("Sec-WebSocket-Version", "8")).unwrap();
+        assert_eq!(response.status(), 426);
+        assert!(!response.is_upgrade());
+    }
+
+    #[test]
+    fn puts_fragments_together_and_answers_pings_in_between() {
+        let input = [frame(false, TEXT, b"Hel"), frame(true, PING, b"?"),
+                     frame(true, CONTINUATION, b"lo")].concat();
+        let (text, output) = read_text(input);
+        assert_eq!(text.as_deref(), Some("Hello"));
+        assert_eq!(output, [0x80 | PONG, 1, b'?']);
+    }
+
+    #[test]
+    fn says_goodbye_back_with_the_clients_code() {
+        let (text, output) = read_text(frame(true, CLOSE, &1000u16.to_be_bytes()));
+        assert_eq!(text, None);
+        assert_eq!(output, [0x80 | CLOSE, 2, 0x03, 0xE8]);
+    }
+
+    #[test]
+    fn closes_the_connection_on_frames_breaking_the_rules() {
+        let close_with = |code: u16| {
+            let mut frame = vec![0x80 | CLOSE, 2];
+            frame.extend_from_slice(&code.to_be_bytes());
+            frame
+        };
+        assert_eq!(read_text(frame(true, BINARY, b"\x00")), (None, close_with(UNSUPPORTED_DATA)));
+        assert_eq!(read_text(frame(true, TEXT, b"\xff")), (None, close_with(INVALID_DATA)));
+        assert_eq!(read_text(frame(true, CONTINUATION, b"x")), (None, close_with(PROTOCOL_ERROR)));
+        // Unmasked
+        assert_eq!(read_text(vec![0x81, 1, b'x']), (None, close_with(PROTOCOL_ERROR)));
+        let too_big = [vec![0x81, 0xFF], (MAX_MESSAGE_BYTES as u64 + 1).to_be_bytes().to_vec()];
+        assert_eq!(read_text(too_big.concat()), (None, close_with(MESSAGE_TOO_BIG)));
+    }
+
+    #[test]
+    fn sends_longer_messages_with_a_longer_length() {
+        let mut stream = MockStream::new("");
+        WebSocket { stream: &mut stream }.send_text(&"x".repeat(200)).unwrap();
+        assert_eq!(stream.output[..4], [0x80 | TEXT, 126, 0, 200]);
+        assert_eq!(stream.output.len(), 204);
+    }
+}
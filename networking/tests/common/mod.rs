@@ -0,0 +1,81 @@
+// What the integration tests share: starting the server on a port of its
+// own, and talking to it. Every file under tests/ is a crate of its own, and
+// uses only some of these, hence the allow
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Kills the server when the test is over, however it ends
+pub struct Server {
+    child: Child,
+    pub address: String,
+}
+
+impl Server {
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    // How the server exited, if it did within `timeout`
+    pub fn wait_for_exit(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return Some(status);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        None
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// A port nobody is listening on, as far as the OS knows right now
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+// Starts the server with `args` on top of an address of its own, and waits
+// for it to listen. Cargo builds the server for integration tests, and says
+// where it put it
+pub fn start(args: &[&str]) -> Server {
+    let address = format!("127.0.0.1:{}", free_port());
+    let child = Command::new(env!("CARGO_BIN_EXE_networking"))
+        .args(["--address", &address])
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let server = Server { child, address };
+
+    // The connection made to find out is one the server has to handle, and
+    // may turn away; only the tests' own connections count
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(&server.address).is_err() {
+        assert!(Instant::now() < deadline, "The server did not start listening");
+        thread::sleep(Duration::from_millis(50));
+    }
+    server
+}
+
+// Sends a GET for `target` on a connection of its own, and reads everything
+// the server sends back until it closes the connection
+pub fn get(address: &str, target: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+           target).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
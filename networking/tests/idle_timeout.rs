@@ -0,0 +1,27 @@
+// Starts the server with a short idle timeout and a long read timeout, and
+// checks that a kept-alive connection which goes quiet between requests is
+// closed after the first, not the second
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+mod common;
+
+#[test]
+fn closes_a_connection_idle_between_requests() {
+    let server = common::start(&["--idle-timeout", "1", "--read-timeout", "10"]);
+
+    let mut stream = TcpStream::connect(&server.address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let started = Instant::now();
+    write!(stream, "GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+    // The response, then nothing until the server closes its end
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let elapsed = started.elapsed();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(!response.contains("408"), "{}", response);
+    assert!(elapsed >= Duration::from_secs(1), "Closed straight away: {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(5), "Closed after {:?}", elapsed);
+}
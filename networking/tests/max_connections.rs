@@ -0,0 +1,55 @@
+// Starts the server with room for a single connection, and checks that a
+// second one is turned away with a 503 while the first is open, and let in
+// once it is closed
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+#[test]
+fn turns_away_connections_past_the_limit() {
+    let server = common::start(&["--max-connections", "1"]);
+
+    // An event stream keeps the connection busy between its ticks. The
+    // connection start() made to see whether the server was up may hold the
+    // slot for a moment still, and have this one turned away
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let (holder, mut reader) = loop {
+        let mut holder = TcpStream::connect(&server.address).unwrap();
+        write!(holder, "GET /events/ticks?count=3 HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(holder.try_clone().unwrap());
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        if status.starts_with("HTTP/1.1 200 ") {
+            break (holder, reader);
+        }
+        assert!(Instant::now() < deadline, "Never let in: {}", status);
+        thread::sleep(Duration::from_millis(100));
+    };
+    // Once the first tick is in, the response is well under way
+    let mut line = String::new();
+    while line != "data: 1\n" {
+        line.clear();
+        assert!(reader.read_line(&mut line).unwrap() > 0, "The stream ended early");
+    }
+
+    let response = common::get(&server.address, "/healthz");
+    assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+    assert!(response.contains("\r\nRetry-After: 1\r\n"), "{}", response);
+
+    // The slot is given back once the worker notices the connection closed,
+    // which it does when writing the next tick
+    drop(reader);
+    drop(holder);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let response = common::get(&server.address, "/healthz");
+        if response.starts_with("HTTP/1.1 200 ") {
+            break;
+        }
+        assert!(Instant::now() < deadline, "Still turned away: {}", response);
+        thread::sleep(Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,36 @@
+// Starts the server and checks that it answers requests at the same time,
+// each one on a worker thread of its own, rather than one after the other.
+//
+// The slow requests are for /events/ticks?count=2, an event stream which
+// takes a second between its two ticks. Four of them at once take about a
+// second altogether with four workers, and four seconds with one
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+const REQUESTS: u32 = 4;
+const SLOW: Duration = Duration::from_secs(1);
+
+#[test]
+fn handles_slow_requests_in_parallel() {
+    let server = common::start(&["--threads", &REQUESTS.to_string()]);
+
+    let started = Instant::now();
+    let clients: Vec<_> = (0..REQUESTS)
+        .map(|_| {
+            let address = server.address.clone();
+            thread::spawn(move || common::get(&address, "/events/ticks?count=2"))
+        })
+        .collect();
+    for client in clients {
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("data: 2\n"), "{}", response);
+    }
+    let elapsed = started.elapsed();
+
+    // One after the other would take REQUESTS times as long as one request
+    assert!(elapsed >= SLOW, "The requests were not slow: {:?}", elapsed);
+    assert!(elapsed < SLOW * REQUESTS / 2, "The requests took {:?}", elapsed);
+}
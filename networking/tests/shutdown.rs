@@ -0,0 +1,31 @@
+// Starts the server, asks it to stop with a SIGINT, as Ctrl-C does, and
+// checks that it finishes the request it is in the middle of first
+#![cfg(unix)]
+
+extern crate libc;
+
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn finishes_the_requests_being_handled_before_exiting() {
+    let mut server = common::start(&["--threads", "2"]);
+
+    // Two ticks, a second apart
+    let address = server.address.clone();
+    let client = thread::spawn(move || common::get(&address, "/events/ticks?count=2"));
+    thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(unsafe { libc::kill(server.pid() as libc::pid_t, libc::SIGINT) }, 0);
+    let status = server.wait_for_exit(Duration::from_secs(10))
+        .expect("The server did not exit");
+    assert!(status.success(), "The server exited with {}", status);
+
+    let response = client.join().unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("data: 2\n"), "{}", response);
+    assert!(TcpStream::connect(&server.address).is_err());
+}